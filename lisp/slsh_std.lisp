@@ -1,49 +1,48 @@
 (defmacro setq (sym bind)
+	"Same as set but quotes sym for you."
 	`(set (quote ,sym) ,bind))
 
 (defmacro defq (sym bind)
+	"Same as def but quotes sym for you."
 	`(def (quote ,sym) ,bind))
 
-(defmacro defn (name args body)
-	`(defq ,name (fn ,args ,body)))
+(defmacro defn (name args &rest body)
+	"Define a lambda named name, body can start with a docstring."
+	`(defq ,name (fn ,args ,@body)))
 
 (defmacro loop (params bindings body)
+	"Call a lambda with params and body on bindings, use recur to loop."
 		`((fn ,params ,body) ,@bindings))
 
+;; Use name# inside a backquote for a symbol that can not capture user
+;; variables (each name# is replaced with a gensym when expanded).
 (defmacro dotimes (times body)
-	(let ((idx-name (gensym)))
-	`(loop (idx-name) (,times) (progn
+	"Evaluate body times times."
+	`(loop (idx#) (,times) (progn
 		(eval ,body)
-		(if (> idx-name 1) (recur (- idx-name 1)))))))
+		(if (> idx# 1) (recur (- idx# 1))))))
 
 (defmacro dotimesi (idx-bind times body)
-	(let ((stop-name (gensym)))
-	`(loop (,idx-bind stop-name) (0 (- ,times 1)) (progn
+	"Evaluate body times times with idx-bind set to the iteration (from 0)."
+	`(loop (,idx-bind stop#) (0 (- ,times 1)) (progn
 		(eval ,body)
-		(if (< ,idx-bind stop-name) (recur (+ ,idx-bind 1) stop-name))))))
+		(if (< ,idx-bind stop#) (recur (+ ,idx-bind 1) stop#)))))
 
 (defmacro for (bind in_list body)
-	`(let ((,bind))
-		(if (> (length ,in_list) 0)
-			(loop (plist) (,in_list) (progn
-				(setq ,bind (first plist))
+	"Evaluate body for each element of in_list with bind set to it."
+	`(let ((,bind) (list# ,in_list))
+		(if (> (length list#) 0)
+			(loop (plist#) (list#) (progn
+				(setq ,bind (first plist#))
 				(eval ,body)
-				(if (> (length plist) 1) (recur (rest plist))))))))
+				(if (> (length plist#) 1) (recur (rest plist#))))))))
 
 (defmacro fori (idx_bind bind in_list body)
-	`(let ((,bind)(,idx_bind))
-		(if (> (length ,in_list) 0)
-			(loop (plist idx) (,in_list 0) (progn
-				(setq ,bind (first plist))
-				(setq ,idx_bind idx)
+	"Evaluate body for each element of in_list with bind set to it and idx_bind to its index."
+	`(let ((,bind) (,idx_bind) (list# ,in_list))
+		(if (> (length list#) 0)
+			(loop (plist# idx#) (list# 0) (progn
+				(setq ,bind (first plist#))
+				(setq ,idx_bind idx#)
 				(eval ,body)
-				(if (> (length plist) 1) (recur (rest plist) (+ idx 1))))))))
-
-(defmacro match (condition &rest branches)
-	(let ((cond-name) (out_list '()) (make-cond))
-		(setq make-cond (fn (condition val action others)
-			(if (null val) action
-				(if (null others) `(if (= ,condition ,val) ,action)
-					`(if (= ,condition ,val) ,action ,(make-cond condition (first (first others)) (nth 1 (first others)) (rest others)))))))
-		(setq cond-name condition)
-		(make-cond cond-name (first (first branches)) (nth 1 (first branches)) (rest branches))))
+				(if (> (length plist#) 1) (recur (rest plist#) (+ idx# 1))))))))
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{self, WaitStatus};
+use nix::unistd::{self, ForkResult, Pid};
+
+use crate::environment::*;
+use crate::shell::*;
+use crate::types::*;
+
+// Seconds a segment can run before it is killed unless :timeout is given.
+pub const DEFAULT_SEGMENT_TIMEOUT: u64 = 10;
+
+// How long the prompt waits for segments before using their last values.
+const SEGMENT_WAIT_MS: u64 = 50;
+
+// A prompt segment (async-segment) computed in the background so a slow one
+// (git in a big repo) never blocks the prompt.  Its lambda runs in a forked
+// child that writes the result to a pipe, (segment 'name) is the last value
+// computed in the current directory until a new one arrives.
+#[derive(Debug)]
+pub struct Segment {
+    pub lambda: Expression,
+    // Reuse a value this long, None recomputes it for every prompt.
+    pub ttl: Option<Duration>,
+    // Kill the child if it runs longer than this.
+    pub timeout: Duration,
+    // Last value by the directory it was computed in and when.
+    values: HashMap<PathBuf, (String, Instant)>,
+    job: Option<Job>,
+}
+
+#[derive(Debug)]
+struct Job {
+    pid: Pid,
+    output: File,
+    buf: Vec<u8>,
+    cwd: PathBuf,
+    started: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct AsyncSegments {
+    pub segments: HashMap<String, Segment>,
+}
+
+fn kill_job(job: &Job) {
+    // The segment runs in its own process group, get anything it started too.
+    if let Err(err) = signal::killpg(job.pid, Signal::SIGKILL) {
+        eprintln!("Error killing prompt segment {}: {}", job.pid, err);
+    }
+    let _ = wait::waitpid(job.pid, None);
+}
+
+impl Segment {
+    fn is_stale(&self, cwd: &PathBuf) -> bool {
+        if self.job.is_some() {
+            return false;
+        }
+        match (self.values.get(cwd), self.ttl) {
+            (Some((_, updated)), Some(ttl)) => updated.elapsed() >= ttl,
+            _ => true,
+        }
+    }
+
+    // Read what the child wrote so far, true once it is done (or killed).
+    fn read_job(&mut self) -> bool {
+        let job = match &mut self.job {
+            Some(job) => job,
+            None => return true,
+        };
+        let mut chunk = [0; 4096];
+        loop {
+            match job.output.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => job.buf.extend_from_slice(&chunk[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_err) => {
+                    // Nothing more yet, give up on it if it is taking too long.
+                    if job.started.elapsed() >= self.timeout {
+                        kill_job(job);
+                        self.job = None;
+                        return true;
+                    }
+                    return false;
+                }
+            }
+        }
+        if let Ok(WaitStatus::Exited(_, 0)) = wait::waitpid(job.pid, None) {
+            let value = String::from_utf8_lossy(&job.buf).to_string();
+            self.values.insert(job.cwd.clone(), (value, Instant::now()));
+        }
+        self.job = None;
+        true
+    }
+}
+
+impl AsyncSegments {
+    pub fn add(
+        &mut self,
+        name: String,
+        lambda: Expression,
+        ttl: Option<Duration>,
+        timeout: Duration,
+    ) {
+        self.remove(&name);
+        self.segments.insert(
+            name,
+            Segment {
+                lambda,
+                ttl,
+                timeout,
+                values: HashMap::new(),
+                job: None,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        if let Some(segment) = self.segments.remove(name) {
+            if let Some(job) = &segment.job {
+                kill_job(job);
+            }
+        }
+    }
+
+    // The last value of segment name for the current directory.
+    pub fn value(&self, name: &str) -> Option<String> {
+        let cwd = env::current_dir().unwrap_or_default();
+        let segment = self.segments.get(name)?;
+        Some(match segment.values.get(&cwd) {
+            Some((value, _)) => value.clone(),
+            None => String::new(),
+        })
+    }
+
+    // The pipes of the segments still running, readable when they have output.
+    pub fn job_fds(&self) -> Vec<RawFd> {
+        self.segments
+            .values()
+            .filter_map(|segment| segment.job.as_ref())
+            .map(|job| job.output.as_raw_fd())
+            .collect()
+    }
+
+    // Read the running segments, true if one finished with a new value for the
+    // current directory (so the prompt should be drawn again).
+    pub fn update(&mut self) -> bool {
+        let cwd = env::current_dir().unwrap_or_default();
+        let mut changed = false;
+        for segment in self.segments.values_mut() {
+            if segment.job.is_none() {
+                continue;
+            }
+            let before = segment.values.get(&cwd).map(|(value, _)| value.clone());
+            if segment.read_job() {
+                changed |= segment.values.get(&cwd).map(|(value, _)| value) != before.as_ref();
+            }
+        }
+        changed
+    }
+
+    // Wait up to wait for running segments to finish.
+    fn collect(&mut self, wait: Duration) {
+        let start = Instant::now();
+        loop {
+            let mut fds: Vec<PollFd> = Vec::new();
+            for segment in self.segments.values_mut() {
+                if !segment.read_job() {
+                    if let Some(job) = &segment.job {
+                        fds.push(PollFd::new(job.output.as_raw_fd(), PollFlags::POLLIN));
+                    }
+                }
+            }
+            let elapsed = start.elapsed();
+            if fds.is_empty() || elapsed >= wait {
+                return;
+            }
+            let timeout = (wait - elapsed).as_millis() as i32;
+            if let Err(err) = poll(&mut fds, timeout.max(1)) {
+                if err.as_errno() != Some(nix::errno::Errno::EINTR) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn redirect_to_null() -> io::Result<()> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        unistd::dup2(null.as_raw_fd(), fd).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    }
+    Ok(())
+}
+
+// In the forked child, evaluate the segment and write its value to fd.
+fn run_segment(environment: &mut Environment, lambda: Expression, fd: i32) -> ! {
+    environment.is_tty = false;
+    environment.save_exit_status = false;
+    // Keep the commands it runs in its process group so a timeout kills them.
+    environment.state.pipe_pgid = Some(unistd::getpid().as_raw() as u32);
+    let mut out = unsafe { File::from_raw_fd(fd) };
+    let call = Expression::List(vec![Expression::List(vec![
+        Expression::Atom(Atom::Symbol("quote".to_string())),
+        lambda,
+    ])]);
+    let res = unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .and_then(|_| redirect_to_null())
+        .and_then(|_| eval(environment, &call))
+        .and_then(|exp| exp.make_string(environment))
+        .and_then(|value| out.write_all(value.as_bytes()));
+    unsafe { nix::libc::_exit(if res.is_ok() { 0 } else { 1 }) }
+}
+
+fn start_segment(environment: &mut Environment, lambda: Expression) -> io::Result<Job> {
+    let to_io = |e: nix::Error| io::Error::new(io::ErrorKind::Other, e);
+    let cwd = env::current_dir()?;
+    // Close on exec so commands the segment runs do not hold the pipe open.
+    let (read_fd, write_fd) = unistd::pipe2(OFlag::O_CLOEXEC).map_err(to_io)?;
+    match unistd::fork() {
+        Ok(ForkResult::Child) => {
+            let _ = unistd::close(read_fd);
+            run_segment(environment, lambda, write_fd)
+        }
+        Ok(ForkResult::Parent { child }) => {
+            let _ = unistd::close(write_fd);
+            fcntl(read_fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(to_io)?;
+            Ok(Job {
+                pid: child,
+                output: unsafe { File::from_raw_fd(read_fd) },
+                buf: Vec::new(),
+                cwd,
+                started: Instant::now(),
+            })
+        }
+        Err(err) => {
+            let _ = unistd::close(read_fd);
+            let _ = unistd::close(write_fd);
+            Err(to_io(err))
+        }
+    }
+}
+
+// Start the segments that are out of date for the current directory and give
+// them a moment to finish, called before each prompt.
+pub fn refresh_segments(environment: &mut Environment) {
+    let segments = environment.async_segments.clone();
+    let cwd = env::current_dir().unwrap_or_default();
+    let stale: Vec<(String, Expression)> = segments
+        .borrow()
+        .segments
+        .iter()
+        .filter(|(_, segment)| segment.is_stale(&cwd))
+        .map(|(name, segment)| (name.clone(), segment.lambda.clone()))
+        .collect();
+    for (name, lambda) in stale {
+        match start_segment(environment, lambda) {
+            Ok(job) => {
+                if let Some(segment) = segments.borrow_mut().segments.get_mut(&name) {
+                    segment.job = Some(job);
+                }
+            }
+            Err(err) => eprintln!("Error starting prompt segment {}: {}", name, err),
+        }
+    }
+    segments
+        .borrow_mut()
+        .collect(Duration::from_millis(SEGMENT_WAIT_MS));
+}
@@ -6,12 +6,14 @@ use nix::{
     unistd::{self, Pid},
 };
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::hash::BuildHasher;
 use std::io;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use crate::builtins_util::*;
 use crate::config::VERSION_STRING;
@@ -19,6 +21,7 @@ use crate::environment::*;
 use crate::process::*;
 use crate::reader::*;
 use crate::shell::*;
+use crate::spawn::*;
 use crate::types::*;
 
 fn builtin_eval(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
@@ -39,6 +42,72 @@ fn builtin_eval(environment: &mut Environment, args: &[Expression]) -> io::Resul
     }
 }
 
+// Find file_name, relative names not found from the current directory are
+// looked for in the module search path.
+fn find_load_file(file_name: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(file_name);
+    if path.exists() || path.is_absolute() {
+        return Some(path);
+    }
+    find_module_file(file_name)
+}
+
+// Find file_name in the module search path only (never the current directory).
+fn find_module_file(file_name: &str) -> Option<PathBuf> {
+    for mut dir in module_search_path() {
+        dir.push(file_name);
+        if dir.is_file() {
+            return Some(dir);
+        }
+    }
+    None
+}
+
+pub fn load_file(environment: &mut Environment, file_name: &str) -> io::Result<Expression> {
+    let path = match find_load_file(file_name) {
+        Some(path) => path,
+        None => {
+            let msg = format!("load: {} not found", file_name);
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+        }
+    };
+    let contents = fs::read_to_string(&path)?;
+    environment.loaded_files.push(path);
+    let ast = read(&contents);
+    match ast {
+        Ok(ast) => {
+            let ast = match ast {
+                Expression::List(list) => {
+                    if let Some(first) = list.first() {
+                        match first {
+                            Expression::List(_) => {
+                                let mut v = Vec::with_capacity(list.len() + 1);
+                                v.push(Expression::Atom(Atom::Symbol("progn".to_string())));
+                                for l in list {
+                                    v.push(l);
+                                }
+                                Expression::List(v)
+                            }
+                            _ => Expression::List(list),
+                        }
+                    } else {
+                        Expression::List(list)
+                    }
+                }
+                _ => ast,
+            };
+            // A ns in the file only lasts until the end of the file.
+            let old_namespace = environment.current_namespace.clone();
+            let scope_depth = environment.current_scope.len();
+            let res = eval(environment, &ast);
+            environment.current_scope.truncate(scope_depth);
+            environment.current_namespace = old_namespace;
+            res
+        }
+        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.reason)),
+    }
+}
+
 fn builtin_load(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
     let mut args: Vec<Expression> = to_args(environment, args)?;
     if args.len() != 1 {
@@ -47,35 +116,135 @@ fn builtin_load(environment: &mut Environment, args: &[Expression]) -> io::Resul
             "load needs one argument",
         ))
     } else {
-        let contents = fs::read_to_string(args.pop().unwrap().make_string(environment)?)?;
-        let ast = read(&contents);
-        match ast {
-            Ok(ast) => {
-                let ast = match ast {
-                    Expression::List(list) => {
-                        if let Some(first) = list.get(0) {
-                            match first {
-                                Expression::List(_) => {
-                                    let mut v = Vec::with_capacity(list.len() + 1);
-                                    v.push(Expression::Atom(Atom::Symbol("progn".to_string())));
-                                    for l in list {
-                                        v.push(l);
-                                    }
-                                    Expression::List(v)
-                                }
-                                _ => Expression::List(list),
-                            }
-                        } else {
-                            Expression::List(list)
-                        }
-                    }
-                    _ => ast,
-                };
-                eval(environment, &ast)
+        let file_name = args.pop().unwrap().make_string(environment)?;
+        load_file(environment, &file_name)
+    }
+}
+
+fn symbol_name(exp: &Expression, form: &str) -> io::Result<String> {
+    match exp {
+        Expression::Atom(Atom::Symbol(s)) => Ok(s.clone()),
+        Expression::Atom(Atom::String(s)) => Ok(s.clone()),
+        _ => {
+            let msg = format!("{} requires a symbol, got {}", form, exp.to_string());
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+fn add_exports(environment: &mut Environment, exports: &[Expression]) -> io::Result<()> {
+    let ns = match &environment.current_namespace {
+        Some(ns) => ns.clone(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "ns-export can only be used in a namespace",
+            ))
+        }
+    };
+    let mut names = Vec::with_capacity(exports.len());
+    for export in exports {
+        names.push(symbol_name(export, "ns-export")?);
+    }
+    if let Some(namespace) = environment.namespaces.get_mut(&ns) {
+        for name in names {
+            namespace.exports.insert(name);
+        }
+    }
+    Ok(())
+}
+
+fn builtin_ns(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.is_empty() {
+        return match &environment.current_namespace {
+            Some(ns) => Ok(Expression::Atom(Atom::Symbol(ns.clone()))),
+            None => Ok(Expression::Atom(Atom::Nil)),
+        };
+    }
+    let name = symbol_name(&args[0], "ns")?;
+    let exports = match &args[1..] {
+        [] => None,
+        [Expression::Atom(Atom::Symbol(key)), Expression::List(exports)] if key == ":export" => {
+            Some(exports)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "ns takes a name and optionally :export (symbols)",
+            ))
+        }
+    };
+    // A new ns replaces the one in effect instead of stacking on it.
+    if let Some(current) = &environment.current_namespace {
+        let in_namespace = match environment.namespaces.get(current) {
+            Some(namespace) => {
+                Rc::ptr_eq(&namespace.scope, environment.current_scope.last().unwrap())
             }
-            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.reason)),
+            None => false,
+        };
+        if in_namespace && environment.current_scope.len() > 1 {
+            environment.current_scope.pop();
         }
     }
+    enter_namespace(environment, &name);
+    if let Some(exports) = exports {
+        add_exports(environment, exports)?;
+    }
+    Ok(Expression::Atom(Atom::Nil))
+}
+
+fn builtin_ns_export(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    add_exports(environment, args)?;
+    Ok(Expression::Atom(Atom::Nil))
+}
+
+fn builtin_import(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    let (name, alias) = match args {
+        [name] => (symbol_name(name, "import")?, None),
+        [name, Expression::Atom(Atom::Symbol(key)), alias] if key == ":as" => (
+            symbol_name(name, "import")?,
+            Some(symbol_name(alias, "import")?),
+        ),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "import takes a module name and optionally :as alias",
+            ))
+        }
+    };
+    if !environment.namespaces.contains_key(&name) {
+        let file_name = match find_module_file(&format!("{}.lisp", name)) {
+            Some(path) => path.to_string_lossy().to_string(),
+            None => {
+                let msg = format!(
+                    "import: module {} not found in {:?}",
+                    name,
+                    module_search_path()
+                );
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+            }
+        };
+        // Load the module into its own namespace.
+        let old_namespace = environment.current_namespace.clone();
+        let scope_depth = environment.current_scope.len();
+        enter_namespace(environment, &name);
+        let res = load_file(environment, &file_name);
+        environment.current_scope.truncate(scope_depth);
+        environment.current_namespace = old_namespace;
+        res?;
+    }
+    if let Some(alias) = alias {
+        let current = match &environment.current_namespace {
+            Some(ns) => ns.clone(),
+            None => "".to_string(),
+        };
+        environment
+            .ns_imports
+            .entry(current)
+            .or_default()
+            .insert(alias, name);
+    }
+    Ok(Expression::Atom(Atom::Nil))
 }
 
 fn builtin_if(environment: &mut Environment, parts: &[Expression]) -> io::Result<Expression> {
@@ -155,6 +324,10 @@ fn builtin_set(environment: &mut Environment, args: &[Expression]) -> io::Result
             let mut val = match val {
                 Expression::Atom(atom) => Expression::Atom(atom),
                 Expression::List(list) => Expression::List(list),
+                // A spawned lisp form stays a process for wait.
+                Expression::Process(ProcessState::Running(pid)) if is_spawned(environment, pid) => {
+                    Expression::Process(ProcessState::Running(pid))
+                }
                 Expression::Process(ProcessState::Running(_pid)) => Expression::Atom(Atom::String(
                     val.make_string(environment)
                         .unwrap_or_else(|_| "PROCESS FAILED".to_string()),
@@ -269,6 +442,10 @@ fn builtin_def(environment: &mut Environment, args: &[Expression]) -> io::Result
         let mut val = match val {
             Expression::Atom(atom) => Expression::Atom(atom),
             Expression::List(list) => Expression::List(list),
+            // A spawned lisp form stays a process for wait.
+            Expression::Process(ProcessState::Running(pid)) if is_spawned(environment, pid) => {
+                Expression::Process(ProcessState::Running(pid))
+            }
             Expression::Process(ProcessState::Running(_pid)) => Expression::Atom(Atom::String(
                 val.make_string(environment)
                     .unwrap_or_else(|_| "PROCESS FAILED".to_string()),
@@ -293,20 +470,35 @@ fn builtin_def(environment: &mut Environment, args: &[Expression]) -> io::Result
     }
 }
 
+// A docstring is a string between the params and the body.
+fn split_doc<'a>(
+    parts: &'a [Expression],
+    form: &str,
+) -> io::Result<(Option<String>, &'a Expression)> {
+    match parts {
+        [body] => Ok((None, body)),
+        [Expression::Atom(Atom::String(doc)), body] => Ok((Some(doc.clone()), body)),
+        _ => {
+            let msg = format!("{} takes params, an optional docstring and a body", form);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
 fn builtin_fn(environment: &mut Environment, parts: &[Expression]) -> io::Result<Expression> {
-    if parts.len() != 2 {
+    if parts.len() != 2 && parts.len() != 3 {
         Err(io::Error::new(
             io::ErrorKind::Other,
-            "fn can only have two forms",
+            "fn can only have two forms (or three with a docstring)",
         ))
     } else {
-        let mut parts = parts.iter();
-        let params = parts.next().unwrap();
-        let body = parts.next().unwrap();
+        let params = &parts[0];
+        let (doc, body) = split_doc(&parts[1..], "fn")?;
         Ok(Expression::Atom(Atom::Lambda(Lambda {
             params: Box::new(params.clone()),
             body: Box::new(body.clone()),
             capture: environment.current_scope.last().unwrap().clone(),
+            doc,
         })))
     }
 }
@@ -378,45 +570,44 @@ fn builtin_quote(_environment: &mut Environment, args: &[Expression]) -> io::Res
     Ok(args.get(0).unwrap().clone())
 }
 
-fn replace_commas(environment: &mut Environment, list: &[Expression]) -> io::Result<Expression> {
+fn is_auto_gensym(symbol: &str) -> bool {
+    symbol.len() > 1 && symbol.ends_with('#')
+}
+
+// Replace a name# symbol with a gensym, the same name# gets the same gensym
+// within one backquote.
+fn auto_gensym(
+    environment: &mut Environment,
+    symbol: &str,
+    auto_syms: &mut HashMap<String, String>,
+) -> Expression {
+    if let Some(sym) = auto_syms.get(symbol) {
+        return Expression::Atom(Atom::Symbol(sym.clone()));
+    }
+    environment.state.gensym_count += 1;
+    let sym = format!(
+        "gs::{}::{}",
+        &symbol[..symbol.len() - 1],
+        environment.state.gensym_count
+    );
+    auto_syms.insert(symbol.to_string(), sym.clone());
+    Expression::Atom(Atom::Symbol(sym))
+}
+
+fn replace_commas(
+    environment: &mut Environment,
+    list: &[Expression],
+    auto_syms: &mut HashMap<String, String>,
+) -> io::Result<Expression> {
     let mut output: Vec<Expression> = Vec::with_capacity(list.len());
     let mut comma_next = false;
     let mut amp_next = false;
     for exp in list {
-        let exp = if let Expression::List(tlist) = exp {
-            replace_commas(environment, &tlist)?
-        } else {
-            exp.clone()
-        };
-        if let Expression::Atom(Atom::Symbol(symbol)) = &exp {
-            if symbol == "," {
-                comma_next = true;
-            } else if symbol == ",@" {
-                amp_next = true;
-            } else if comma_next {
-                output.push(eval(environment, &exp)?);
-                comma_next = false;
-            } else if amp_next {
-                let nl = eval(environment, &exp)?;
-                if let Expression::List(mut new_list) = nl {
-                    for item in new_list.drain(..) {
-                        output.push(item);
-                    }
-                } else {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        ",@ must be applied to a list",
-                    ));
-                }
-                amp_next = false;
-            } else {
-                output.push(exp);
-            }
-        } else if comma_next {
-            output.push(eval(environment, &exp)?);
+        if comma_next {
+            output.push(eval(environment, exp)?);
             comma_next = false;
         } else if amp_next {
-            let nl = eval(environment, &exp)?;
+            let nl = eval(environment, exp)?;
             if let Expression::List(mut new_list) = nl {
                 for item in new_list.drain(..) {
                     output.push(item);
@@ -429,7 +620,17 @@ fn replace_commas(environment: &mut Environment, list: &[Expression]) -> io::Res
             }
             amp_next = false;
         } else {
-            output.push(exp);
+            match exp {
+                Expression::Atom(Atom::Symbol(symbol)) if symbol == "," => comma_next = true,
+                Expression::Atom(Atom::Symbol(symbol)) if symbol == ",@" => amp_next = true,
+                Expression::Atom(Atom::Symbol(symbol)) if is_auto_gensym(symbol) => {
+                    output.push(auto_gensym(environment, symbol, auto_syms));
+                }
+                Expression::List(tlist) => {
+                    output.push(replace_commas(environment, tlist, auto_syms)?)
+                }
+                _ => output.push(exp.clone()),
+            }
         }
     }
     Ok(Expression::List(output))
@@ -442,33 +643,22 @@ fn builtin_bquote(environment: &mut Environment, args: &[Expression]) -> io::Res
             "bquote takes one form",
         ));
     }
-    if let Expression::List(list) = &args[0] {
-        replace_commas(environment, &list)
-    } else {
-        Ok(args.get(0).unwrap().clone())
+    let mut auto_syms: HashMap<String, String> = HashMap::new();
+    match &args[0] {
+        Expression::List(list) => replace_commas(environment, list, &mut auto_syms),
+        Expression::Atom(Atom::Symbol(symbol)) if is_auto_gensym(symbol) => {
+            Ok(auto_gensym(environment, symbol, &mut auto_syms))
+        }
+        _ => Ok(args[0].clone()),
     }
 }
 
-/*fn builtin_spawn(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
-    let mut new_args: Vec<Expression> = Vec::with_capacity(args.len());
-    for a in args {
-        new_args.push(a.clone());
+fn builtin_spawn(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() != 1 {
+        return Err(io::Error::new(io::ErrorKind::Other, "spawn takes one form"));
     }
-    let mut data: HashMap<String, Expression> = HashMap::new();
-    clone_symbols(
-        &environment.current_scope.last().unwrap().borrow(),
-        &mut data,
-    );
-    let _child = std::thread::spawn(move || {
-        let mut enviro = build_new_spawn_scope(data);
-        let _args = to_args(&mut enviro, &new_args).unwrap();
-        if let Err(err) = reap_procs(&enviro) {
-            eprintln!("Error waiting on spawned processes: {}", err);
-        }
-    });
-    //let res = child.join()
-    Ok(Expression::Atom(Atom::Nil))
-}*/
+    spawn(environment, &args[0])
+}
 
 fn builtin_and(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
     if args.len() < 2 {
@@ -550,20 +740,20 @@ fn builtin_get_type(environment: &mut Environment, args: &[Expression]) -> io::R
 }
 
 fn builtin_defmacro(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
-    if args.len() != 3 {
+    if args.len() != 3 && args.len() != 4 {
         Err(io::Error::new(
             io::ErrorKind::Other,
-            "defmacro can only have three forms (symbol, bindings and body)",
+            "defmacro can only have three forms (symbol, bindings and body) plus an optional docstring",
         ))
     } else {
-        let mut args = args.iter();
-        let name = args.next().unwrap();
-        let params = args.next().unwrap();
-        let body = args.next().unwrap();
+        let name = &args[0];
+        let params = &args[1];
+        let (doc, body) = split_doc(&args[2..], "defmacro")?;
         if let Expression::Atom(Atom::Symbol(s)) = name {
             let m = Rc::new(Expression::Atom(Atom::Macro(Macro {
                 params: Box::new(params.clone()),
                 body: Box::new(body.clone()),
+                doc,
             })));
             set_expression_global(environment, s.clone(), m);
             Ok(Expression::Atom(Atom::Nil))
@@ -576,6 +766,36 @@ fn builtin_defmacro(environment: &mut Environment, args: &[Expression]) -> io::R
     }
 }
 
+// Expand the macro call list one time, None if it is not a macro call.
+fn expand_macro_once(
+    environment: &mut Environment,
+    list: &[Expression],
+) -> io::Result<Option<Expression>> {
+    let (command, parts) = match list.split_first() {
+        Some((Expression::Atom(Atom::Symbol(command)), parts)) => (command, parts),
+        _ => return Ok(None),
+    };
+    let sh_macro = match get_form(environment, command) {
+        Some(exp) => match &*exp {
+            Expression::Atom(Atom::Macro(sh_macro)) => sh_macro.clone(),
+            _ => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+    let new_scope = match environment.current_scope.last() {
+        Some(last) => build_new_scope(Some(last.clone())),
+        None => build_new_scope(None),
+    };
+    environment.current_scope.push(new_scope.clone());
+    if let Err(err) = setup_args(environment, None, &sh_macro.params, parts, false) {
+        environment.current_scope.pop();
+        return Err(err);
+    }
+    let expansion = eval(environment, &sh_macro.body);
+    environment.current_scope.pop();
+    Ok(Some(expansion?))
+}
+
 fn builtin_expand_macro(
     environment: &mut Environment,
     args: &[Expression],
@@ -586,8 +806,14 @@ fn builtin_expand_macro(
             "expand-macro can only have one form (list defining the macro call)",
         ))
     } else if let Expression::List(list) = &args[0] {
-        let (command, parts) = match list.split_first() {
-            Some((c, p)) => (c, p),
+        let command = match list.first() {
+            Some(Expression::Atom(Atom::Symbol(command))) => command,
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "expand-macro first item must be a symbol",
+                ))
+            }
             None => {
                 return Err(io::Error::new(
                     io::ErrorKind::Other,
@@ -595,40 +821,12 @@ fn builtin_expand_macro(
                 ));
             }
         };
-        if let Expression::Atom(Atom::Symbol(command)) = command {
-            if let Some(exp) = get_expression(environment, &command) {
-                if let Expression::Atom(Atom::Macro(sh_macro)) = &*exp {
-                    let new_scope = match environment.current_scope.last() {
-                        Some(last) => build_new_scope(Some(last.clone())),
-                        None => build_new_scope(None),
-                    };
-                    environment.current_scope.push(new_scope.clone());
-                    if let Err(err) = setup_args(environment, None, &sh_macro.params, parts, false)
-                    {
-                        environment.current_scope.pop();
-                        return Err(err);
-                    }
-                    let expansion = eval(environment, &sh_macro.body);
-                    if let Err(err) = expansion {
-                        environment.current_scope.pop();
-                        return Err(err);
-                    }
-                    let expansion = expansion.unwrap();
-                    environment.current_scope.pop();
-                    Ok(expansion)
-                } else {
-                    let msg = format!("expand-macro: {} not a macro", command);
-                    Err(io::Error::new(io::ErrorKind::Other, msg))
-                }
-            } else {
+        match expand_macro_once(environment, list)? {
+            Some(expansion) => Ok(expansion),
+            None => {
                 let msg = format!("expand-macro: {} not a macro", command);
                 Err(io::Error::new(io::ErrorKind::Other, msg))
             }
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "expand-macro first item must be a symbol",
-            ))
         }
     } else {
         Err(io::Error::new(
@@ -638,6 +836,61 @@ fn builtin_expand_macro(
     }
 }
 
+fn builtin_macroexpand_1(
+    environment: &mut Environment,
+    args: &[Expression],
+) -> io::Result<Expression> {
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "macroexpand-1 takes one form",
+        ));
+    }
+    if let Expression::List(list) = &args[0] {
+        if let Some(expansion) = expand_macro_once(environment, list)? {
+            return Ok(expansion);
+        }
+    }
+    Ok(args[0].clone())
+}
+
+fn macroexpand_all(environment: &mut Environment, form: &Expression) -> io::Result<Expression> {
+    let mut form = form.clone();
+    while let Expression::List(list) = &form {
+        match expand_macro_once(environment, list)? {
+            Some(expansion) => form = expansion,
+            None => break,
+        }
+    }
+    if let Expression::List(list) = &form {
+        // Quoted forms are data, leave them alone.
+        if let Some(Expression::Atom(Atom::Symbol(s))) = list.first() {
+            if s == "quote" || s == "bquote" {
+                return Ok(form);
+            }
+        }
+        let mut output: Vec<Expression> = Vec::with_capacity(list.len());
+        for exp in list {
+            output.push(macroexpand_all(environment, exp)?);
+        }
+        return Ok(Expression::List(output));
+    }
+    Ok(form)
+}
+
+fn builtin_macroexpand_all(
+    environment: &mut Environment,
+    args: &[Expression],
+) -> io::Result<Expression> {
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "macroexpand-all takes one form",
+        ));
+    }
+    macroexpand_all(environment, &args[0])
+}
+
 fn builtin_recur(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
     let mut args = to_args(environment, args)?;
     let mut arg_list: Vec<Expression> = Vec::with_capacity(args.len());
@@ -806,6 +1059,137 @@ fn builtin_loose_symbols(
     Ok(last_eval)
 }
 
+// User and system time of the shell's children that have been waited for.
+fn children_times() -> (Duration, Duration) {
+    let mut usage: nix::libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { nix::libc::getrusage(nix::libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+        return (Duration::default(), Duration::default());
+    }
+    let duration = |tv: nix::libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    (duration(usage.ru_utime), duration(usage.ru_stime))
+}
+
+fn format_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    format!("{}m{}.{:03}s", secs / 60, secs % 60, time.subsec_millis())
+}
+
+// (time form) evaluates form and reports the wall time it took and the user
+// and system time of the commands it ran on stderr.
+fn builtin_time(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() != 1 {
+        return Err(io::Error::new(io::ErrorKind::Other, "time takes one form"));
+    }
+    let start = Instant::now();
+    let (user_start, sys_start) = children_times();
+    let procs_before: HashSet<u32> = environment.procs.borrow().keys().cloned().collect();
+    let res = eval(environment, &args[0]);
+    let real = start.elapsed();
+    // Reap the earlier commands of a pipeline that are done so they count.
+    let started: Vec<u32> = environment
+        .procs
+        .borrow()
+        .keys()
+        .filter(|pid| !procs_before.contains(pid))
+        .cloned()
+        .collect();
+    for pid in started {
+        try_wait_pid(environment, pid);
+    }
+    let (user_end, sys_end) = children_times();
+    let user = user_end - user_start;
+    let sys = sys_end - sys_start;
+    eprintln!(
+        "\nreal\t{}\nuser\t{}\nsys\t{}",
+        format_time(real),
+        format_time(user),
+        format_time(sys)
+    );
+    res
+}
+
+fn hook_name(environment: &mut Environment, exp: &Expression, form: &str) -> io::Result<String> {
+    match eval(environment, exp)? {
+        Expression::Atom(Atom::Symbol(name)) | Expression::Atom(Atom::String(name))
+            if HOOK_NAMES.contains(&name.as_str()) =>
+        {
+            Ok(name)
+        }
+        name => {
+            let msg = format!(
+                "{}: unknown hook {}, use one of {}",
+                form,
+                name.to_string(),
+                HOOK_NAMES.join(", ")
+            );
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// Lambdas are the same hook if they came from the same definition.
+fn same_lambda(a: &Expression, b: &Expression) -> bool {
+    match (a, b) {
+        (Expression::Atom(Atom::Lambda(a)), Expression::Atom(Atom::Lambda(b))) => {
+            Rc::ptr_eq(&a.capture, &b.capture)
+                && a.params.to_string() == b.params.to_string()
+                && a.body.to_string() == b.body.to_string()
+        }
+        _ => false,
+    }
+}
+
+// (add-hook 'precmd (fn (status duration) ...)), adding it again does nothing.
+fn builtin_add_hook(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "add-hook takes a hook name and a function",
+        ));
+    }
+    let name = hook_name(environment, &args[0], "add-hook")?;
+    let hook = eval(environment, &args[1])?;
+    if let Expression::Atom(Atom::Lambda(_)) = hook {
+        let hooks = environment.hooks.entry(name).or_default();
+        if !hooks.iter().any(|h| same_lambda(h, &hook)) {
+            hooks.push(hook);
+        }
+        Ok(Expression::Atom(Atom::True))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "add-hook: requires a lambda",
+        ))
+    }
+}
+
+// (remove-hook 'precmd f) removes f, (remove-hook 'precmd) all of them.
+fn builtin_remove_hook(
+    environment: &mut Environment,
+    args: &[Expression],
+) -> io::Result<Expression> {
+    if args.is_empty() || args.len() > 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "remove-hook takes a hook name and optionally a function",
+        ));
+    }
+    let name = hook_name(environment, &args[0], "remove-hook")?;
+    let hook = match args.get(1) {
+        Some(hook) => Some(eval(environment, hook)?),
+        None => None,
+    };
+    if let Some(hooks) = environment.hooks.get_mut(&name) {
+        match hook {
+            Some(hook) => hooks.retain(|h| !same_lambda(h, &hook)),
+            None => hooks.clear(),
+        }
+    }
+    Ok(Expression::Atom(Atom::True))
+}
+
 macro_rules! ensure_tonicity {
     ($check_fn:expr, $values:expr, $type:ty, $type_two:ty) => {{
         let first = $values.first().ok_or(io::Error::new(
@@ -846,6 +1230,15 @@ macro_rules! ensure_tonicity_all {
 pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
     data.insert("eval".to_string(), Rc::new(Expression::Func(builtin_eval)));
     data.insert("load".to_string(), Rc::new(Expression::Func(builtin_load)));
+    data.insert("ns".to_string(), Rc::new(Expression::Func(builtin_ns)));
+    data.insert(
+        "ns-export".to_string(),
+        Rc::new(Expression::Func(builtin_ns_export)),
+    );
+    data.insert(
+        "import".to_string(),
+        Rc::new(Expression::Func(builtin_import)),
+    );
     data.insert("if".to_string(), Rc::new(Expression::Func(builtin_if)));
     data.insert(
         "print".to_string(),
@@ -899,6 +1292,14 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         "expand-macro".to_string(),
         Rc::new(Expression::Func(builtin_expand_macro)),
     );
+    data.insert(
+        "macroexpand-1".to_string(),
+        Rc::new(Expression::Func(builtin_macroexpand_1)),
+    );
+    data.insert(
+        "macroexpand-all".to_string(),
+        Rc::new(Expression::Func(builtin_macroexpand_all)),
+    );
     data.insert(
         "recur".to_string(),
         Rc::new(Expression::Func(builtin_recur)),
@@ -921,11 +1322,24 @@ pub fn add_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S
         "run-bg".to_string(),
         Rc::new(Expression::Func(builtin_run_bg)),
     );
+    data.insert(
+        "spawn".to_string(),
+        Rc::new(Expression::Func(builtin_spawn)),
+    );
     data.insert("form".to_string(), Rc::new(Expression::Func(builtin_form)));
     data.insert(
         "loose-symbols".to_string(),
         Rc::new(Expression::Func(builtin_loose_symbols)),
     );
+    data.insert("time".to_string(), Rc::new(Expression::Func(builtin_time)));
+    data.insert(
+        "add-hook".to_string(),
+        Rc::new(Expression::Func(builtin_add_hook)),
+    );
+    data.insert(
+        "remove-hook".to_string(),
+        Rc::new(Expression::Func(builtin_remove_hook)),
+    );
 
     data.insert(
         "=".to_string(),
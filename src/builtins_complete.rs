@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::completions::*;
+use crate::environment::*;
+use crate::shell::*;
+use crate::types::*;
+
+const USAGE: &str = "complete takes a command then :subcommands, :options or :args with a value";
+
+fn candidates_from_exp(exp: &Expression, key: &str) -> io::Result<Vec<Candidate>> {
+    match exp {
+        Expression::List(list) => Ok(list.iter().map(candidate_from_exp).collect()),
+        Expression::Atom(Atom::Nil) => Ok(Vec::new()),
+        _ => {
+            let msg = format!("complete: {} requires a list, got {}", key, exp.to_string());
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// (complete 'git :subcommands ("commit" ("checkout" "Switch branches")) :options (...) :args fn)
+// :options can also be 'man or 'help to parse them from the man page or --help.
+// With just a command removes its spec.
+fn builtin_complete(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other, USAGE));
+    }
+    let command = match eval(environment, &args[0])? {
+        Expression::Atom(Atom::Symbol(s)) | Expression::Atom(Atom::String(s)) => s,
+        exp => {
+            let msg = format!("complete requires a command name, got {}", exp.to_string());
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    if args.len() == 1 {
+        environment.completion_specs.remove(&command);
+        return Ok(Expression::Atom(Atom::Nil));
+    }
+    let mut spec = CompletionSpec {
+        subcommands: Vec::new(),
+        options: Vec::new(),
+        help_options: false,
+        args: None,
+    };
+    for pair in args[1..].chunks(2) {
+        let key = match pair {
+            [Expression::Atom(Atom::Symbol(key)), _] => key.as_str(),
+            [_, _] => "",
+            _ => return Err(io::Error::new(io::ErrorKind::Other, USAGE)),
+        };
+        let val = eval(environment, &pair[1])?;
+        match key {
+            ":subcommands" => spec.subcommands = candidates_from_exp(&val, key)?,
+            ":options" => match &val {
+                Expression::Atom(Atom::Symbol(s)) if s == "man" || s == "help" => {
+                    spec.help_options = s == "help";
+                }
+                _ => spec.options = candidates_from_exp(&val, key)?,
+            },
+            ":args" => match val {
+                Expression::Atom(Atom::Lambda(_)) | Expression::Func(_) => spec.args = Some(val),
+                Expression::Atom(Atom::Nil) => spec.args = None,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "complete: :args requires a function",
+                    ))
+                }
+            },
+            _ => {
+                let msg = format!("complete: unknown key {}", pair[0].to_string());
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    environment.completion_specs.insert(command, spec);
+    Ok(Expression::Atom(Atom::True))
+}
+
+pub fn add_complete_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "complete".to_string(),
+        Rc::new(Expression::Func(builtin_complete)),
+    );
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::environment::*;
+use crate::shell::*;
+use crate::types::*;
+
+// Signature and docstring for the builtins (lambdas and macros carry their own).
+const BUILTIN_DOCS: &[(&str, &str, &str)] = &[
+    ("eval", "form", "Evaluate form (or a string containing a form)."),
+    ("load", "file", "Load a lisp file, relative names not found are looked for in the module search path."),
+    ("ns", "[name [:export (symbols)]]", "Start namespace name for the rest of the file, returns the current namespace with no name."),
+    ("ns-export", "symbol+", "Export symbols from the current namespace."),
+    ("import", "module [:as alias]", "Load module.lisp from the module search path into namespace module."),
+    ("if", "test then [else]", "Evaluate then if test is t otherwise else."),
+    ("print", "form*", "Print the forms to stdout."),
+    ("println", "form*", "Print the forms to stdout followed by a newline."),
+    ("format", "form*", "Produce a string by concatenating the forms."),
+    ("progn", "form*", "Evaluate each form in turn, returns the last result."),
+    ("set", "symbol value", "Set symbol to value in the scope it is defined in."),
+    ("export", "symbol value", "Set the environment variable symbol to value."),
+    ("def", "symbol value", "Define symbol to value in the current scope."),
+    ("fn", "params [doc] body", "Create a lambda, set it into a symbol to give it a name (see defn)."),
+    ("let", "((symbol value)*) form*", "Evaluate the forms in a new scope with the bindings."),
+    ("quote", "form", "Return form without evaluating it."),
+    ("bquote", "form", "Backquote, return form without evaluating it except for , and ,@ forms."),
+    ("and", "form*", "Evaluate forms until one is nil, returns the last result."),
+    ("or", "form*", "Evaluate forms until one is not nil, returns that result."),
+    ("not", "form", "Return t if form is nil, otherwise nil."),
+    ("null", "form", "Return t if form is nil, otherwise nil."),
+    ("is-def", "symbol", "Return t if symbol is defined for the current scope."),
+    ("get-type", "form", "Evaluate form and return its type as a string."),
+    ("defmacro", "name params [doc] body", "Define a macro."),
+    ("expand-macro", "form", "Expand the macro call form."),
+    ("macroexpand-1", "form", "Expand a macro call one time, returns form unchanged if it is not a macro call."),
+    ("macroexpand-all", "form", "Expand all macro calls in form (not inside quoted forms)."),
+    ("recur", "arg*", "Call the enclosing lambda again with new args (tail call)."),
+    ("gensym", "", "Generate a unique symbol."),
+    ("jobs", "", "List the background jobs."),
+    ("fg", "", "Put the last background job in the foreground."),
+    ("version", "", "Return the version string."),
+    ("command", "form", "Run form only as an external command (not a lisp form)."),
+    ("run-bg", "form", "Run the command form in the background."),
+    ("spawn", "form", "Evaluate form in a forked child, returns a process with its output (wait returns its value)."),
+    ("form", "form", "Run form only as a lisp form (not an external command)."),
+    ("loose-symbols", "form*", "Evaluate the forms with undefined symbols treated as strings."),
+    ("=", "form form+", "Return t if all the forms are equal."),
+    (">", "form form+", "Return t if the forms are in decreasing order."),
+    (">=", "form form+", "Return t if the forms are in non-increasing order."),
+    ("<", "form form+", "Return t if the forms are in increasing order."),
+    ("<=", "form form+", "Return t if the forms are in non-decreasing order."),
+    ("complete", "command [:subcommands list] [:options list] [:args fn]", "Set the completion spec for command, with no other arguments it is removed."),
+    ("doc", "symbol", "Return the signature and docstring for symbol."),
+    ("glob", "pattern+", "Return a list of the paths matching the patterns (with brace expansion)."),
+    ("which", "command", "Return the path of the executable for command, nil if not in $PATH."),
+    ("type", "command", "Describe what command runs (builtin, lambda, macro or executable path)."),
+    ("rehash", "", "Forget the cached $PATH executables, they are rescanned on the next lookup."),
+    ("set-keymap", "keymap", "Use the 'vi or 'emacs key bindings for the line editor."),
+    ("bind-key", "key fn", "Call fn with the buffer and cursor when key (x, C-x, M-x or F1-F12) is pressed, nil removes the binding."),
+    ("set-history", "[:size int] [:file-size int] [:dedupe t/nil] [:share t/nil]", "Set the history size and options."),
+    ("history", "[:match string] [:here t] [:cwd dir] [:success t] [:limit int]", "Return matching history entries, oldest first, as (command time cwd status duration-ms) lists."),
+    ("async-segment", "name fn [:ttl int] [:timeout int]", "Compute a prompt segment in the background with fn, nil as fn removes it."),
+    ("segment", "name", "Return the last value of prompt segment name for the current directory."),
+    ("time", "form", "Evaluate form and print the real, user and system time it took to stderr."),
+    ("add-hook", "hook fn", "Run fn on hook (preexec, precmd, chpwd, exit or command-not-found)."),
+    ("remove-hook", "hook [fn]", "Remove fn (or every function) from hook."),
+    ("cd", "path", "Change to the directory path."),
+    ("use-stdout", "form*", "Evaluate the forms with commands writing to the shell's stdout and stderr instead of being captured (unless in a pipe)."),
+    ("out-null", "form*", "Evaluate the forms with stdout going to null."),
+    ("err-null", "form*", "Evaluate the forms with stderr going to null."),
+    ("file-rdr", "file form", "Evaluate form with stdin read from file."),
+    ("stdout-to", "file form*", "Evaluate the forms with stdout appended to file."),
+    ("stderr-to", "file form*", "Evaluate the forms with stderr appended to file."),
+    ("fd-to", "fd file form+", "Evaluate the forms with fd of external commands appended to file."),
+    ("fd-from", "fd file form+", "Evaluate the forms with fd of external commands read from file."),
+    ("fd-dup", "fd source form+", "Evaluate the forms with fd of external commands a copy of source (2>&1 is (fd-dup 2 1 ...))."),
+    ("fd-close", "fd form+", "Evaluate the forms with fd closed for external commands."),
+    ("file-trunc", "file", "Truncate (or create) file."),
+    ("path-exists", "path", "Return t if path exists."),
+    ("is-file", "path", "Return t if path is a file."),
+    ("is-dir", "path", "Return t if path is a directory."),
+    ("pipe", "form+", "Create a pipe (job) consisting of the forms, sets *pipe-status* to the status of each."),
+    ("wait", "form", "Wait for a process to finish and return its exit status (a list of every stage's for a pipe, the value of a spawned form)."),
+    ("pid", "form", "Return the pid of a form that resolves to a process."),
+    ("psub<", "form", "Run form in the background and return /dev/fd/N to read its output from (process substitution)."),
+    ("psub>", "form", "Run form in the background and return /dev/fd/N to write its input to (process substitution)."),
+    ("pmap", "fn list [:jobs int]", "Call fn (which runs a command) on each item in parallel, at most :jobs at once, return (output status) for each in order."),
+    ("list", "form*", "Produce a list with the forms as elements."),
+    ("first", "list", "Produce the first element of list, nil if it is empty."),
+    ("rest", "list", "Produce list minus the first element, nil if it is empty or one element."),
+    ("length", "list/string", "Return the length of a list or string."),
+    ("last", "list", "Produce the last element of list, nil if it is empty."),
+    ("butlast", "list", "Produce list minus the last element, nil if it is empty or one element."),
+    ("nth", "index list", "Produce the element at index, error if index is out of bounds."),
+    ("setfirst", "form list", "Produce a new list with form as the first element."),
+    ("setrest", "list list", "Produce a new list with the first element of the first list and the rest from the second."),
+    ("setlast", "list form", "Produce a new list with form appended to list."),
+    ("setbutlast", "list list", "Produce a new list with the last element of the second list and the rest from the first."),
+    ("setnth", "index form list", "Produce a new list with the element at index replaced by form."),
+    ("append", "list list", "Produce a new list by appending the second list onto the first."),
+    ("push", "list form", "Push form onto the end of list (modifies list)."),
+    ("pop", "list", "Remove and return the last element of list (modifies list)."),
+    ("match", "form (pattern [:when guard] form*)+", "Evaluate the forms for the first pattern matching form."),
+    ("cond", "(test form*)+", "Evaluate the forms for the first clause whose test is not nil."),
+    ("case", "form (key(s) form*)+", "Evaluate the forms for the first clause with a key equal to form."),
+    ("+", "number number+", "Addition."),
+    ("*", "number number+", "Multiplication."),
+    ("-", "number number+", "Subtraction."),
+    ("/", "number number+", "Division."),
+    ("str-trim", "string", "Trim both left and right of string."),
+    ("str-ltrim", "string", "Left trim string."),
+    ("str-rtrim", "string", "Right trim string."),
+    ("str-replace", "string old new", "Produce a new string with all occurrences of old replaced with new."),
+    ("str-split", "split_string string", "Produce a list by splitting string on split_string."),
+    ("str-cat-list", "string list", "Produce a string by joining list with string as a divider."),
+    ("str-sub", "index length string", "Return a substring of string."),
+];
+
+fn builtin_signature_doc(name: &str) -> Option<(String, String)> {
+    for (builtin, args, doc) in BUILTIN_DOCS {
+        if *builtin == name {
+            let signature = if args.is_empty() {
+                format!("({})", name)
+            } else {
+                format!("({} {})", name, args)
+            };
+            return Some((signature, doc.to_string()));
+        }
+    }
+    None
+}
+
+fn params_signature(name: &str, params: &Expression) -> String {
+    match params {
+        Expression::List(params) if !params.is_empty() => {
+            let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+            format!("({} {})", name, params.join(" "))
+        }
+        _ => format!("({})", name),
+    }
+}
+
+// The signature and docstring (empty if none) for the function or macro value.
+pub fn expression_doc(name: &str, exp: &Expression) -> Option<(String, String)> {
+    let (params, doc) = match exp {
+        Expression::Atom(Atom::Lambda(l)) => (&l.params, &l.doc),
+        Expression::Atom(Atom::Macro(m)) => (&m.params, &m.doc),
+        Expression::Func(_) => return builtin_signature_doc(name),
+        _ => return None,
+    };
+    let doc = match doc {
+        Some(doc) => doc.clone(),
+        None => String::new(),
+    };
+    Some((params_signature(name, params), doc))
+}
+
+fn builtin_doc(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() != 1 {
+        return Err(io::Error::new(io::ErrorKind::Other, "doc takes one symbol"));
+    }
+    let name = match &args[0] {
+        Expression::Atom(Atom::Symbol(s)) => s.clone(),
+        arg => match eval(environment, arg)? {
+            Expression::Atom(Atom::Symbol(s)) | Expression::Atom(Atom::String(s)) => s,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "doc takes one symbol")),
+        },
+    };
+    let exp = match get_form(environment, &name) {
+        Some(exp) => exp,
+        None => {
+            let msg = format!("doc: {} not found", name);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    match expression_doc(&name, &exp) {
+        Some((signature, doc)) if doc.is_empty() => Ok(Expression::Atom(Atom::String(signature))),
+        Some((signature, doc)) => Ok(Expression::Atom(Atom::String(format!(
+            "{}\n\n{}",
+            signature, doc
+        )))),
+        None => Ok(Expression::Atom(Atom::Nil)),
+    }
+}
+
+pub fn add_doc_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert("doc".to_string(), Rc::new(Expression::Func(builtin_doc)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_docs() {
+        let data = Scope::default().data;
+        let mut undocumented: Vec<&String> = data
+            .iter()
+            .filter(|(name, exp)| {
+                matches!(***exp, Expression::Func(_)) && builtin_signature_doc(name).is_none()
+            })
+            .map(|(name, _)| name)
+            .collect();
+        undocumented.sort();
+        assert!(
+            undocumented.is_empty(),
+            "no BUILTIN_DOCS entry for {:?}",
+            undocumented
+        );
+        for (name, _, _) in BUILTIN_DOCS {
+            assert!(
+                matches!(data.get(*name).map(|exp| &**exp), Some(Expression::Func(_))),
+                "BUILTIN_DOCS entry {} is not a builtin",
+                name
+            );
+        }
+        let mut names: Vec<&str> = BUILTIN_DOCS.iter().map(|(name, _, _)| *name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(
+            names.len(),
+            BUILTIN_DOCS.len(),
+            "duplicate BUILTIN_DOCS entry"
+        );
+    }
+}
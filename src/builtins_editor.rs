@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::env;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::async_segments::*;
+use crate::environment::*;
+use crate::line_editor::*;
+use crate::shell::*;
+use crate::types::*;
+
+fn symbol_or_string(environment: &mut Environment, exp: &Expression) -> io::Result<String> {
+    match eval(environment, exp)? {
+        Expression::Atom(Atom::Symbol(s)) | Expression::Atom(Atom::String(s)) => Ok(s),
+        exp => {
+            let msg = format!("expected a symbol or string, got {}", exp.to_string());
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// (set-keymap 'emacs) or (set-keymap 'vi)
+fn builtin_set_keymap(
+    environment: &mut Environment,
+    args: &[Expression],
+) -> io::Result<Expression> {
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "set-keymap takes one form ('vi or 'emacs)",
+        ));
+    }
+    match &symbol_or_string(environment, &args[0])?[..] {
+        "emacs" => environment.editor_config.emacs = true,
+        "vi" => environment.editor_config.emacs = false,
+        keymap => {
+            let msg = format!("set-keymap: unknown keymap {}, use 'vi or 'emacs", keymap);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    }
+    Ok(Expression::Atom(Atom::True))
+}
+
+// (bind-key "C-t" (fn (buffer cursor) ...)), nil as the function removes the binding.
+fn builtin_bind_key(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "bind-key takes a key name and a function",
+        ));
+    }
+    let name = symbol_or_string(environment, &args[0])?;
+    let key = match parse_key_name(&name) {
+        Some(key) => key,
+        None => {
+            let msg = format!("bind-key: invalid key {}, use x, C-x, M-x or F1-F12", name);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    match eval(environment, &args[1])? {
+        Expression::Atom(Atom::Nil) => {
+            environment.editor_config.bindings.remove(&key);
+        }
+        binding @ Expression::Atom(Atom::Lambda(_)) => {
+            environment.editor_config.bindings.insert(key, binding);
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "bind-key: requires a lambda or nil",
+            ))
+        }
+    }
+    Ok(Expression::Atom(Atom::True))
+}
+
+fn history_size(key: &str, val: &Expression) -> io::Result<Option<usize>> {
+    match val {
+        Expression::Atom(Atom::Int(i)) if *i > 0 => Ok(Some(*i as usize)),
+        Expression::Atom(Atom::Nil) => Ok(None),
+        _ => {
+            let msg = format!("set-history: {} requires a positive int", key);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// (set-history :size 1000 :file-size 10000 :dedupe t :share nil)
+fn builtin_set_history(
+    environment: &mut Environment,
+    args: &[Expression],
+) -> io::Result<Expression> {
+    for pair in args.chunks(2) {
+        let key = match pair {
+            [Expression::Atom(Atom::Symbol(key)), _] => key.as_str(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "set-history takes :size, :file-size, :dedupe or :share with a value",
+                ))
+            }
+        };
+        let val = eval(environment, &pair[1])?;
+        let is_true = !matches!(val, Expression::Atom(Atom::Nil));
+        let config = &mut environment.editor_config;
+        match key {
+            ":size" => config.history_size = history_size(key, &val)?,
+            ":file-size" => config.history_file_size = history_size(key, &val)?,
+            ":dedupe" => config.history_dedupe = is_true,
+            ":share" => config.history_share = is_true,
+            _ => {
+                let msg = format!("set-history: unknown key {}", key);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    Ok(Expression::Atom(Atom::True))
+}
+
+// (history :match "git" :here t :success t :limit 10) returns matching entries
+// oldest first as (command time cwd status duration-ms) lists.
+fn builtin_history(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    let mut text = String::new();
+    let mut cwd: Option<String> = None;
+    let mut success = false;
+    let mut limit: Option<usize> = None;
+    for pair in args.chunks(2) {
+        let key = match pair {
+            [Expression::Atom(Atom::Symbol(key)), _] => key.as_str(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "history takes :match, :here, :cwd, :success or :limit with a value",
+                ))
+            }
+        };
+        let val = eval(environment, &pair[1])?;
+        let is_true = !matches!(val, Expression::Atom(Atom::Nil));
+        match (key, val) {
+            (":match", val) => text = val.make_string(environment)?,
+            (":here", _) if is_true => {
+                cwd = Some(env::current_dir()?.to_string_lossy().to_string());
+            }
+            (":here", _) => {}
+            (":cwd", val) => cwd = Some(val.make_string(environment)?),
+            (":success", _) => success = is_true,
+            (":limit", Expression::Atom(Atom::Int(i))) if i >= 0 => limit = Some(i as usize),
+            (":limit", _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "history: :limit requires a positive int",
+                ))
+            }
+            _ => {
+                let msg = format!("history: unknown key {}", key);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    let history = environment.history.clone();
+    let history = history.borrow();
+    let mut entries: Vec<Expression> = history
+        .entries
+        .iter()
+        .filter(|e| e.command.contains(&text))
+        .filter(|e| cwd.is_none() || cwd.as_ref() == Some(&e.cwd))
+        .filter(|e| !success || e.status == 0)
+        .map(|e| {
+            Expression::List(vec![
+                Expression::Atom(Atom::String(e.command.clone())),
+                Expression::Atom(Atom::Int(e.time as i64)),
+                Expression::Atom(Atom::String(e.cwd.clone())),
+                Expression::Atom(Atom::Int(e.status)),
+                Expression::Atom(Atom::Int(e.duration_ms as i64)),
+            ])
+        })
+        .collect();
+    if let Some(limit) = limit {
+        if entries.len() > limit {
+            entries.drain(..entries.len() - limit);
+        }
+    }
+    Ok(Expression::List(entries))
+}
+
+fn seconds(key: &str, val: &Expression) -> io::Result<Duration> {
+    match val {
+        Expression::Atom(Atom::Int(i)) if *i > 0 => Ok(Duration::from_secs(*i as u64)),
+        _ => {
+            let msg = format!("async-segment: {} requires a positive int (seconds)", key);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// (async-segment 'git (fn () ...) :ttl 5 :timeout 10), nil as the function
+// removes the segment.
+fn builtin_async_segment(
+    environment: &mut Environment,
+    args: &[Expression],
+) -> io::Result<Expression> {
+    if args.len() < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "async-segment takes a name, a function and optionally :ttl and :timeout",
+        ));
+    }
+    let name = symbol_or_string(environment, &args[0])?;
+    let lambda = match eval(environment, &args[1])? {
+        Expression::Atom(Atom::Nil) => {
+            environment.async_segments.borrow_mut().remove(&name);
+            return Ok(Expression::Atom(Atom::True));
+        }
+        lambda @ Expression::Atom(Atom::Lambda(_)) => lambda,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "async-segment: requires a lambda or nil",
+            ))
+        }
+    };
+    let mut ttl = None;
+    let mut timeout = Duration::from_secs(DEFAULT_SEGMENT_TIMEOUT);
+    for pair in args[2..].chunks(2) {
+        let val = match pair {
+            [_, val] => eval(environment, val)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "async-segment: :ttl and :timeout need a value",
+                ))
+            }
+        };
+        match &pair[0] {
+            Expression::Atom(Atom::Symbol(key)) if key == ":ttl" => ttl = Some(seconds(key, &val)?),
+            Expression::Atom(Atom::Symbol(key)) if key == ":timeout" => {
+                timeout = seconds(key, &val)?
+            }
+            key => {
+                let msg = format!("async-segment: unknown key {}", key.to_string());
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    environment
+        .async_segments
+        .borrow_mut()
+        .add(name, lambda, ttl, timeout);
+    Ok(Expression::Atom(Atom::True))
+}
+
+// (segment 'git) is the last value of the segment, "" until one arrives.
+fn builtin_segment(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "segment takes one form (the segment name)",
+        ));
+    }
+    let name = symbol_or_string(environment, &args[0])?;
+    let value = environment.async_segments.borrow().value(&name);
+    match value {
+        Some(value) => Ok(Expression::Atom(Atom::String(value))),
+        None => {
+            let msg = format!("segment: unknown segment {}", name);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+pub fn add_editor_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "set-keymap".to_string(),
+        Rc::new(Expression::Func(builtin_set_keymap)),
+    );
+    data.insert(
+        "bind-key".to_string(),
+        Rc::new(Expression::Func(builtin_bind_key)),
+    );
+    data.insert(
+        "set-history".to_string(),
+        Rc::new(Expression::Func(builtin_set_history)),
+    );
+    data.insert(
+        "history".to_string(),
+        Rc::new(Expression::Func(builtin_history)),
+    );
+    data.insert(
+        "async-segment".to_string(),
+        Rc::new(Expression::Func(builtin_async_segment)),
+    );
+    data.insert(
+        "segment".to_string(),
+        Rc::new(Expression::Func(builtin_segment)),
+    );
+}
@@ -9,8 +9,10 @@ use std::rc::Rc;
 use crate::builtins::*;
 use crate::builtins_util::*;
 use crate::environment::*;
+use crate::globbing::*;
 use crate::process::*;
 use crate::shell::*;
+use crate::spawn::*;
 use crate::types::*;
 
 fn cd_expand_all_dots(cd: String) -> String {
@@ -65,12 +67,22 @@ fn builtin_cd(environment: &mut Environment, args: &[Expression]) -> io::Result<
         let new_dir = if new_dir == "-" { &old_dir } else { new_dir };
         let new_dir = cd_expand_all_dots(new_dir.to_string());
         let root = Path::new(&new_dir);
-        env::set_var("OLDPWD", env::current_dir()?);
+        let old_pwd = env::current_dir()?;
+        env::set_var("OLDPWD", &old_pwd);
         if let Err(e) = env::set_current_dir(&root) {
             eprintln!("Error changing to {}, {}", root.display(), e);
             Ok(Expression::Atom(Atom::Nil))
         } else {
-            env::set_var("PWD", env::current_dir()?);
+            let pwd = env::current_dir()?;
+            env::set_var("PWD", &pwd);
+            run_hooks(
+                environment,
+                "chpwd",
+                &[
+                    Expression::Atom(Atom::String(old_pwd.to_string_lossy().to_string())),
+                    Expression::Atom(Atom::String(pwd.to_string_lossy().to_string())),
+                ],
+            );
             Ok(Expression::Atom(Atom::True))
         }
     }
@@ -197,6 +209,84 @@ fn builtin_stderr_to(environment: &mut Environment, args: &[Expression]) -> io::
     internal_output_to(environment, args, "stderr-to", false)
 }
 
+fn fd_arg(environment: &mut Environment, arg: &Expression, name: &str) -> io::Result<i32> {
+    let fd = eval(environment, arg)?.make_int(environment)?;
+    if fd < 0 || fd > i64::from(i32::MAX) {
+        let msg = format!("{} needs a file descriptor number, got {}", name, fd);
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    Ok(fd as i32)
+}
+
+fn file_arg(environment: &mut Environment, arg: &Expression, name: &str) -> io::Result<String> {
+    if let Expression::Atom(Atom::String(s)) = eval(environment, arg)? {
+        Ok(s)
+    } else {
+        let msg = format!("{} must have a file", name);
+        Err(io::Error::new(io::ErrorKind::Other, msg))
+    }
+}
+
+// Evaluate body with fd redirected for the external commands it runs.
+fn with_fd_redirect(
+    environment: &mut Environment,
+    fd: i32,
+    redirect: FdRedirect,
+    body: &[Expression],
+) -> io::Result<Expression> {
+    environment.state.fd_redirects.push((fd, redirect));
+    let res = builtin_progn(environment, body);
+    environment.state.fd_redirects.pop();
+    res
+}
+
+fn builtin_fd_to(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() < 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "fd-to must have an fd, a file and at least one form",
+        ));
+    }
+    let fd = fd_arg(environment, &args[0], "fd-to")?;
+    let file = file_arg(environment, &args[1], "fd-to")?;
+    with_fd_redirect(environment, fd, FdRedirect::FileAppend(file), &args[2..])
+}
+
+fn builtin_fd_from(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() < 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "fd-from must have an fd, a file and at least one form",
+        ));
+    }
+    let fd = fd_arg(environment, &args[0], "fd-from")?;
+    let file = file_arg(environment, &args[1], "fd-from")?;
+    with_fd_redirect(environment, fd, FdRedirect::FileRead(file), &args[2..])
+}
+
+fn builtin_fd_dup(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() < 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "fd-dup must have an fd, the fd to copy and at least one form",
+        ));
+    }
+    let fd = fd_arg(environment, &args[0], "fd-dup")?;
+    let source = fd_arg(environment, &args[1], "fd-dup")?;
+    with_fd_redirect(environment, fd, FdRedirect::Dup(source), &args[2..])
+}
+
+fn builtin_fd_close(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "fd-close must have an fd and at least one form",
+        ));
+    }
+    let fd = fd_arg(environment, &args[0], "fd-close")?;
+    with_fd_redirect(environment, fd, FdRedirect::Close, &args[1..])
+}
+
 fn builtin_path_exists(
     environment: &mut Environment,
     args: &[Expression],
@@ -272,6 +362,33 @@ fn builtin_is_dir(environment: &mut Environment, args: &[Expression]) -> io::Res
     }
 }
 
+// (glob "src/**/*.rs" "*.{toml,md}") returns the matching paths as a list.
+fn builtin_glob(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    let (extended, _no_match) = glob_settings(environment)?;
+    let mut paths = Vec::new();
+    for arg in args {
+        let pattern = match eval(environment, arg)? {
+            Expression::Atom(Atom::String(s)) => s,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "glob patterns must be strings",
+                ))
+            }
+        };
+        for word in expand_braces(&pattern) {
+            let word = match expand_tilde(&word) {
+                Some(p) => p,
+                None => word,
+            };
+            for path in expand_glob(&word, extended) {
+                paths.push(Expression::Atom(Atom::String(path)));
+            }
+        }
+    }
+    Ok(Expression::List(paths))
+}
+
 fn builtin_pipe(environment: &mut Environment, parts: &[Expression]) -> io::Result<Expression> {
     if environment.in_pipe {
         return Err(io::Error::new(
@@ -284,6 +401,7 @@ fn builtin_pipe(environment: &mut Environment, parts: &[Expression]) -> io::Resu
     let mut out = Expression::Atom(Atom::Nil);
     environment.state.stdout_status = Some(IOState::Pipe);
     let mut i = 1; // Meant 1 here.
+    let mut stages = Vec::with_capacity(parts.len());
     for p in parts {
         if i == parts.len() {
             environment.state.stdout_status = old_out_status.clone();
@@ -292,7 +410,9 @@ fn builtin_pipe(environment: &mut Environment, parts: &[Expression]) -> io::Resu
         environment.data_in = Some(out);
         let res = eval(environment, p);
         if let Err(err) = res {
+            environment.data_in = None;
             environment.in_pipe = false;
+            environment.state.pipe_pgid = None;
             environment.state.stdout_status = old_out_status;
             return Err(err);
         }
@@ -307,6 +427,7 @@ fn builtin_pipe(environment: &mut Environment, parts: &[Expression]) -> io::Resu
             }
         }
         out = res.unwrap();
+        stages.push(out.clone());
         i += 1;
     }
     environment.data_in = None;
@@ -315,9 +436,34 @@ fn builtin_pipe(environment: &mut Environment, parts: &[Expression]) -> io::Resu
         environment.state.pipe_pgid = None;
     }
     environment.state.stdout_status = old_out_status;
+    match out {
+        // Running in the background, wait gets the statuses.
+        Expression::Process(ProcessState::Running(pid)) => add_pipeline(environment, pid, stages),
+        _ => {
+            let (status, _) = wait_pipeline(environment, &mut stages);
+            if let Expression::Process(ProcessState::Over(pid, exit_status)) = out {
+                out = Expression::Process(ProcessState::Over(pid, status.unwrap_or(exit_status)));
+                add_pipeline(environment, pid, stages);
+            }
+        }
+    }
     Ok(out)
 }
 
+// The statuses of every stage when waiting on the last process of a pipeline.
+fn wait_pipeline_pid(environment: &mut Environment, arg: &Expression) -> Option<Expression> {
+    let pid = match arg {
+        Expression::Process(ProcessState::Running(pid))
+        | Expression::Process(ProcessState::Over(pid, _)) => *pid,
+        Expression::Atom(Atom::Int(pid)) => *pid as u32,
+        _ => return None,
+    };
+    let mut stages = environment.state.pipelines.remove(&pid)?;
+    let (_, statuses) = wait_pipeline(environment, &mut stages);
+    environment.state.pipelines.insert(pid, stages);
+    Some(statuses)
+}
+
 fn builtin_wait(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
     if args.len() != 1 {
         Err(io::Error::new(
@@ -326,27 +472,42 @@ fn builtin_wait(environment: &mut Environment, args: &[Expression]) -> io::Resul
         ))
     } else {
         match eval(environment, &args[0]) {
-            Ok(arg) => match arg {
-                Expression::Process(ProcessState::Running(pid)) => {
-                    match wait_pid(environment, pid, None) {
-                        Some(exit_status) => {
-                            Ok(Expression::Atom(Atom::Int(i64::from(exit_status))))
-                        }
-                        None => Ok(Expression::Atom(Atom::Nil)),
+            Ok(arg) => {
+                if let Some(statuses) = wait_pipeline_pid(environment, &arg) {
+                    return Ok(statuses);
+                }
+                // A spawned lisp form returns its value.
+                if let Expression::Process(ProcessState::Running(pid)) = arg {
+                    if let Some(value) = wait_spawned(environment, pid) {
+                        return value;
                     }
                 }
-                Expression::Process(ProcessState::Over(_pid, exit_status)) => {
-                    Ok(Expression::Atom(Atom::Int(i64::from(exit_status))))
+                match arg {
+                    Expression::Process(ProcessState::Running(pid)) => {
+                        match wait_pid(environment, pid, None) {
+                            Some(exit_status) => {
+                                Ok(Expression::Atom(Atom::Int(i64::from(exit_status))))
+                            }
+                            None => Ok(Expression::Atom(Atom::Nil)),
+                        }
+                    }
+                    Expression::Process(ProcessState::Over(_pid, exit_status)) => {
+                        Ok(Expression::Atom(Atom::Int(i64::from(exit_status))))
+                    }
+                    Expression::Atom(Atom::Int(pid)) => {
+                        match wait_pid(environment, pid as u32, None) {
+                            Some(exit_status) => {
+                                Ok(Expression::Atom(Atom::Int(i64::from(exit_status))))
+                            }
+                            None => Ok(Expression::Atom(Atom::Nil)),
+                        }
+                    }
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "wait error: not a pid",
+                    )),
                 }
-                Expression::Atom(Atom::Int(pid)) => match wait_pid(environment, pid as u32, None) {
-                    Some(exit_status) => Ok(Expression::Atom(Atom::Int(i64::from(exit_status)))),
-                    None => Ok(Expression::Atom(Atom::Nil)),
-                },
-                _ => Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "wait error: not a pid",
-                )),
-            },
+            }
             Err(err) => {
                 let msg = format!("wait error evaluating form: {}", err);
                 Err(io::Error::new(io::ErrorKind::Other, msg))
@@ -355,6 +516,88 @@ fn builtin_wait(environment: &mut Environment, args: &[Expression]) -> io::Resul
     }
 }
 
+// Commands pmap runs at once without :jobs.
+fn default_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map(|jobs| jobs.get())
+        .unwrap_or(4)
+}
+
+fn builtin_pmap(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if args.len() != 2 && args.len() != 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "pmap takes a function, a list and optionally :jobs",
+        ));
+    }
+    let lambda = match eval(environment, &args[0])? {
+        lambda @ Expression::Atom(Atom::Lambda(_)) => lambda,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "pmap: requires a lambda",
+            ))
+        }
+    };
+    let items = match eval(environment, &args[1])? {
+        Expression::List(items) => items,
+        Expression::Atom(Atom::Nil) => Vec::new(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "pmap: requires a list",
+            ))
+        }
+    };
+    let mut jobs = default_jobs();
+    if let [key, val] = &args[2..] {
+        match key {
+            Expression::Atom(Atom::Symbol(key)) if key == ":jobs" => {
+                let val = eval(environment, val)?.make_int(environment)?;
+                if val < 1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "pmap: :jobs must be at least 1",
+                    ));
+                }
+                jobs = val as usize;
+            }
+            key => {
+                let msg = format!("pmap: unknown key {}", key.to_string());
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    }
+    Ok(Expression::List(parallel_map(
+        environment,
+        &lambda,
+        items,
+        jobs,
+    )?))
+}
+
+fn builtin_psub_out(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if let [form] = args {
+        process_substitution(environment, form, false)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "psub< takes one form (a command)",
+        ))
+    }
+}
+
+fn builtin_psub_in(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if let [form] = args {
+        process_substitution(environment, form, true)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "psub> takes one form (a command)",
+        ))
+    }
+}
+
 fn builtin_pid(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
     if args.len() != 1 {
         Err(io::Error::new(
@@ -409,6 +652,22 @@ pub fn add_file_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expressio
         "stderr-to".to_string(),
         Rc::new(Expression::Func(builtin_stderr_to)),
     );
+    data.insert(
+        "fd-to".to_string(),
+        Rc::new(Expression::Func(builtin_fd_to)),
+    );
+    data.insert(
+        "fd-from".to_string(),
+        Rc::new(Expression::Func(builtin_fd_from)),
+    );
+    data.insert(
+        "fd-dup".to_string(),
+        Rc::new(Expression::Func(builtin_fd_dup)),
+    );
+    data.insert(
+        "fd-close".to_string(),
+        Rc::new(Expression::Func(builtin_fd_close)),
+    );
     data.insert(
         "file-trunc".to_string(),
         Rc::new(Expression::Func(builtin_file_trunc)),
@@ -425,7 +684,17 @@ pub fn add_file_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expressio
         "is-dir".to_string(),
         Rc::new(Expression::Func(builtin_is_dir)),
     );
+    data.insert("glob".to_string(), Rc::new(Expression::Func(builtin_glob)));
     data.insert("pipe".to_string(), Rc::new(Expression::Func(builtin_pipe)));
     data.insert("wait".to_string(), Rc::new(Expression::Func(builtin_wait)));
     data.insert("pid".to_string(), Rc::new(Expression::Func(builtin_pid)));
+    data.insert("pmap".to_string(), Rc::new(Expression::Func(builtin_pmap)));
+    data.insert(
+        "psub<".to_string(),
+        Rc::new(Expression::Func(builtin_psub_out)),
+    );
+    data.insert(
+        "psub>".to_string(),
+        Rc::new(Expression::Func(builtin_psub_in)),
+    );
 }
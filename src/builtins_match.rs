@@ -0,0 +1,475 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use glob::Pattern;
+use regex::Regex;
+
+use crate::builtins::builtin_progn;
+use crate::environment::*;
+use crate::shell::*;
+use crate::types::*;
+
+// A string containing a match for pattern (regex crate syntax).
+pub fn re_is_match(pattern: &str, text: &str) -> io::Result<bool> {
+    match Regex::new(pattern) {
+        Ok(re) => Ok(re.is_match(text)),
+        Err(err) => {
+            let msg = format!("match invalid :re pattern {}, {}", pattern, err);
+            Err(io::Error::new(io::ErrorKind::Other, msg))
+        }
+    }
+}
+
+// Turn a process into its output so it can be compared more than once.
+fn resolve_process(environment: &Environment, exp: Expression) -> io::Result<Expression> {
+    match exp {
+        Expression::Process(_) => Ok(Expression::Atom(Atom::String(
+            exp.make_string(environment)?,
+        ))),
+        _ => Ok(exp),
+    }
+}
+
+pub fn values_equal(environment: &Environment, a: &Expression, b: &Expression) -> io::Result<bool> {
+    match (a, b) {
+        (Expression::Atom(Atom::Nil), Expression::Atom(Atom::Nil)) => Ok(true),
+        (Expression::Atom(Atom::Nil), Expression::List(list))
+        | (Expression::List(list), Expression::Atom(Atom::Nil)) => Ok(list.is_empty()),
+        (Expression::Atom(Atom::Nil), _) | (_, Expression::Atom(Atom::Nil)) => Ok(false),
+        (Expression::Atom(Atom::True), Expression::Atom(Atom::True)) => Ok(true),
+        (Expression::Atom(Atom::Int(i1)), Expression::Atom(Atom::Int(i2))) => Ok(i1 == i2),
+        (Expression::Atom(Atom::Int(_)), Expression::Atom(Atom::Float(_)))
+        | (Expression::Atom(Atom::Float(_)), Expression::Atom(Atom::Int(_)))
+        | (Expression::Atom(Atom::Float(_)), Expression::Atom(Atom::Float(_))) => {
+            Ok((a.make_float(environment)? - b.make_float(environment)?).abs() < 0.000_001)
+        }
+        (Expression::List(l1), Expression::List(l2)) => {
+            if l1.len() != l2.len() {
+                return Ok(false);
+            }
+            for (e1, e2) in l1.iter().zip(l2.iter()) {
+                if !values_equal(environment, e1, e2)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        (Expression::List(_), _) | (_, Expression::List(_)) => Ok(false),
+        _ => Ok(a.make_string(environment)? == b.make_string(environment)?),
+    }
+}
+
+fn pattern_string_arg(args: &[Expression], name: &str) -> io::Result<String> {
+    if let [Expression::Atom(Atom::String(s))] = args {
+        Ok(s.clone())
+    } else {
+        let msg = format!("match pattern {} takes one string", name);
+        Err(io::Error::new(io::ErrorKind::Other, msg))
+    }
+}
+
+fn match_list_pattern(
+    environment: &Environment,
+    patterns: &[Expression],
+    values: &[Expression],
+    bindings: &mut HashMap<String, Rc<Expression>>,
+) -> io::Result<bool> {
+    let mut values = values.iter();
+    let mut patterns = patterns.iter();
+    while let Some(pattern) = patterns.next() {
+        if let Expression::Atom(Atom::Symbol(s)) = pattern {
+            if s == "&rest" {
+                let rest_pattern = match (patterns.next(), patterns.next()) {
+                    (Some(rest_pattern), None) => rest_pattern,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "match &rest must have one pattern after",
+                        ))
+                    }
+                };
+                let rest: Vec<Expression> = values.cloned().collect();
+                let rest = if rest.is_empty() {
+                    Expression::Atom(Atom::Nil)
+                } else {
+                    Expression::List(rest)
+                };
+                return match_pattern(environment, rest_pattern, &rest, bindings);
+            }
+        }
+        match values.next() {
+            Some(value) => {
+                if !match_pattern(environment, pattern, value, bindings)? {
+                    return Ok(false);
+                }
+            }
+            None => return Ok(false),
+        }
+    }
+    Ok(values.next().is_none())
+}
+
+fn match_pattern(
+    environment: &Environment,
+    pattern: &Expression,
+    value: &Expression,
+    bindings: &mut HashMap<String, Rc<Expression>>,
+) -> io::Result<bool> {
+    match pattern {
+        Expression::Atom(Atom::Symbol(s)) if s == "_" => Ok(true),
+        Expression::Atom(Atom::Symbol(s)) => {
+            if let Some(bound) = bindings.get(s) {
+                // Repeating a variable requires the same value.
+                let bound = bound.clone();
+                values_equal(environment, &bound, value)
+            } else {
+                bindings.insert(s.clone(), Rc::new(value.clone()));
+                Ok(true)
+            }
+        }
+        Expression::List(list) => {
+            let (command, args) = match list.split_first() {
+                Some((Expression::Atom(Atom::Symbol(c)), args)) => (c.as_str(), args),
+                Some(_) => ("", &list[..]),
+                None => return values_equal(environment, pattern, value),
+            };
+            match command {
+                "quote" => {
+                    if args.len() != 1 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "match quoted pattern takes one form",
+                        ));
+                    }
+                    values_equal(environment, &args[0], value)
+                }
+                ":glob" => {
+                    let pat = pattern_string_arg(args, ":glob")?;
+                    match Pattern::new(&pat) {
+                        Ok(pat) => match value {
+                            Expression::List(_) => Ok(false),
+                            _ => Ok(pat.matches(&value.make_string(environment)?)),
+                        },
+                        Err(err) => {
+                            let msg = format!("match invalid :glob pattern {}, {}", pat, err);
+                            Err(io::Error::new(io::ErrorKind::Other, msg))
+                        }
+                    }
+                }
+                ":re" => {
+                    let pat = pattern_string_arg(args, ":re")?;
+                    match value {
+                        Expression::List(_) => Ok(false),
+                        _ => re_is_match(&pat, &value.make_string(environment)?),
+                    }
+                }
+                ":or" => {
+                    for sub_pattern in args {
+                        let mut sub_bindings = bindings.clone();
+                        if match_pattern(environment, sub_pattern, value, &mut sub_bindings)? {
+                            *bindings = sub_bindings;
+                            return Ok(true);
+                        }
+                    }
+                    Ok(false)
+                }
+                _ => match value {
+                    Expression::List(values) => {
+                        match_list_pattern(environment, list, values, bindings)
+                    }
+                    Expression::Atom(Atom::Nil) => {
+                        match_list_pattern(environment, list, &[], bindings)
+                    }
+                    _ => Ok(false),
+                },
+            }
+        }
+        _ => values_equal(environment, pattern, value),
+    }
+}
+
+fn builtin_match(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    let (value, branches) = match args.split_first() {
+        Some((value, branches)) => (value, branches),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "match needs a form to match and branches",
+            ))
+        }
+    };
+    let value = eval(environment, value)?;
+    let value = resolve_process(environment, value)?;
+    for (i, branch) in branches.iter().enumerate() {
+        let (pattern, mut body) = match branch {
+            Expression::List(list) if !list.is_empty() => (&list[0], &list[1..]),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "match branches must be a list of pattern and body",
+                ))
+            }
+        };
+        let mut bindings: HashMap<String, Rc<Expression>> = HashMap::new();
+        // Like the old match macro a last (nil body) branch is the default.
+        let default = i == branches.len() - 1 && matches!(pattern, Expression::Atom(Atom::Nil));
+        if !default && !match_pattern(environment, pattern, &value, &mut bindings)? {
+            continue;
+        }
+        let mut guard = None;
+        if let Some(Expression::Atom(Atom::Symbol(s))) = body.first() {
+            if s == ":when" {
+                if body.len() < 2 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "match :when needs a guard form",
+                    ));
+                }
+                guard = Some(&body[1]);
+                body = &body[2..];
+            }
+        }
+        let new_scope = Rc::new(RefCell::new(Scope::with_data(Some(environment), bindings)));
+        environment.current_scope.push(new_scope);
+        if let Some(guard) = guard {
+            match eval(environment, guard) {
+                Ok(Expression::Atom(Atom::Nil)) => {
+                    environment.current_scope.pop();
+                    continue;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    environment.current_scope.pop();
+                    return Err(err);
+                }
+            }
+        }
+        let res = builtin_progn(environment, body);
+        environment.current_scope.pop();
+        return res;
+    }
+    Ok(Expression::Atom(Atom::Nil))
+}
+
+fn builtin_cond(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    for clause in args {
+        if let Expression::List(list) = clause {
+            let (test, body) = match list.split_first() {
+                Some((test, body)) => (test, body),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "cond clauses can not be empty",
+                    ))
+                }
+            };
+            let test = eval(environment, test)?;
+            if let Expression::Atom(Atom::Nil) = test {
+                continue;
+            }
+            if body.is_empty() {
+                return Ok(test);
+            }
+            return builtin_progn(environment, body);
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cond clauses must be lists",
+            ));
+        }
+    }
+    Ok(Expression::Atom(Atom::Nil))
+}
+
+// Keys are not evaluated but may be quoted, 'a and a are the same key.
+fn case_key(key: &Expression) -> Option<&Expression> {
+    match key {
+        Expression::List(list) if list.len() == 2 => match &list[0] {
+            Expression::Atom(Atom::Symbol(s)) if s == "quote" => Some(&list[1]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn builtin_case(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    let (value, clauses) = match args.split_first() {
+        Some((value, clauses)) => (value, clauses),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "case needs a form to test and clauses",
+            ))
+        }
+    };
+    let value = eval(environment, value)?;
+    let value = resolve_process(environment, value)?;
+    for clause in clauses {
+        let (keys, body) = match clause {
+            Expression::List(list) if !list.is_empty() => (&list[0], &list[1..]),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "case clauses must be a list of key(s) and body",
+                ))
+            }
+        };
+        let hit = match keys {
+            Expression::Atom(Atom::True) => true,
+            Expression::Atom(Atom::Symbol(s)) if s == "otherwise" || s == "_" => true,
+            Expression::List(list) => match case_key(keys) {
+                Some(key) => values_equal(environment, key, &value)?,
+                None => {
+                    let mut found = false;
+                    for key in list {
+                        let key = case_key(key).unwrap_or(key);
+                        if values_equal(environment, key, &value)? {
+                            found = true;
+                            break;
+                        }
+                    }
+                    found
+                }
+            },
+            key => values_equal(environment, key, &value)?,
+        };
+        if hit {
+            return builtin_progn(environment, body);
+        }
+    }
+    Ok(Expression::Atom(Atom::Nil))
+}
+
+pub fn add_match_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "match".to_string(),
+        Rc::new(Expression::Func(builtin_match)),
+    );
+    data.insert("cond".to_string(), Rc::new(Expression::Func(builtin_cond)));
+    data.insert("case".to_string(), Rc::new(Expression::Func(builtin_case)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use crate::reader::read;
+
+    fn eval_str(environment: &mut Environment, text: &str) -> String {
+        let exp = read(text).unwrap();
+        let value = eval(environment, &exp).unwrap();
+        value.make_string(environment).unwrap()
+    }
+
+    #[test]
+    fn test_match() {
+        let mut environment = build_default_environment();
+        let env = &mut environment;
+        eval_str(
+            env,
+            "(def 'm (fn (v) (match v (1 \"one\") ((x x) \"pair\") ((a &rest r) r) (_ \"other\"))))",
+        );
+        assert_eq!(eval_str(env, "(m 1)"), "one");
+        assert_eq!(eval_str(env, "(m '(2 2))"), "pair");
+        assert_eq!(eval_str(env, "(m '(2 3 4))"), "( 3 4 )");
+        assert_eq!(eval_str(env, "(m \"s\")"), "other");
+        assert_eq!(
+            eval_str(
+                env,
+                "(match 5 (n :when (> n 9) \"big\") (n :when (> n 3) \"mid\"))"
+            ),
+            "mid"
+        );
+        assert_eq!(
+            eval_str(env, "(match \"a.rs\" ((:glob \"*.rs\") \"rust\"))"),
+            "rust"
+        );
+        assert_eq!(eval_str(env, "(match 'b ((:or 'a 'b) \"ab\"))"), "ab");
+        assert_eq!(eval_str(env, "(match 3 (1 \"one\"))"), "nil");
+    }
+
+    #[test]
+    fn test_match_nil_default() {
+        let mut environment = build_default_environment();
+        let env = &mut environment;
+        // A last nil branch matches anything, like the old match macro.
+        assert_eq!(
+            eval_str(env, "(match 3 (1 \"one\") (nil \"default\"))"),
+            "default"
+        );
+        assert_eq!(
+            eval_str(env, "(match 1 (1 \"one\") (nil \"default\"))"),
+            "one"
+        );
+        // Anywhere else nil only matches nil.
+        assert_eq!(
+            eval_str(env, "(match 3 (nil \"nil\") (_ \"other\"))"),
+            "other"
+        );
+        assert_eq!(
+            eval_str(env, "(match nil (nil \"nil\") (_ \"other\"))"),
+            "nil"
+        );
+    }
+
+    #[test]
+    fn test_case() {
+        let mut environment = build_default_environment();
+        let env = &mut environment;
+        eval_str(env, "(def 'c (fn (v) (case v (1 \"one\") ((2 3) \"two or three\") ('x \"x\") (('y 'z) \"y or z\") (otherwise \"other\"))))");
+        assert_eq!(eval_str(env, "(c 1)"), "one");
+        assert_eq!(eval_str(env, "(c 3)"), "two or three");
+        assert_eq!(eval_str(env, "(c 'x)"), "x");
+        assert_eq!(eval_str(env, "(c 'z)"), "y or z");
+        assert_eq!(eval_str(env, "(c 4)"), "other");
+        assert_eq!(eval_str(env, "(case 4 (1 \"one\"))"), "nil");
+    }
+
+    #[test]
+    fn test_re() {
+        assert!(re_is_match("^ab", "abc").unwrap());
+        assert!(!re_is_match("^ab", "cab").unwrap());
+        assert!(re_is_match("bc$", "abc").unwrap());
+        assert!(!re_is_match("bc$", "bcd").unwrap());
+        assert!(re_is_match("^(a|b)$|^c", "cd").unwrap());
+        assert!(re_is_match("[a-c]x", "zbx").unwrap());
+        assert!(!re_is_match("[^a-c]x", "bx").unwrap());
+        assert!(re_is_match("\\d+\\s\\w", "12 a").unwrap());
+        assert!(re_is_match("^a.?b+c*$", "abb").unwrap());
+        assert!(!re_is_match("^a.?b+c*$", "ac").unwrap());
+        assert!(re_is_match("^x{2,3}$", "xxx").unwrap());
+        assert!(re_is_match("[", "").is_err());
+    }
+
+    #[test]
+    fn test_re_pathological() {
+        // Exponential for a backtracking matcher.
+        let text = "a".repeat(64);
+        let start = Instant::now();
+        assert!(!re_is_match("^(a*)*b$", &text).unwrap());
+        assert!(!re_is_match("^(a|aa)+$", &format!("{}b", text)).unwrap());
+        assert!(start.elapsed().as_secs() < 5);
+    }
+
+    #[test]
+    fn test_re_pattern() {
+        let mut environment = build_default_environment();
+        let env = &mut environment;
+        assert_eq!(
+            eval_str(
+                env,
+                "(match \"v1.20\" ((:re \"^v\\\\d+\\\\.\\\\d+$\") \"version\") (_ \"no\"))"
+            ),
+            "version"
+        );
+        assert_eq!(
+            eval_str(env, "(match '(1) ((:re \"1\") \"re\") (_ \"list\"))"),
+            "list"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::rc::Rc;
+
+use crate::environment::*;
+use crate::shell::*;
+use crate::types::*;
+
+fn command_name(
+    environment: &mut Environment,
+    args: &[Expression],
+    form: &str,
+) -> io::Result<String> {
+    let msg = format!("{} takes one command name", form);
+    if args.len() != 1 {
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+    match &args[0] {
+        Expression::Atom(Atom::Symbol(s)) => Ok(s.clone()),
+        arg => match eval(environment, arg)? {
+            Expression::Atom(Atom::Symbol(s)) | Expression::Atom(Atom::String(s)) => Ok(s),
+            _ => Err(io::Error::new(io::ErrorKind::Other, msg)),
+        },
+    }
+}
+
+// The path of the executable that would run for a command, nil if none.
+fn builtin_which(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    let command = command_name(environment, args, "which")?;
+    match environment.path_hash.borrow_mut().lookup(&command) {
+        Some(path) => Ok(Expression::Atom(Atom::String(
+            path.to_string_lossy().to_string(),
+        ))),
+        None => Ok(Expression::Atom(Atom::Nil)),
+    }
+}
+
+// Describe what runs for a command (lisp forms before executables), nil if nothing.
+fn builtin_type(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    let command = command_name(environment, args, "type")?;
+    let kind = match get_form(environment, &command) {
+        Some(exp) => match &*exp {
+            Expression::Func(_) => Some("a builtin".to_string()),
+            Expression::Atom(Atom::Lambda(_)) => Some("a lambda".to_string()),
+            Expression::Atom(Atom::Macro(_)) => Some("a macro".to_string()),
+            _ => None,
+        },
+        None => None,
+    };
+    let kind = match kind {
+        Some(kind) => Some(kind),
+        None => environment
+            .path_hash
+            .borrow_mut()
+            .lookup(&command)
+            .map(|path| path.to_string_lossy().to_string()),
+    };
+    match kind {
+        Some(kind) => Ok(Expression::Atom(Atom::String(format!(
+            "{} is {}",
+            command, kind
+        )))),
+        None => Ok(Expression::Atom(Atom::Nil)),
+    }
+}
+
+fn builtin_rehash(environment: &mut Environment, args: &[Expression]) -> io::Result<Expression> {
+    if !args.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "rehash takes no arguments",
+        ));
+    }
+    environment.path_hash.borrow_mut().clear();
+    Ok(Expression::Atom(Atom::True))
+}
+
+pub fn add_path_builtins<S: BuildHasher>(data: &mut HashMap<String, Rc<Expression>, S>) {
+    data.insert(
+        "which".to_string(),
+        Rc::new(Expression::Func(builtin_which)),
+    );
+    data.insert("type".to_string(), Rc::new(Expression::Func(builtin_type)));
+    data.insert(
+        "rehash".to_string(),
+        Rc::new(Expression::Func(builtin_rehash)),
+    );
+}
@@ -1,10 +1,22 @@
-use glob::{glob, glob_with, MatchOptions};
+use glob::{glob_with, MatchOptions};
 use liner::{Completer, CursorPosition, Event, EventKind};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::{self, Pid};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
-use std::path::Path;
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
 use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::builtins_doc::expression_doc;
 use crate::builtins_util::expand_tilde;
 use crate::environment::*;
 use crate::shell::*;
@@ -23,24 +35,523 @@ enum CompType {
 enum HookResult {
     Default,
     Path,
-    UseList(Vec<String>),
+    UseList(Vec<Candidate>),
+}
+
+// A completion candidate, the description is shown when listing candidates.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+// Completion spec for a command, registered with the complete builtin.
+#[derive(Clone, Debug)]
+pub struct CompletionSpec {
+    pub subcommands: Vec<Candidate>,
+    pub options: Vec<Candidate>,
+    // With no options use the command's --help output instead of its man page.
+    pub help_options: bool,
+    // Lambda called as (fn (word index flags positional)) for arguments.
+    pub args: Option<Expression>,
 }
 
 pub struct ShellCompleter {
     environment: Rc<RefCell<Environment>>,
     comp_type: CompType,
     args: Vec<String>,
+    // Index in args of the word being completed (may be args.len()).
+    word_index: usize,
+}
+
+fn hook_result(res: Expression, hook_name: &str) -> HookResult {
+    match res {
+        Expression::Atom(Atom::String(s)) | Expression::Atom(Atom::Symbol(s)) => match s.as_ref() {
+            "path" => HookResult::Path,
+            "default" => HookResult::Default,
+            _ => {
+                eprintln!("ERROR: unknown completion hook command, {}", s);
+                HookResult::Default
+            }
+        },
+        Expression::Atom(Atom::Nil) => HookResult::Default,
+        Expression::List(mut list) => {
+            let mut v = Vec::with_capacity(list.len());
+            for l in list.drain(..) {
+                v.push(candidate_from_exp(&l));
+            }
+            HookResult::UseList(v)
+        }
+        _ => {
+            eprintln!(
+                "WARNING: unexpected result from {}, {:?}, ignoring.",
+                hook_name, res
+            );
+            HookResult::Default
+        }
+    }
+}
+
+// A candidate is a name or a (name description) list.
+pub fn candidate_from_exp(exp: &Expression) -> Candidate {
+    match exp {
+        Expression::List(list) if !list.is_empty() => Candidate {
+            name: list[0].to_string(),
+            description: list.get(1).map(|d| d.to_string()),
+        },
+        _ => Candidate {
+            name: exp.to_string(),
+            description: None,
+        },
+    }
+}
+
+// How completion candidates are matched against the word being completed.
+// Set from slshrc with *completion-fuzzy* (subsequence instead of prefix
+// matching), *completion-case-sensitive* (otherwise only if the word has an
+// upper case letter) and *completion-frecency* (boost often/recently used
+// words), all t or nil.
+pub struct Matcher {
+    fuzzy: bool,
+    case_sensitive: bool,
+    frecency: Option<Rc<RefCell<Frecency>>>,
+}
+
+impl Default for Matcher {
+    // Fuzzy and case insensitive, without frecency.
+    fn default() -> Self {
+        Matcher {
+            fuzzy: true,
+            case_sensitive: false,
+            frecency: None,
+        }
+    }
+}
+
+fn is_word_boundary(prev: char, ch: char) -> bool {
+    match prev {
+        '/' | '_' | '-' | '.' | ' ' | '(' | '\'' | '$' => true,
+        _ => prev.is_lowercase() && ch.is_uppercase(),
+    }
 }
 
+impl Matcher {
+    pub fn new(environment: &Environment) -> Matcher {
+        let flag = |name: &str, default: bool| match get_expression(environment, name) {
+            Some(exp) => !matches!(*exp, Expression::Atom(Atom::Nil)),
+            None => default,
+        };
+        let frecency = if flag("*completion-frecency*", true) {
+            Some(environment.frecency.clone())
+        } else {
+            None
+        };
+        Matcher {
+            fuzzy: flag("*completion-fuzzy*", true),
+            case_sensitive: flag("*completion-case-sensitive*", false),
+            frecency,
+        }
+    }
+
+    // Score of candidate for pattern (higher is better), None if no match.
+    pub fn score(&self, pattern: &str, candidate: &str) -> Option<i64> {
+        let case_sensitive = self.case_sensitive || pattern.chars().any(char::is_uppercase);
+        let same = |a: char, b: char| {
+            if case_sensitive {
+                a == b
+            } else {
+                a.to_lowercase().eq(b.to_lowercase())
+            }
+        };
+        let pattern: Vec<char> = pattern.chars().collect();
+        let candidate: Vec<char> = candidate.chars().collect();
+        let is_prefix = pattern.len() <= candidate.len()
+            && pattern
+                .iter()
+                .zip(candidate.iter())
+                .all(|(a, b)| same(*a, *b));
+        if is_prefix {
+            return Some(100);
+        }
+        if !self.fuzzy {
+            return None;
+        }
+        let mut score = 0;
+        let mut pi = 0;
+        let mut last_match: Option<usize> = None;
+        for (ci, ch) in candidate.iter().enumerate() {
+            if pi == pattern.len() {
+                break;
+            }
+            if !same(pattern[pi], *ch) {
+                continue;
+            }
+            score += 1;
+            if ci == 0 || is_word_boundary(candidate[ci - 1], *ch) {
+                score += 6;
+            }
+            match last_match {
+                Some(last) if last + 1 == ci => score += 4,
+                Some(last) => score -= (ci - last - 1).min(3) as i64,
+                None => {}
+            }
+            last_match = Some(ci);
+            pi += 1;
+        }
+        if pi < pattern.len() {
+            None
+        } else {
+            Some(score)
+        }
+    }
+
+    pub fn matches(&self, pattern: &str, candidate: &str) -> bool {
+        self.score(pattern, candidate).is_some()
+    }
+
+    // Drop candidates that do not match pattern and sort the rest best first.
+    pub fn rank(&self, pattern: &str, mut candidates: Vec<Candidate>) -> Vec<Candidate> {
+        let mut scored: Vec<(i64, Candidate)> = candidates
+            .drain(..)
+            .filter_map(|c| {
+                let score = self.score(pattern, &c.name)?;
+                let boost = match &self.frecency {
+                    Some(frecency) => frecency.borrow().score(&c.name).min(50),
+                    None => 0,
+                };
+                Some((score + boost, c))
+            })
+            .collect();
+        scored.sort_by_key(|s| std::cmp::Reverse(s.0));
+        scored.drain(..).map(|(_, c)| c).collect()
+    }
+}
+
+// Words from command lines that ran (commands, arguments, paths) with their
+// use count and last use, saved to ~/.local/share/slsh/frecency.  The file is
+// a log, each command appends the entries it changed (the last line for a
+// word wins) and it is rewritten when entries are dropped or it gets long.
+#[derive(Clone, Debug, Default)]
+pub struct Frecency {
+    file: Option<PathBuf>,
+    entries: HashMap<String, (u32, u64)>,
+    // Lines in file.
+    lines: usize,
+}
+
+const MAX_FRECENCY_ENTRIES: usize = 1000;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn frecency_key(word: &str) -> &str {
+    let word = word.trim_matches(&['(', ')', '\'', '"', '`'][..]);
+    let word = word.trim_start_matches('$');
+    if word.len() > 1 {
+        word.trim_end_matches('/')
+    } else {
+        word
+    }
+}
+
+fn frecency_line(word: &str, count: u32, last: u64) -> String {
+    format!("{}\t{}\t{}\n", count, last, word)
+}
+
+impl Frecency {
+    pub fn load(&mut self, file: PathBuf) {
+        if let Ok(contents) = fs::read_to_string(&file) {
+            for line in contents.lines() {
+                self.lines += 1;
+                let mut parts = line.splitn(3, '\t');
+                let count = parts.next().and_then(|c| c.parse::<u32>().ok());
+                let last = parts.next().and_then(|l| l.parse::<u64>().ok());
+                if let (Some(count), Some(last), Some(word)) = (count, last, parts.next()) {
+                    self.entries.insert(word.to_string(), (count, last));
+                }
+            }
+        }
+        self.file = Some(file);
+        self.prune(now_secs());
+        if self.lines > self.entries.len() * 2 {
+            self.save_all();
+        }
+    }
+
+    fn entry_score(count: u32, last: u64, now: u64) -> i64 {
+        let age = now.saturating_sub(last);
+        let weight = if age < 3600 {
+            16
+        } else if age < 86400 {
+            8
+        } else if age < 604_800 {
+            2
+        } else {
+            1
+        };
+        i64::from(count) * weight / 4
+    }
+
+    pub fn score(&self, word: &str) -> i64 {
+        match self.entries.get(frecency_key(word)) {
+            Some((count, last)) => Frecency::entry_score(*count, *last, now_secs()),
+            None => 0,
+        }
+    }
+
+    pub fn record_line(&mut self, line: &str) {
+        self.record_line_at(line, now_secs());
+    }
+
+    fn record_line_at(&mut self, line: &str, now: u64) {
+        let mut changed = String::new();
+        for word in line.split_whitespace() {
+            let word = frecency_key(word);
+            if word.len() < 2 {
+                continue;
+            }
+            let entry = self.entries.entry(word.to_string()).or_insert((0, now));
+            entry.0 += 1;
+            entry.1 = now;
+            changed.push_str(&frecency_line(word, entry.0, entry.1));
+        }
+        if self.prune(now) {
+            self.save_all();
+        } else if !changed.is_empty() {
+            if let Err(err) = self.append(&changed) {
+                eprintln!("WARNING: Unable to save completion frecency: {}", err);
+            }
+        }
+    }
+
+    // Drop the lowest scoring entries over MAX_FRECENCY_ENTRIES, true if any
+    // were dropped.
+    fn prune(&mut self, now: u64) -> bool {
+        if self.entries.len() <= MAX_FRECENCY_ENTRIES {
+            return false;
+        }
+        let mut scores: Vec<(i64, String)> = self
+            .entries
+            .iter()
+            .map(|(word, (count, last))| (Frecency::entry_score(*count, *last, now), word.clone()))
+            .collect();
+        scores.sort();
+        for (_, word) in scores
+            .iter()
+            .take(self.entries.len() - MAX_FRECENCY_ENTRIES)
+        {
+            self.entries.remove(word);
+        }
+        true
+    }
+
+    fn append(&mut self, lines: &str) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)?
+                .write_all(lines.as_bytes())?;
+            self.lines += lines.lines().count();
+        }
+        Ok(())
+    }
+
+    // Rewrite the file with just the current entries, written to a temp file
+    // and renamed so another shell never reads half of it.
+    fn save_all(&mut self) {
+        let file = match &self.file {
+            Some(file) => file.clone(),
+            None => return,
+        };
+        let mut contents = String::new();
+        for (word, (count, last)) in &self.entries {
+            contents.push_str(&frecency_line(word, *count, *last));
+        }
+        let mut tmp_file = file.clone().into_os_string();
+        tmp_file.push(format!(".{}.tmp", process::id()));
+        let res = fs::write(&tmp_file, contents).and_then(|_| fs::rename(&tmp_file, &file));
+        match res {
+            Ok(()) => self.lines = self.entries.len(),
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_file);
+                eprintln!("WARNING: Unable to save completion frecency: {}", err);
+            }
+        }
+    }
+}
+
+// Complete the word at word_index (start is the part before the cursor) using
+// the spec registered for the command (args[0]).  Returns None if the command
+// has no spec.
+pub fn spec_completions(
+    environment: &mut Environment,
+    args: &[String],
+    word_index: usize,
+    start: &str,
+) -> Option<Vec<Candidate>> {
+    if word_index == 0 {
+        return None;
+    }
+    let spec = environment.completion_specs.get(args.first()?)?.clone();
+    let matcher = Matcher::new(environment);
+    let mut flags: Vec<Expression> = Vec::new();
+    let mut positional: Vec<Expression> = Vec::new();
+    let mut end_of_flags = false;
+    for word in args.iter().take(word_index).skip(1) {
+        if !end_of_flags && word == "--" {
+            end_of_flags = true;
+        } else if !end_of_flags && word.starts_with('-') {
+            flags.push(Expression::Atom(Atom::String(word.clone())));
+        } else {
+            positional.push(Expression::Atom(Atom::String(word.clone())));
+        }
+    }
+    if !end_of_flags && start.starts_with('-') {
+        let options = if spec.options.is_empty() {
+            command_options(&args[0], spec.help_options)
+        } else {
+            spec.options
+        };
+        if !options.is_empty() {
+            return Some(matcher.rank(start, options));
+        }
+    }
+    if positional.is_empty() && !spec.subcommands.is_empty() {
+        return Some(matcher.rank(start, spec.subcommands));
+    }
+    let args_fn = match spec.args {
+        Some(args_fn) => args_fn,
+        None => {
+            let files = get_dir_matches(start, &matcher);
+            return Some(matcher.rank(start, names_to_candidates(files)));
+        }
+    };
+    let quote = |exp: Expression| {
+        Expression::List(vec![
+            Expression::Atom(Atom::Symbol("quote".to_string())),
+            exp,
+        ])
+    };
+    let call = Expression::List(vec![
+        quote(args_fn),
+        Expression::Atom(Atom::String(start.to_string())),
+        Expression::Atom(Atom::Int(word_index as i64)),
+        quote(Expression::List(flags)),
+        quote(Expression::List(positional)),
+    ]);
+    let result = match eval(environment, &call) {
+        Ok(res) => hook_result(res, "completion :args"),
+        Err(err) => {
+            eprintln!("ERROR calling completion :args for {}: {}", args[0], err);
+            HookResult::Default
+        }
+    };
+    let candidates = match result {
+        HookResult::Default => names_to_candidates(get_dir_matches(start, &matcher)),
+        HookResult::Path => names_to_candidates(get_path_matches(start, &matcher)),
+        HookResult::UseList(list) => list,
+    };
+    Some(matcher.rank(start, candidates))
+}
+
+fn names_to_candidates(mut names: Vec<String>) -> Vec<Candidate> {
+    names
+        .drain(..)
+        .map(|name| Candidate {
+            name,
+            description: None,
+        })
+        .collect()
+}
+
+fn common_prefix(candidates: &[Candidate]) -> String {
+    let mut prefix = candidates[0].name.clone();
+    for c in &candidates[1..] {
+        let len = prefix
+            .char_indices()
+            .zip(c.name.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map_or(0, |((i, a), _)| i + a.len_utf8());
+        prefix.truncate(len);
+    }
+    prefix
+}
+
+// Most candidates that will be listed by the shell.
+const MAX_LISTED_CANDIDATES: usize = 100;
+
 impl ShellCompleter {
     pub fn new(environment: Rc<RefCell<Environment>>) -> ShellCompleter {
         ShellCompleter {
             environment,
             comp_type: CompType::Nothing,
             args: Vec::new(),
+            word_index: 0,
         }
     }
 
+    // What to hand liner for candidates.  liner only shows the names and would
+    // replace the word with the candidates' common prefix, so if any candidate
+    // has a description, or fuzzy matches have no common prefix extending start,
+    // leave the list for the line editor to show and just complete the common
+    // prefix (or keep start).
+    fn show_candidates(&self, start: &str, mut candidates: Vec<Candidate>) -> Vec<String> {
+        if candidates.len() < 2 {
+            return candidates.drain(..).map(|c| c.name).collect();
+        }
+        let prefix = common_prefix(&candidates);
+        // A longer prefix with the typed characters in it is fine for fuzzy.
+        let extends_start = prefix.starts_with(start)
+            || (prefix.chars().count() > start.chars().count()
+                && Matcher::default().matches(start, &prefix));
+        let described = candidates.len() <= MAX_LISTED_CANDIDATES
+            && candidates.iter().any(|c| c.description.is_some());
+        if extends_start && !described {
+            return candidates.drain(..).map(|c| c.name).collect();
+        }
+        let width = candidates
+            .iter()
+            .take(MAX_LISTED_CANDIDATES)
+            .map(|c| c.name.len())
+            .max()
+            .unwrap_or(0);
+        let mut list: Vec<String> = candidates
+            .iter()
+            .take(MAX_LISTED_CANDIDATES)
+            .map(|c| match &c.description {
+                Some(desc) => format!("{:width$}  -- {}", c.name, desc, width = width),
+                None => c.name.clone(),
+            })
+            .collect();
+        if candidates.len() > MAX_LISTED_CANDIDATES {
+            list.push(format!(
+                "... and {} more",
+                candidates.len() - MAX_LISTED_CANDIDATES
+            ));
+        }
+        self.environment.borrow_mut().completion_list = list;
+        if extends_start {
+            vec![prefix]
+        } else {
+            vec![start.to_string()]
+        }
+    }
+
+    fn run_spec(&mut self, start: &str) -> Option<Vec<String>> {
+        let candidates = spec_completions(
+            &mut self.environment.borrow_mut(),
+            &self.args,
+            self.word_index,
+            start,
+        )?;
+        Some(self.show_candidates(start, candidates))
+    }
+
     fn run_hook(&mut self) -> HookResult {
         if self.args.is_empty() {
             return HookResult::Default;
@@ -79,33 +590,7 @@ impl ShellCompleter {
                 }
             };
             match eval(&mut self.environment.borrow_mut(), &exp) {
-                Ok(res) => {
-                    match res {
-                        Expression::Atom(Atom::String(s)) | Expression::Atom(Atom::Symbol(s)) => {
-                            match s.as_ref() {
-                                "path" => HookResult::Path,
-                                "default" => HookResult::Default,
-                                _ => {
-                                    eprintln!("ERROR: unknown completion hook command, {}", s);
-                                    HookResult::Default
-                                }
-                            }
-                        }
-                        Expression::Atom(Atom::Nil) => HookResult::Default,
-                        Expression::List(mut list) => {
-                            let mut v = Vec::with_capacity(list.len());
-                            for l in list.drain(..) {
-                                let s = l.to_string();
-                                v.push(s);
-                            }
-                            HookResult::UseList(v)
-                        }
-                        _ => {
-                            eprintln!("WARNING: unexpected result from __completion_hook, {:?}, ignoring.", res);
-                            HookResult::Default
-                        }
-                    }
-                }
+                Ok(res) => hook_result(res, "__completion_hook"),
                 Err(err) => {
                     eprintln!("ERROR calling __completion_hook: {}", err);
                     HookResult::Default
@@ -119,43 +604,69 @@ impl ShellCompleter {
 
 impl Completer for ShellCompleter {
     fn completions(&mut self, start: &str) -> Vec<String> {
+        let matcher = Matcher::new(&self.environment.borrow());
         match self.comp_type {
             CompType::Nothing => Vec::new(),
             CompType::Command => {
-                let mut ret = get_dir_matches(start);
-                find_lisp_fns(&self.environment.borrow(), &mut ret, start);
-                find_exes(&mut ret, start);
-                ret
+                let mut ret = names_to_candidates(get_dir_matches(start, &matcher));
+                find_lisp_fns(&self.environment.borrow(), &mut ret, start, &matcher);
+                let mut exes = Vec::new();
+                find_exes(&self.environment.borrow(), &mut exes, start, &matcher);
+                ret.append(&mut names_to_candidates(exes));
+                self.show_candidates(start, matcher.rank(start, ret))
             }
             CompType::CommandParen => {
-                let mut ret: Vec<String> = Vec::new();
-                find_lisp_fns(&self.environment.borrow(), &mut ret, start);
-                find_exes(&mut ret, start);
-                ret
+                let mut ret: Vec<Candidate> = Vec::new();
+                find_lisp_fns(&self.environment.borrow(), &mut ret, start, &matcher);
+                let mut exes = Vec::new();
+                find_exes(&self.environment.borrow(), &mut exes, start, &matcher);
+                ret.append(&mut names_to_candidates(exes));
+                self.show_candidates(start, matcher.rank(start, ret))
             }
             CompType::EnvVar => match self.run_hook() {
-                HookResult::Default => get_env_matches(start),
-                HookResult::Path => get_path_matches(start),
-                HookResult::UseList(list) => list,
+                HookResult::Default => {
+                    let ret = names_to_candidates(get_env_matches(start, &matcher));
+                    self.show_candidates(start, matcher.rank(start, ret))
+                }
+                HookResult::Path => {
+                    let ret = names_to_candidates(get_path_matches(start, &matcher));
+                    self.show_candidates(start, matcher.rank(start, ret))
+                }
+                HookResult::UseList(list) => self.show_candidates(start, list),
             },
             CompType::Symbols => match self.run_hook() {
                 HookResult::Default => {
                     let mut ret: Vec<String> = Vec::new();
-                    find_lisp_symbols(&self.environment.borrow(), &mut ret, start);
-                    ret
+                    find_lisp_symbols(&self.environment.borrow(), &mut ret, start, &matcher);
+                    self.show_candidates(start, matcher.rank(start, names_to_candidates(ret)))
                 }
-                HookResult::Path => get_path_matches(start),
-                HookResult::UseList(list) => list,
-            },
-            CompType::Other => match self.run_hook() {
-                HookResult::Default => {
-                    let mut ret = get_dir_matches(start);
-                    find_lisp_symbols(&self.environment.borrow(), &mut ret, start);
-                    ret
+                HookResult::Path => {
+                    let ret = names_to_candidates(get_path_matches(start, &matcher));
+                    self.show_candidates(start, matcher.rank(start, ret))
                 }
-                HookResult::Path => get_path_matches(start),
-                HookResult::UseList(list) => list,
+                HookResult::UseList(list) => self.show_candidates(start, list),
             },
+            CompType::Other => {
+                if let Some(list) = self.run_spec(start) {
+                    return list;
+                }
+                match self.run_hook() {
+                    HookResult::Default if start.starts_with('-') && self.word_index > 0 => {
+                        let options = command_options(&self.args[0], false);
+                        self.show_candidates(start, matcher.rank(start, options))
+                    }
+                    HookResult::Default => {
+                        let mut ret = get_dir_matches(start, &matcher);
+                        find_lisp_symbols(&self.environment.borrow(), &mut ret, start, &matcher);
+                        self.show_candidates(start, matcher.rank(start, names_to_candidates(ret)))
+                    }
+                    HookResult::Path => {
+                        let ret = names_to_candidates(get_path_matches(start, &matcher));
+                        self.show_candidates(start, matcher.rank(start, ret))
+                    }
+                    HookResult::UseList(list) => self.show_candidates(start, list),
+                }
+            }
         }
     }
 
@@ -163,18 +674,28 @@ impl Completer for ShellCompleter {
         self.args.clear();
         if let EventKind::BeforeComplete = event.kind {
             let (words, pos) = event.editor.get_words_and_cursor_position();
-            for word_limits in &words {
+            let mut first_word = 0;
+            for (i, word_limits) in words.iter().enumerate() {
                 let mut word = event
                     .editor
                     .current_buffer()
                     .range(word_limits.0, word_limits.1);
                 if word.contains('(') {
                     self.args.clear();
+                    first_word = i;
                     let mut swords = word.rsplitn(2, '(');
                     word = swords.nth(0).unwrap().to_string();
                 }
                 self.args.push(word);
             }
+            let cursor_word = match pos {
+                CursorPosition::InWord(i)
+                | CursorPosition::OnWordLeftEdge(i)
+                | CursorPosition::OnWordRightEdge(i) => i,
+                CursorPosition::InSpace(Some(i), _) => i + 1,
+                CursorPosition::InSpace(None, _) => 0,
+            };
+            self.word_index = cursor_word.saturating_sub(first_word);
             self.comp_type = match pos {
                 _ if words.is_empty() => CompType::Nothing,
                 CursorPosition::InWord(0) => CompType::Command,
@@ -291,25 +812,111 @@ fn find_file_completions(start: &str, cur_path: &Path) -> Vec<String> {
     res
 }
 
-fn get_dir_matches(start: &str) -> Vec<String> {
+// Most directories a fuzzy path segment can expand to.
+const MAX_FUZZY_DIRS: usize = 20;
+
+fn read_dir_names(dir: &Path) -> Vec<(String, bool)> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| {
+                let is_dir = entry.path().is_dir();
+                (entry.file_name().to_string_lossy().to_string(), is_dir)
+            })
+            .collect(),
+        Err(_err) => Vec::new(),
+    }
+}
+
+// Each part of the path in start is fuzzy matched against the directory entries
+// (exact directories are used as is).
+fn find_fuzzy_file_completions(start: &str, cur_path: &Path, matcher: &Matcher) -> Vec<String> {
+    let (dir_part, name_part) = match start.rfind('/') {
+        Some(idx) => (&start[..=idx], &start[idx + 1..]),
+        None => ("", start),
+    };
+    let resolve = |dir: &str| {
+        if dir.is_empty() {
+            cur_path.to_path_buf()
+        } else {
+            cur_path.join(dir)
+        }
+    };
+    let mut dirs: Vec<String> = vec![if dir_part.starts_with('/') { "/" } else { "" }.to_string()];
+    for segment in dir_part.split('/').filter(|s| !s.is_empty()) {
+        let mut next = Vec::new();
+        for dir in &dirs {
+            let literal = format!("{}{}/", dir, segment);
+            if segment == "." || segment == ".." || resolve(&literal).is_dir() {
+                next.push(literal);
+                continue;
+            }
+            for (name, is_dir) in read_dir_names(&resolve(dir)) {
+                if is_dir && matcher.matches(segment, &name) {
+                    next.push(format!("{}{}/", dir, name));
+                }
+            }
+        }
+        next.truncate(MAX_FUZZY_DIRS);
+        dirs = next;
+    }
+    let mut res = Vec::new();
+    for dir in &dirs {
+        for (name, is_dir) in read_dir_names(&resolve(dir)) {
+            if name_part.is_empty() || matcher.matches(name_part, &name) {
+                let mut item = format!("{}{}", dir, name);
+                if is_dir {
+                    item.push('/');
+                }
+                res.push(item);
+            }
+        }
+    }
+    res
+}
+
+fn get_dir_matches(start: &str, matcher: &Matcher) -> Vec<String> {
     match env::current_dir() {
         Ok(p) => {
-            //let mut fc = FilenameCompleter::new(Some(p));
-            match expand_tilde(start) {
-                Some(s) => find_file_completions(&s, &p), //fc.completions(&s),
-                None => find_file_completions(start, &p), //fc.completions(start),
+            let home = match expand_tilde("~") {
+                Some(home) if start == "~" || start.starts_with("~/") => home,
+                _ => String::new(),
+            };
+            let expanded = if home.is_empty() {
+                start.to_string()
+            } else {
+                format!("{}{}", home, start.get(2..).unwrap_or(""))
+            };
+            let mut matches = if matcher.fuzzy {
+                find_fuzzy_file_completions(&expanded, &p, matcher)
+            } else {
+                find_file_completions(&expanded, &p)
+            };
+            // Put the ~ back so the candidates still match what was typed.
+            if !home.is_empty() {
+                for path in matches.iter_mut() {
+                    if path.starts_with(&home) {
+                        *path = format!("~/{}", &path[home.len()..]);
+                    }
+                }
             }
+            matches
         }
         Err(_err) => Vec::new(),
     }
 }
 
-fn get_path_matches(start: &str) -> Vec<String> {
-    let mut res = get_dir_matches(start);
-    res.drain(..).filter(|p| Path::new(&p).is_dir()).collect()
+fn get_path_matches(start: &str, matcher: &Matcher) -> Vec<String> {
+    let mut res = get_dir_matches(start, matcher);
+    res.drain(..)
+        .filter(|p| match expand_tilde(p) {
+            Some(p) => Path::new(&p).is_dir(),
+            None => Path::new(&p).is_dir(),
+        })
+        .collect()
 }
 
-fn get_env_matches(start: &str) -> Vec<String> {
+fn get_env_matches(start: &str, matcher: &Matcher) -> Vec<String> {
     let env_start = if start.starts_with('$') {
         &start[1..]
     } else {
@@ -317,38 +924,61 @@ fn get_env_matches(start: &str) -> Vec<String> {
     };
     let mut ret: Vec<String> = Vec::new();
     for (key, _value) in env::vars() {
-        if key.starts_with(env_start) {
+        if matcher.matches(env_start, &key) {
             ret.push(format!("${}", key));
         }
     }
     ret
 }
 
-fn find_lisp_fns(environment: &Environment, comps: &mut Vec<String>, org_start: &str) {
+fn find_lisp_fns(
+    environment: &Environment,
+    comps: &mut Vec<Candidate>,
+    org_start: &str,
+    matcher: &Matcher,
+) {
     let (start, need_paren) = if org_start.starts_with('(') {
         (&org_start[1..], true)
     } else {
         (org_start, false)
     };
     let data = &environment.root_scope.borrow().data;
-    for key in data.keys() {
-        if key.starts_with(start) {
-            let val = if need_paren {
+    for (key, val) in data.iter() {
+        if matcher.matches(start, key) {
+            let name = if need_paren {
                 format!("({}", key)
             } else {
                 key.to_string()
             };
-            match **data.get(key).unwrap() {
-                Expression::Func(_) => comps.push(val),
-                Expression::Atom(Atom::Lambda(_)) => comps.push(val),
-                Expression::Atom(Atom::Macro(_)) => comps.push(val),
-                _ => {}
+            // Signature and the first line of the docstring.
+            match expression_doc(key, val) {
+                Some((signature, doc)) => {
+                    let description = match doc.lines().next() {
+                        Some(line) => format!("{} {}", signature, line.trim()),
+                        None => signature,
+                    };
+                    comps.push(Candidate {
+                        name,
+                        description: Some(description),
+                    });
+                }
+                // A builtin without an entry in BUILTIN_DOCS.
+                None if matches!(**val, Expression::Func(_)) => comps.push(Candidate {
+                    name,
+                    description: None,
+                }),
+                None => {}
             }
         }
     }
 }
 
-fn find_lisp_symbols(environment: &Environment, comps: &mut Vec<String>, org_start: &str) {
+fn find_lisp_symbols(
+    environment: &Environment,
+    comps: &mut Vec<String>,
+    org_start: &str,
+    matcher: &Matcher,
+) {
     let (start, need_quote) = if org_start.starts_with('\'') {
         (&org_start[1..], true)
     } else {
@@ -356,7 +986,7 @@ fn find_lisp_symbols(environment: &Environment, comps: &mut Vec<String>, org_sta
     };
     let data = &environment.root_scope.borrow().data;
     for key in data.keys() {
-        if key.starts_with(start) {
+        if matcher.matches(start, key) {
             let val = if need_quote {
                 format!("'{}", key)
             } else {
@@ -374,55 +1004,890 @@ fn find_lisp_symbols(environment: &Environment, comps: &mut Vec<String>, org_sta
     }
 }
 
-fn find_exes(comps: &mut Vec<String>, start: &str) {
+fn find_exes(environment: &Environment, comps: &mut Vec<String>, start: &str, matcher: &Matcher) {
     let (start, need_paren) = if start.starts_with('(') {
         (&start[1..], true)
     } else {
         (start, false)
     };
-    let paths = if let Some(paths) = env::var_os("PATH") {
-        env::split_paths(&paths)
-            .map(|s| {
-                if !s.to_string_lossy().ends_with('/') {
-                    let mut oss = s.into_os_string();
-                    oss.push("/");
-                    oss.into()
-                } else {
-                    s
+    for p in environment.path_hash.borrow_mut().names() {
+        if matcher.matches(start, &p) {
+            let com = if need_paren { format!("({}", p) } else { p };
+            comps.push(com);
+        }
+    }
+}
+
+// Option completions generated from a command's man page or --help output.
+// They are cached in ~/.local/share/slsh/completions keyed on the executable
+// path and its mtime.
+
+fn find_exe_path(command: &str) -> Option<PathBuf> {
+    if command.contains('/') {
+        let path = PathBuf::from(command);
+        return if path.is_file() { Some(path) } else { None };
+    }
+    let paths = env::var_os("PATH")?;
+    for dir in env::split_paths(&paths) {
+        let path = dir.join(command);
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+    None
+}
+
+fn exe_mtime(exe: &Path) -> Option<String> {
+    let modified = fs::metadata(exe).ok()?.modified().ok()?;
+    let since = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("{}.{}", since.as_secs(), since.subsec_nanos()))
+}
+
+fn option_cache_file(exe: &Path, source: &str) -> Option<PathBuf> {
+    let home = env::var("HOME").ok()?;
+    let name = exe.to_string_lossy().replace('/', "%");
+    Some(PathBuf::from(format!(
+        "{}/.local/share/slsh/completions/{}.{}",
+        home, name, source
+    )))
+}
+
+fn read_option_cache(cache: &Path, mtime: &str) -> Option<Vec<Candidate>> {
+    let contents = fs::read_to_string(cache).ok()?;
+    let mut lines = contents.lines();
+    if lines.next()? != mtime {
+        return None;
+    }
+    Some(
+        lines
+            .map(|line| {
+                let mut parts = line.splitn(2, '\t');
+                Candidate {
+                    name: parts.next().unwrap_or("").to_string(),
+                    description: parts
+                        .next()
+                        .filter(|d| !d.is_empty())
+                        .map(|d| d.to_string()),
                 }
             })
-            .collect()
+            .collect(),
+    )
+}
+
+fn write_option_cache(cache: &Path, mtime: &str, options: &[Candidate]) -> io::Result<()> {
+    if let Some(dir) = cache.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut contents = format!("{}\n", mtime);
+    for option in options {
+        let description = match &option.description {
+            Some(d) => d.as_str(),
+            None => "",
+        };
+        contents.push_str(&format!("{}\t{}\n", option.name, description));
+    }
+    fs::write(cache, contents)
+}
+
+// Options for command from its man page, or from running it with --help if
+// use_help is set (this runs the command so it is not done by default).
+pub fn command_options(command: &str, use_help: bool) -> Vec<Candidate> {
+    let exe = match find_exe_path(command) {
+        Some(exe) => exe,
+        None => return Vec::new(),
+    };
+    let mtime = match exe_mtime(&exe) {
+        Some(mtime) => mtime,
+        None => return Vec::new(),
+    };
+    let cache = option_cache_file(&exe, if use_help { "help" } else { "man" });
+    if let Some(cache) = &cache {
+        if let Some(options) = read_option_cache(cache, &mtime) {
+            return options;
+        }
+    }
+    let options = if use_help {
+        help_text(&exe).map(|text| parse_help_options(&text))
+    } else {
+        let name = exe
+            .file_name()
+            .map_or_else(String::new, |n| n.to_string_lossy().to_string());
+        man_page_text(&name).map(|text| parse_man_options(&text))
+    }
+    .unwrap_or_default();
+    // Nothing found is not cached, a man page installed later will be used.
+    if options.is_empty() {
+        return options;
+    }
+    if let Some(cache) = cache {
+        if let Err(err) = write_option_cache(&cache, &mtime, &options) {
+            eprintln!(
+                "WARNING: Unable to write completion cache {}: {}",
+                cache.display(),
+                err
+            );
+        }
+    }
+    options
+}
+
+fn help_text(exe: &Path) -> Option<String> {
+    let mut com = Command::new(exe);
+    com.arg("--help")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // In its own process group so anything it started is killed with it.
+    unsafe {
+        com.pre_exec(|| -> io::Result<()> {
+            unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+        });
+    }
+    let child = com.spawn().ok()?;
+    let pid = child.id();
+    let (tx, rx) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let _ = tx.send(child.wait_with_output());
+    });
+    // Something that does not understand --help may just sit there.
+    let output = match rx.recv_timeout(Duration::from_secs(2)) {
+        Ok(output) => output.ok(),
+        Err(_) => {
+            // Once the group is gone the pipes close and the reader finishes.
+            let _ = signal::killpg(Pid::from_raw(pid as i32), Signal::SIGKILL);
+            None
+        }
+    };
+    let _ = reader.join();
+    let output = output?;
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Some(text)
+}
+
+fn man_page_path(name: &str) -> Option<PathBuf> {
+    let defaults = ["/usr/local/share/man", "/usr/share/man", "/usr/man"];
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    if let Some(manpath) = env::var_os("MANPATH") {
+        for dir in env::split_paths(&manpath) {
+            if dir.as_os_str().is_empty() {
+                dirs.extend(defaults.iter().map(PathBuf::from));
+            } else {
+                dirs.push(dir);
+            }
+        }
+    } else {
+        dirs.extend(defaults.iter().map(PathBuf::from));
+    }
+    for dir in &dirs {
+        for section in &["1", "8", "6"] {
+            for ext in &["", ".gz"] {
+                let path = dir.join(format!("man{}/{}.{}{}", section, name, section, ext));
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+        }
+    }
+    None
+}
+
+// Text of a man page file, a .gz page is decompressed by running gzip -dc (so
+// it needs gzip in $PATH).
+fn read_man_file(path: &Path) -> Option<String> {
+    if path.extension() == Some(OsStr::new("gz")) {
+        let output = Command::new("gzip").arg("-dc").arg(path).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&output.stdout).to_string())
     } else {
-        Vec::new()
+        let bytes = fs::read(path).ok()?;
+        Some(String::from_utf8_lossy(&bytes).to_string())
+    }
+}
+
+fn man_page_text(name: &str) -> Option<String> {
+    let path = man_page_path(name)?;
+    let text = read_man_file(&path)?;
+    // Some pages are just ".so man1/other.1".
+    if let Some(so) = text.lines().next().filter(|l| l.starts_with(".so ")) {
+        let dir = path.parent()?.parent()?;
+        let target = dir.join(so[4..].trim());
+        if target.is_file() {
+            return read_man_file(&target);
+        }
+        let mut gz = target.into_os_string();
+        gz.push(".gz");
+        return read_man_file(Path::new(&gz));
+    }
+    Some(text)
+}
+
+// Plain text from a line of roff, escapes are removed or replaced.
+fn roff_text(line: &str) -> String {
+    let mut text = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    let skip_name = |chars: &mut std::iter::Peekable<std::str::Chars>| match chars.next() {
+        Some('(') => {
+            let name: String = chars.take(2).collect();
+            name
+        }
+        Some('[') => chars.take_while(|c| *c != ']').collect(),
+        Some(ch) => ch.to_string(),
+        None => String::new(),
     };
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            text.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('f') | Some('*') | Some('n') | Some('k') => {
+                skip_name(&mut chars);
+            }
+            Some('(') => {
+                let name: String = chars.by_ref().take(2).collect();
+                match name.as_str() {
+                    "em" | "en" | "hy" | "mi" => text.push('-'),
+                    "aq" | "oq" | "cq" => text.push('\''),
+                    "dq" | "lq" | "rq" => text.push('"'),
+                    "bu" => text.push('*'),
+                    _ => {}
+                }
+            }
+            Some('[') => {
+                let _: String = chars.by_ref().take_while(|c| *c != ']').collect();
+            }
+            Some('s') => {
+                if let Some('+') | Some('-') = chars.peek() {
+                    chars.next();
+                }
+                while let Some(c) = chars.peek() {
+                    if !c.is_ascii_digit() {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            Some('"') => break,
+            Some('-') => text.push('-'),
+            Some('e') | Some('\\') => text.push('\\'),
+            Some(' ') | Some('~') => text.push(' '),
+            Some('&') | Some('c') | Some('%') | Some('/') | Some(',') | Some(':') | Some('^')
+            | Some('|') | Some('0') => {}
+            Some(ch) => text.push(ch),
+            None => {}
+        }
+    }
+    text
+}
 
-    for p in paths {
-        if let Some(p) = p.to_str() {
-            let pat = format!("{}*", p);
-            match glob(&pat) {
-                Ok(paths) => {
-                    for p in paths {
-                        match p {
-                            Ok(p) => {
-                                if let Some(p) = p.file_name() {
-                                    if let Some(p) = p.to_str() {
-                                        if p.starts_with(start) {
-                                            let com = if need_paren {
-                                                format!("({}", p)
-                                            } else {
-                                                p.to_string()
-                                            };
-                                            comps.push(com);
-                                        }
-                                    }
-                                }
-                            }
-                            Err(_err) => {}
-                        }
+fn macro_args(rest: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut chars = rest.chars().peekable();
+    loop {
+        while let Some(' ') | Some('\t') = chars.peek() {
+            chars.next();
+        }
+        match chars.peek() {
+            None => break,
+            Some('"') => {
+                chars.next();
+                args.push(chars.by_ref().take_while(|c| *c != '"').collect());
+            }
+            Some(_) => {
+                let mut arg = String::new();
+                while let Some(ch) = chars.peek() {
+                    if *ch == ' ' || *ch == '\t' {
+                        break;
+                    }
+                    arg.push(*ch);
+                    chars.next();
+                }
+                args.push(arg);
+            }
+        }
+    }
+    args
+}
+
+// Text of a man or mdoc macro that produces text (None if it does not).
+fn macro_text(name: &str, rest: &str) -> Option<String> {
+    let args = macro_args(rest);
+    let text = match name {
+        "B" | "I" | "SM" | "SB" => args.join(" "),
+        "BR" | "RB" | "BI" | "IB" | "IR" | "RI" => args.concat(),
+        // mdoc cross reference, "ssh-agent 1 ." is ssh-agent(1).
+        "Xr" if args.len() > 1 => format!("{}({}){}", args[0], args[1], args[2..].concat()),
+        "It" | "Fl" | "Op" | "Ar" | "Cm" | "Nm" | "Xo" => {
+            // mdoc: Fl adds a dash to the next word, other macros are dropped.
+            let mut words: Vec<String> = Vec::new();
+            let mut flag = name == "Fl";
+            for arg in args {
+                if arg == "Fl" {
+                    flag = true;
+                } else if arg.len() == 2
+                    && arg.starts_with(|c: char| c.is_ascii_uppercase())
+                    && arg.ends_with(|c: char| c.is_ascii_lowercase())
+                {
+                    flag = false;
+                } else if flag {
+                    words.push(format!("-{}", arg));
+                    flag = false;
+                } else {
+                    words.push(arg);
+                }
+            }
+            if flag {
+                words.push("-".to_string());
+            }
+            words.join(" ")
+        }
+        _ => return None,
+    };
+    Some(roff_text(&text))
+}
+
+// The options named in an option tag line, e.g. "-o, --output=FILE".
+fn tag_options(tag: &str) -> Vec<String> {
+    let mut options = Vec::new();
+    for word in tag.split(|c: char| c == ',' || c == '|' || c.is_whitespace()) {
+        let word = word.trim_start_matches('[');
+        let mut chars = word.chars();
+        if chars.next() != Some('-') {
+            continue;
+        }
+        match chars.next() {
+            Some(ch) if ch == '-' || ch.is_alphanumeric() || ch == '?' => {}
+            _ => continue,
+        }
+        let end = word[1..]
+            .find(&['=', '[', ']', '<', '"'][..])
+            .map_or(word.len(), |i| i + 1);
+        let option = word[..end].trim_end_matches(&['.', ':', ';'][..]);
+        if option.len() > 1 && option != "--" {
+            options.push(option.to_string());
+        }
+    }
+    options
+}
+
+fn short_description(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    // The first sentence, not a . on its own as in "implied . and ..".
+    let end = text.match_indices(". ").map(|(idx, _)| idx).find(|idx| {
+        !text[..*idx].ends_with(' ') && text[idx + 2..].starts_with(|c: char| c.is_uppercase())
+    });
+    let text = match end {
+        Some(idx) => &text[..idx],
+        None if text.ends_with('.') && !text.ends_with(" .") && !text.ends_with("..") => {
+            &text[..text.len() - 1]
+        }
+        None => text,
+    };
+    if text.chars().count() > 70 {
+        let mut short: String = text.chars().take(67).collect();
+        short.push_str("...");
+        Some(short)
+    } else {
+        Some(text.to_string())
+    }
+}
+
+fn add_options(options: &mut Vec<Candidate>, tag: &str, description: &str) {
+    let description = short_description(description);
+    for name in tag_options(tag) {
+        if !options.iter().any(|o| o.name == name) {
+            options.push(Candidate {
+                name,
+                description: description.clone(),
+            });
+        }
+    }
+}
+
+// Options from the tagged paragraphs (.TP, .IP and mdoc .It) of a man page.
+pub fn parse_man_options(text: &str) -> Vec<Candidate> {
+    let mut options = Vec::new();
+    let mut tag: Option<String> = None;
+    let mut description = String::new();
+    let mut next_is_tag = false;
+    for line in text.lines() {
+        let line_text = if line.starts_with('.') || line.starts_with('\'') {
+            let mut parts = line[1..].trim_start().splitn(2, char::is_whitespace);
+            let name = parts.next().unwrap_or("");
+            let rest = parts.next().unwrap_or("").trim();
+            match name {
+                "TP" | "TQ" | "IP" | "It" | "PP" | "P" | "LP" | "SH" | "SS" | "Sh" | "Ss"
+                | "Pp" | "El" | "Bl" => {
+                    if let Some(tag) = tag.take() {
+                        add_options(&mut options, &tag, &description);
+                    }
+                    description.clear();
+                    next_is_tag = name == "TP" || name == "TQ";
+                    if name == "IP" {
+                        tag = macro_args(rest).first().map(|t| roff_text(t));
+                    } else if name == "It" {
+                        tag = macro_text(name, rest);
+                    }
+                    continue;
+                }
+                _ => match macro_text(name, rest) {
+                    Some(text) => text,
+                    None => continue,
+                },
+            }
+        } else {
+            roff_text(line)
+        };
+        if next_is_tag {
+            tag = Some(line_text);
+            next_is_tag = false;
+        } else if tag.is_some()
+            && !line_text.trim().is_empty()
+            && !description.contains(". ")
+            && description.len() < 200
+        {
+            if !description.is_empty() {
+                description.push(' ');
+            }
+            description.push_str(line_text.trim());
+        }
+    }
+    if let Some(tag) = tag {
+        add_options(&mut options, &tag, &description);
+    }
+    options
+}
+
+// Options from --help output, lines starting with a dash with the description
+// after two or more spaces (or on the next line).
+pub fn parse_help_options(text: &str) -> Vec<Candidate> {
+    let mut options = Vec::new();
+    let lines: Vec<&str> = text.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_start();
+        if !trimmed.starts_with('-') {
+            continue;
+        }
+        let split = trimmed.find("  ").or_else(|| trimmed.find('\t'));
+        let (tag, description) = match split {
+            Some(idx) => (&trimmed[..idx], trimmed[idx..].trim()),
+            None => (trimmed, ""),
+        };
+        let description = if description.is_empty() {
+            let indent = line.len() - trimmed.len();
+            match lines.get(i + 1) {
+                Some(next) => {
+                    let next_trimmed = next.trim_start();
+                    if next.len() - next_trimmed.len() > indent && !next_trimmed.starts_with('-') {
+                        next_trimmed
+                    } else {
+                        ""
                     }
                 }
-                Err(_err) => {}
+                None => "",
             }
+        } else {
+            description
+        };
+        add_options(&mut options, tag, description);
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From coreutils 9.1 ls.1 (help2man).
+    const LS_MAN: &str = r#".SH DESCRIPTION
+.\" Add any additional description here
+.PP
+List information about the FILEs (the current directory by default).
+Sort entries alphabetically if none of \fB\-cftuvSUX\fR nor \fB\-\-sort\fR is specified.
+.PP
+Mandatory arguments to long options are mandatory for short options too.
+.TP
+\fB\-a\fR, \fB\-\-all\fR
+do not ignore entries starting with .
+.TP
+\fB\-A\fR, \fB\-\-almost\-all\fR
+do not list implied . and ..
+.TP
+\fB\-\-author\fR
+with \fB\-l\fR, print the author of each file
+.TP
+\fB\-b\fR, \fB\-\-escape\fR
+print C\-style escapes for nongraphic characters
+.TP
+\fB\-\-block\-size\fR=\fI\,SIZE\/\fR
+with \fB\-l\fR, scale sizes by SIZE when printing them;
+e.g., '\-\-block\-size=M'; see SIZE format below
+.TP
+\fB\-B\fR, \fB\-\-ignore\-backups\fR
+do not list implied entries ending with ~
+.TP
+\fB\-c\fR
+with \fB\-lt\fR: sort by, and show, ctime (time of last
+modification of file status information);
+with \fB\-l\fR: show ctime and sort by name;
+otherwise: sort by ctime, newest first
+.TP
+\fB\-C\fR
+list entries by columns
+.TP
+\fB\-\-color\fR[=\fI\,WHEN\/\fR]"#;
+
+    // From OpenSSH ssh.1 (mdoc).
+    const SSH_MAN: &str = r#"The options are as follows:
+.Pp
+.Bl -tag -width Ds -compact
+.It Fl 4
+Forces
+.Nm
+to use IPv4 addresses only.
+.Pp
+.It Fl 6
+Forces
+.Nm
+to use IPv6 addresses only.
+.Pp
+.It Fl A
+Enables forwarding of connections from an authentication agent such as
+.Xr ssh-agent 1 .
+This can also be specified on a per-host basis in a configuration file.
+.Pp
+Agent forwarding should be enabled with caution.
+Users with the ability to bypass file permissions on the remote host
+(for the agent's
+.Ux Ns -domain
+socket) can access the local agent through the forwarded connection.
+An attacker cannot obtain key material from the agent,
+however they can perform operations on the keys that enable them to
+authenticate using the identities loaded into the agent.
+A safer alternative may be to use a jump host
+(see
+.Fl J ) .
+.Pp
+.It Fl a
+Disables forwarding of the authentication agent connection.
+.Pp
+.It Fl B Ar bind_interface
+Bind to the address of
+.Ar bind_interface
+before attempting to connect to the destination host.
+This is only useful on systems with more than one address.
+.Pp
+.It Fl b Ar bind_address
+Use
+.Ar bind_address
+on the local machine as the source address
+of the connection.
+Only useful on systems with more than one address.
+.Pp
+.It Fl C"#;
+
+    // From coreutils 9.1 ls --help.
+    const LS_HELP: &str = r#"Usage: ls [OPTION]... [FILE]...
+List information about the FILEs (the current directory by default).
+Sort entries alphabetically if none of -cftuvSUX nor --sort is specified.
+
+Mandatory arguments to long options are mandatory for short options too.
+  -a, --all                  do not ignore entries starting with .
+  -A, --almost-all           do not list implied . and ..
+      --author               with -l, print the author of each file
+  -b, --escape               print C-style escapes for nongraphic characters
+      --block-size=SIZE      with -l, scale sizes by SIZE when printing them;
+                             e.g., '--block-size=M'; see SIZE format below
+
+  -B, --ignore-backups       do not list implied entries ending with ~
+  -c                         with -lt: sort by, and show, ctime (time of last
+                             modification of file status information);
+                             with -l: show ctime and sort by name;
+                             otherwise: sort by ctime, newest first"#;
+
+    // From GNU tar 1.34 --help, descriptions on the next line.
+    const TAR_HELP: &str = r#"  -m, --touch                don't extract file modified time
+      --no-delay-directory-restore
+                             cancel the effect of --delay-directory-restore
+                             option"#;
+
+    fn option<'a>(options: &'a [Candidate], name: &str) -> Option<&'a str> {
+        let option = options.iter().find(|o| o.name == name)?;
+        Some(option.description.as_deref().unwrap_or(""))
+    }
+
+    #[test]
+    fn test_roff_text() {
+        assert_eq!(roff_text(r"\fB\-a\fR, \fB\-\-all\fR"), "-a, --all");
+        assert_eq!(
+            roff_text(r"\fB\-\-block\-size\fR=\fI\,SIZE\/\fR"),
+            "--block-size=SIZE"
+        );
+        assert_eq!(
+            roff_text(r"C\-style \(lqquoted\(rq \s-1small\s0"),
+            "C-style \"quoted\" small"
+        );
+        assert_eq!(roff_text(r#"text \" a comment"#), "text ");
+    }
+
+    #[test]
+    fn test_tag_options() {
+        assert_eq!(tag_options("-a, --all"), vec!["-a", "--all"]);
+        assert_eq!(tag_options("--block-size=SIZE"), vec!["--block-size"]);
+        assert_eq!(tag_options("--color[=WHEN]"), vec!["--color"]);
+        assert_eq!(tag_options("-B bind_interface"), vec!["-B"]);
+        assert!(tag_options("- -- not an option").is_empty());
+    }
+
+    #[test]
+    fn test_parse_man_options() {
+        let options = parse_man_options(LS_MAN);
+        assert_eq!(
+            option(&options, "-a"),
+            Some("do not ignore entries starting with .")
+        );
+        assert_eq!(
+            option(&options, "--all"),
+            Some("do not ignore entries starting with .")
+        );
+        assert_eq!(
+            option(&options, "--almost-all"),
+            Some("do not list implied . and ..")
+        );
+        assert_eq!(
+            option(&options, "--author"),
+            Some("with -l, print the author of each file")
+        );
+        assert_eq!(option(&options, "--block-size").map(|d| d.len()), Some(70));
+        assert_eq!(option(&options, "-C"), Some("list entries by columns"));
+        // Last tag with no description.
+        assert_eq!(option(&options, "--color"), Some(""));
+        // Not from the text before the options.
+        assert_eq!(option(&options, "-cftuvSUX"), None);
+        assert_eq!(option(&options, "--sort"), None);
+
+        let options = parse_man_options(SSH_MAN);
+        let names: Vec<&str> = options.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(names, vec!["-4", "-6", "-A", "-a", "-B", "-b", "-C"]);
+        assert_eq!(
+            option(&options, "-4"),
+            Some("Forces to use IPv4 addresses only")
+        );
+        assert_eq!(
+            option(&options, "-a"),
+            Some("Disables forwarding of the authentication agent connection")
+        );
+    }
+
+    #[test]
+    fn test_parse_help_options() {
+        let options = parse_help_options(LS_HELP);
+        let names: Vec<&str> = options.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "-a",
+                "--all",
+                "-A",
+                "--almost-all",
+                "--author",
+                "-b",
+                "--escape",
+                "--block-size",
+                "-B",
+                "--ignore-backups",
+                "-c"
+            ]
+        );
+        assert_eq!(
+            option(&options, "--almost-all"),
+            Some("do not list implied . and ..")
+        );
+        assert_eq!(
+            option(&options, "--block-size"),
+            Some("with -l, scale sizes by SIZE when printing them;")
+        );
+
+        let options = parse_help_options(TAR_HELP);
+        assert_eq!(
+            option(&options, "--touch"),
+            Some("don't extract file modified time")
+        );
+        assert_eq!(
+            option(&options, "--no-delay-directory-restore"),
+            Some("cancel the effect of --delay-directory-restore")
+        );
+    }
+
+    #[test]
+    fn test_help_text_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::Instant;
+
+        // Ignores --help and leaves a child holding its stdout.
+        let exe = env::temp_dir().join(format!("slsh-test-help-{}", std::process::id()));
+        fs::write(&exe, "#!/bin/sh\nsleep 30 &\nsleep 30\n").unwrap();
+        fs::set_permissions(&exe, fs::Permissions::from_mode(0o755)).unwrap();
+        let start = Instant::now();
+        assert_eq!(help_text(&exe), None);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        fs::remove_file(&exe).unwrap();
+
+        let exe = env::temp_dir().join(format!("slsh-test-help-ok-{}", std::process::id()));
+        fs::write(&exe, "#!/bin/sh\necho '  -x, --extra  an option'\n").unwrap();
+        fs::set_permissions(&exe, fs::Permissions::from_mode(0o755)).unwrap();
+        let options = parse_help_options(&help_text(&exe).unwrap());
+        assert_eq!(option(&options, "--extra"), Some("an option"));
+        fs::remove_file(&exe).unwrap();
+    }
+
+    fn matcher(fuzzy: bool) -> Matcher {
+        Matcher {
+            fuzzy,
+            case_sensitive: false,
+            frecency: None,
         }
     }
+
+    fn candidates(names: &[&str]) -> Vec<Candidate> {
+        names_to_candidates(names.iter().map(|n| n.to_string()).collect())
+    }
+
+    #[test]
+    fn test_matcher_score() {
+        let fuzzy = matcher(true);
+        assert_eq!(fuzzy.score("src", "src/shell.rs"), Some(100));
+        assert_eq!(fuzzy.score("SRC", "src/shell.rs"), None);
+        assert_eq!(fuzzy.score("xyz", "src/shell.rs"), None);
+        // In order only.
+        assert_eq!(fuzzy.score("lhs", "src/shell.rs"), None);
+        let boundary = fuzzy.score("bui_fi", "src/builtins_file.rs").unwrap();
+        let scattered = fuzzy.score("bui_fi", "xbxuxix_xfxi").unwrap();
+        assert!(boundary > scattered);
+        assert!(boundary < 100);
+        let consecutive = fuzzy.score("she", "src/shell.rs").unwrap();
+        let apart = fuzzy.score("she", "src/sxhxe.rs").unwrap();
+        assert!(consecutive > apart);
+        // Case change is a word boundary.
+        assert!(fuzzy.score("fb", "fooBar").unwrap() > fuzzy.score("fb", "foobar").unwrap());
+
+        let prefix = matcher(false);
+        assert_eq!(prefix.score("src", "src/shell.rs"), Some(100));
+        assert_eq!(prefix.score("shell", "src/shell.rs"), None);
+
+        let ranked = fuzzy.rank(
+            "sh",
+            candidates(&["xsxh", "src/shell.rs", "shell.rs", "zz"]),
+        );
+        let names: Vec<&str> = ranked.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["shell.rs", "src/shell.rs", "xsxh"]);
+    }
+
+    #[test]
+    fn test_frecency() {
+        let now = 1_000_000_000;
+        assert_eq!(Frecency::entry_score(4, now, now), 16);
+        assert_eq!(Frecency::entry_score(4, now - 7200, now), 8);
+        assert_eq!(Frecency::entry_score(4, now - 2 * 86400, now), 2);
+        assert_eq!(Frecency::entry_score(4, now - 30 * 86400, now), 1);
+        // Used more wins over the same age.
+        assert!(Frecency::entry_score(8, now, now) > Frecency::entry_score(4, now, now));
+
+        let file = env::temp_dir().join(format!("slsh-test-frecency-{}", std::process::id()));
+        let _ = fs::remove_file(&file);
+        let mut frecency = Frecency::default();
+        frecency.load(file.clone());
+        frecency.record_line_at("git commit src/", now);
+        frecency.record_line_at("git push", now);
+        assert_eq!(frecency.entries.get("git"), Some(&(2, now)));
+        assert_eq!(frecency.entries.get("src"), Some(&(1, now)));
+        // Appended, the last line for git wins when loaded.
+        assert_eq!(fs::read_to_string(&file).unwrap().lines().count(), 5);
+        let mut loaded = Frecency::default();
+        loaded.load(file.clone());
+        assert_eq!(loaded.entries, frecency.entries);
+        // Too many entries drops the lowest scores and rewrites the file.
+        for i in 0..MAX_FRECENCY_ENTRIES {
+            loaded.record_line_at(&format!("old{}", i), now - 30 * 86400);
+        }
+        assert_eq!(loaded.entries.len(), MAX_FRECENCY_ENTRIES);
+        assert!(loaded.entries.contains_key("git"));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap().lines().count(),
+            MAX_FRECENCY_ENTRIES
+        );
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_show_candidates() {
+        let environment = Rc::new(RefCell::new(build_default_environment()));
+        let completer = ShellCompleter::new(environment.clone());
+        let take_list = || std::mem::take(&mut environment.borrow_mut().completion_list);
+
+        // Prefix matches go to liner as is.
+        let names = completer.show_candidates("sr", candidates(&["src/", "srv/"]));
+        assert_eq!(names, vec!["src/", "srv/"]);
+        assert!(take_list().is_empty());
+        // Fuzzy matches with no common prefix longer than the word keep the
+        // word (liner would cut it to the common prefix) and are listed.
+        let start = "bui_fi";
+        let fuzzy = matcher(true).rank(
+            start,
+            candidates(&["src/builtins_file.rs", "lib/build_files.lisp"]),
+        );
+        let names = completer.show_candidates(start, fuzzy);
+        assert_eq!(names, vec![start]);
+        assert_eq!(take_list().len(), 2);
+        // Fuzzy matches sharing a longer prefix (with the word in it) are fine.
+        let names =
+            completer.show_candidates("bfi", candidates(&["builtins_file.rs", "builtins_find.rs"]));
+        assert_eq!(names, vec!["builtins_file.rs", "builtins_find.rs"]);
+        assert!(take_list().is_empty());
+        // Whatever liner gets never has a common prefix that drops what was
+        // typed.
+        for (start, names) in &[
+            ("sh", vec!["xsxh", "src/shell.rs", "shell.rs"]),
+            ("sh", vec!["zzzzzax-s-h", "zzzzzbx-s-h"]),
+            ("SHE", vec!["shell.rs", "SHELL"]),
+            ("fi", vec!["file", "fin", "xfi"]),
+        ] {
+            let ranked = matcher(true).rank(start, candidates(names));
+            let names = completer.show_candidates(start, ranked);
+            let prefix = common_prefix(&names_to_candidates(names));
+            assert!(
+                prefix.starts_with(start) || matcher(true).matches(start, &prefix),
+                "{} cut to {}",
+                start,
+                prefix
+            );
+            take_list();
+        }
+        // Described candidates are listed, the common prefix completed.
+        let described = vec![
+            Candidate {
+                name: "--all".to_string(),
+                description: Some("everything".to_string()),
+            },
+            Candidate {
+                name: "--almost-all".to_string(),
+                description: Some("not . and ..".to_string()),
+            },
+        ];
+        assert_eq!(completer.show_candidates("--a", described), vec!["--al"]);
+        assert_eq!(
+            take_list(),
+            vec![
+                "--all         -- everything",
+                "--almost-all  -- not . and .."
+            ]
+        );
+    }
 }
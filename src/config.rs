@@ -5,6 +5,8 @@ pub struct Config {
     pub command: Option<String>,
     pub script: Option<String>,
     pub args: Vec<String>,
+    pub no_std: bool,
+    pub snapshot: bool,
 }
 
 pub const VERSION_STRING: &str = env!("VERSION_STRING");
@@ -18,6 +20,11 @@ USAGE:
 FLAGS:
     -v, --version  Print the version, platform and revision of server then exit.
     -h, --help     Print help (this) and exit.
+    --no-std       Do not load the standard lisp libraries (slsh_std.lisp and slsh_shell.lisp).
+    --snapshot     Start from a snapshot of the initialized shell (~/.local/share/slsh/image),
+                   it is rebuilt when slshrc or anything it loaded changes.  Scripts use a
+                   snapshot of the standard libraries.  No effect on -c, it never runs
+                   the init scripts.
 
 OPTIONS:
     -c             Command to run instead of entering the REPL.
@@ -47,6 +54,8 @@ pub fn get_config() -> Result<Config, ()> {
     let mut command: Option<String> = None;
     let mut script: Option<String> = None;
     let mut command_args: Vec<String> = Vec::new();
+    let mut no_std = false;
+    let mut snapshot = false;
 
     let mut args: Vec<OsString> = env::args_os().collect();
     args.reverse();
@@ -70,6 +79,12 @@ pub fn get_config() -> Result<Config, ()> {
                         help(&exe_name);
                         return Err(());
                     }
+                    "--no-std" if command_args.is_empty() && script.is_none() => {
+                        no_std = true;
+                    }
+                    "--snapshot" if command_args.is_empty() && script.is_none() => {
+                        snapshot = true;
+                    }
                     _ => {
                         if command.is_none() && script.is_none() {
                             script = Some(arg);
@@ -88,5 +103,7 @@ pub fn get_config() -> Result<Config, ()> {
         command,
         script,
         args: command_args,
+        no_std,
+        snapshot,
     })
 }
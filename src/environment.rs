@@ -1,16 +1,29 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::io;
+use std::path::PathBuf;
 use std::process::Child;
 use std::rc::Rc;
+use std::time::Instant;
 
+use crate::async_segments::AsyncSegments;
 use crate::builtins::add_builtins;
+use crate::builtins_complete::add_complete_builtins;
+use crate::builtins_doc::add_doc_builtins;
+use crate::builtins_editor::add_editor_builtins;
 use crate::builtins_file::add_file_builtins;
 use crate::builtins_list::add_list_builtins;
+use crate::builtins_match::add_match_builtins;
 use crate::builtins_math::add_math_builtins;
+use crate::builtins_path::add_path_builtins;
 use crate::builtins_str::add_str_builtins;
+use crate::completions::{CompletionSpec, Frecency};
+use crate::history::RichHistory;
+use crate::line_editor::EditorConfig;
+use crate::path_hash::PathHash;
 use crate::process::*;
+use crate::spawn::{is_spawned_running, reap_spawned, SpawnedTasks};
 use crate::types::*;
 
 #[derive(Clone, Debug)]
@@ -20,6 +33,19 @@ pub enum IOState {
     Pipe,
     Inherit,
     Null,
+    // A pipe end from a process substitution.
+    Fd(i32),
+}
+
+// What an fd of an external command is redirected to (fd-to, fd-from, fd-dup,
+// fd-close).
+#[derive(Clone, Debug)]
+pub enum FdRedirect {
+    FileAppend(String),
+    FileRead(String),
+    // A copy of another fd, (fd-dup 2 1 ...) is 2>&1.
+    Dup(i32),
+    Close,
 }
 
 #[derive(Clone, Debug)]
@@ -31,6 +57,19 @@ pub struct EnvState {
     pub eval_level: u32,
     pub is_spawn: bool,
     pub pipe_pgid: Option<u32>,
+    // Stdin for the commands of a >(...) process substitution.
+    pub stdin_fd: Option<i32>,
+    // The shell's ends of process substitution pipes, closed once the command
+    // they are arguments for has started.
+    pub subst_fds: Vec<i32>,
+    // Fd redirections for external commands, applied in order.
+    pub fd_redirects: Vec<(i32, FdRedirect)>,
+    // The stages of pipelines by the pid of their last process, for wait to
+    // return every stage's status.
+    pub pipelines: HashMap<u32, Vec<Expression>>,
+    // When the external commands still running (or last run) started by pid,
+    // for *last-duration*.
+    pub started: HashMap<u32, Instant>,
 }
 
 impl Default for EnvState {
@@ -43,6 +82,11 @@ impl Default for EnvState {
             eval_level: 0,
             is_spawn: false,
             pipe_pgid: None,
+            stdin_fd: None,
+            subst_fds: Vec::new(),
+            fd_redirects: Vec::new(),
+            pipelines: HashMap::new(),
+            started: HashMap::new(),
         }
     }
 }
@@ -68,6 +112,11 @@ impl Default for Scope {
         add_str_builtins(&mut data);
         add_list_builtins(&mut data);
         add_file_builtins(&mut data);
+        add_match_builtins(&mut data);
+        add_complete_builtins(&mut data);
+        add_doc_builtins(&mut data);
+        add_path_builtins(&mut data);
+        add_editor_builtins(&mut data);
         Scope { data, outer: None }
     }
 }
@@ -94,6 +143,13 @@ impl Scope {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct Namespace {
+    pub scope: Rc<RefCell<Scope>>,
+    // Only these symbols can be accessed from outside as namespace/symbol.
+    pub exports: HashSet<String>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Environment {
     pub state: EnvState,
@@ -113,6 +169,34 @@ pub struct Environment {
     // Use as a stack of scopes, entering a new pushes and it get popped on exit
     // The actual lookups are done use the scope and it's outer chain NOT this stack.
     pub current_scope: Vec<Rc<RefCell<Scope>>>,
+    // Loaded namespaces (modules) by name.
+    pub namespaces: HashMap<String, Namespace>,
+    // The namespace being defined (None for the root scope), def and defmacro
+    // put their symbols here.
+    pub current_namespace: Option<String>,
+    // Import aliases by the importing namespace ("" for the root scope).
+    pub ns_imports: HashMap<String, HashMap<String, String>>,
+    // Files read by load or import, a snapshot image depends on them.
+    pub loaded_files: Vec<PathBuf>,
+    // Completion specs by command name (see the complete builtin).
+    pub completion_specs: HashMap<String, CompletionSpec>,
+    // Word usage for ranking completions.
+    pub frecency: Rc<RefCell<Frecency>>,
+    // Executables in $PATH by name.
+    pub path_hash: Rc<RefCell<PathHash>>,
+    // Key bindings and history settings for the line editor.
+    pub editor_config: EditorConfig,
+    // Commands run with their time, cwd, status and duration.
+    pub history: Rc<RefCell<RichHistory>>,
+    // Prompt segments computed in the background by name.
+    pub async_segments: Rc<RefCell<AsyncSegments>>,
+    // Functions run on shell events by hook name (see add-hook).
+    pub hooks: HashMap<String, Vec<Expression>>,
+    // Lisp forms running in forked children (spawn) by pid.
+    pub spawned: Rc<RefCell<SpawnedTasks>>,
+    // Candidates with descriptions from the last completion, the line editor
+    // lists them under the line.
+    pub completion_list: Vec<String>,
 }
 
 pub fn build_default_environment() -> Environment {
@@ -133,6 +217,19 @@ pub fn build_default_environment() -> Environment {
         save_exit_status: true,
         root_scope,
         current_scope,
+        namespaces: HashMap::new(),
+        current_namespace: None,
+        ns_imports: HashMap::new(),
+        loaded_files: Vec::new(),
+        completion_specs: HashMap::new(),
+        frecency: Rc::new(RefCell::new(Frecency::default())),
+        path_hash: Rc::new(RefCell::new(PathHash::default())),
+        editor_config: EditorConfig::default(),
+        history: Rc::new(RefCell::new(RichHistory::default())),
+        async_segments: Rc::new(RefCell::new(AsyncSegments::default())),
+        hooks: HashMap::new(),
+        spawned: Rc::new(RefCell::new(HashMap::new())),
+        completion_list: Vec::new(),
     }
 }
 
@@ -162,6 +259,19 @@ pub fn build_new_spawn_scope<S: ::std::hash::BuildHasher>(
         save_exit_status: true,
         root_scope,
         current_scope,
+        namespaces: HashMap::new(),
+        current_namespace: None,
+        ns_imports: HashMap::new(),
+        loaded_files: Vec::new(),
+        completion_specs: HashMap::new(),
+        frecency: Rc::new(RefCell::new(Frecency::default())),
+        path_hash: Rc::new(RefCell::new(PathHash::default())),
+        editor_config: EditorConfig::default(),
+        history: Rc::new(RefCell::new(RichHistory::default())),
+        async_segments: Rc::new(RefCell::new(AsyncSegments::default())),
+        hooks: HashMap::new(),
+        spawned: Rc::new(RefCell::new(HashMap::new())),
+        completion_list: Vec::new(),
     }
 }
 
@@ -174,15 +284,44 @@ pub fn clone_symbols<S: ::std::hash::BuildHasher>(
     scope: &Scope,
     data_in: &mut HashMap<String, Expression, S>,
 ) {
+    // Inner scopes shadow outer ones.
     for (k, v) in &scope.data {
-        let v = &**v;
-        data_in.insert(k.clone(), v.clone());
+        if !data_in.contains_key(k) {
+            data_in.insert(k.clone(), (**v).clone());
+        }
     }
     if let Some(outer) = &scope.outer {
         clone_symbols(&outer.borrow(), data_in);
     }
 }
 
+// Lookup a namespace/symbol, namespace can be an import alias.  Only
+// exported symbols are visible outside of their namespace.
+fn get_qualified_expression(environment: &Environment, key: &str) -> Option<Rc<Expression>> {
+    let idx = key.find('/')?;
+    if idx == 0 || idx == key.len() - 1 {
+        return None;
+    }
+    let (prefix, name) = (&key[..idx], &key[idx + 1..]);
+    let current = match &environment.current_namespace {
+        Some(ns) => ns.clone(),
+        None => "".to_string(),
+    };
+    let ns_name = match environment.ns_imports.get(&current) {
+        Some(imports) => match imports.get(prefix) {
+            Some(ns_name) => ns_name.as_str(),
+            None => prefix,
+        },
+        None => prefix,
+    };
+    let namespace = environment.namespaces.get(ns_name)?;
+    if ns_name == current || namespace.exports.contains(name) {
+        namespace.scope.borrow().data.get(name).cloned()
+    } else {
+        None
+    }
+}
+
 pub fn get_expression(environment: &Environment, key: &str) -> Option<Rc<Expression>> {
     let mut loop_scope = Some(environment.current_scope.last().unwrap().clone());
     while loop_scope.is_some() {
@@ -195,16 +334,27 @@ pub fn get_expression(environment: &Environment, key: &str) -> Option<Rc<Express
     None
 }
 
+// Lookup a symbol in call position, namespace/symbol is only looked up here so
+// as a value (a path argument like util/greet) it is left alone.
+pub fn get_form(environment: &Environment, key: &str) -> Option<Rc<Expression>> {
+    get_expression(environment, key).or_else(|| get_qualified_expression(environment, key))
+}
+
+// Set a symbol in the global scope, this is the current namespace when
+// defining one.
 pub fn set_expression_global(
     environment: &mut Environment,
     key: String,
     expression: Rc<Expression>,
 ) {
-    environment
-        .root_scope
-        .borrow_mut()
-        .data
-        .insert(key, expression);
+    let scope = match &environment.current_namespace {
+        Some(ns) => match environment.namespaces.get(ns) {
+            Some(namespace) => namespace.scope.clone(),
+            None => environment.root_scope.clone(),
+        },
+        None => environment.root_scope.clone(),
+    };
+    scope.borrow_mut().data.insert(key, expression);
 }
 
 pub fn is_expression(environment: &Environment, key: &str) -> bool {
@@ -219,10 +369,45 @@ pub fn is_expression(environment: &Environment, key: &str) -> bool {
             }
             loop_scope = scope.borrow().outer.clone();
         }
-        false
+        get_qualified_expression(environment, key).is_some()
     }
 }
 
+// Make name the current namespace (creating it if needed), its scope is
+// pushed on the current scope so the caller needs to pop it when done.
+pub fn enter_namespace(environment: &mut Environment, name: &str) {
+    let scope = match environment.namespaces.get(name) {
+        Some(namespace) => namespace.scope.clone(),
+        None => {
+            let scope = build_new_scope(Some(environment.root_scope.clone()));
+            environment.namespaces.insert(
+                name.to_string(),
+                Namespace {
+                    scope: scope.clone(),
+                    exports: HashSet::new(),
+                },
+            );
+            scope
+        }
+    };
+    environment.current_scope.push(scope);
+    environment.current_namespace = Some(name.to_string());
+}
+
+// Directories searched by load and import, $SLSH_PATH then ~/.config/slsh/lib.
+pub fn module_search_path() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match env::var_os("SLSH_PATH") {
+        Some(paths) => env::split_paths(&paths).collect(),
+        None => Vec::new(),
+    };
+    if let Ok(home) = env::var("HOME") {
+        let mut lib = PathBuf::from(home);
+        lib.push(".config/slsh/lib");
+        paths.push(lib);
+    }
+    paths
+}
+
 pub fn get_symbols_scope(environment: &Environment, key: &str) -> Option<Rc<RefCell<Scope>>> {
     let mut loop_scope = Some(environment.current_scope.last().unwrap().clone());
     while loop_scope.is_some() {
@@ -237,11 +422,13 @@ pub fn get_symbols_scope(environment: &Environment, key: &str) -> Option<Rc<RefC
 
 pub fn add_process(environment: &Environment, process: Child) -> u32 {
     let pid = process.id();
+    // A reaped spawned task with this pid is not this process.
+    environment.spawned.borrow_mut().remove(&pid);
     environment.procs.borrow_mut().insert(pid, process);
     pid
 }
 
-pub fn reap_procs(environment: &Environment) -> io::Result<()> {
+pub fn reap_procs(environment: &mut Environment) -> io::Result<()> {
     let mut procs = environment.procs.borrow_mut();
     let keys: Vec<u32> = procs.keys().copied().collect();
     let mut pids: Vec<u32> = Vec::with_capacity(keys.len());
@@ -255,5 +442,85 @@ pub fn reap_procs(environment: &Environment) -> io::Result<()> {
         try_wait_pid(environment, pid);
     }
     // XXX remove them or better replace pid with exit status
+    reap_spawned(environment);
+    // Pipelines with nothing left running are done with (wait on one of them
+    // now is a plain wait), this keeps a reused pid from finding an old one.
+    let procs = environment.procs.clone();
+    let mut pipelines = std::mem::take(&mut environment.state.pipelines);
+    pipelines.retain(|_, stages| {
+        stages.iter().any(|stage| match stage {
+            Expression::Process(ProcessState::Running(pid)) => {
+                procs.borrow().contains_key(pid) || is_spawned_running(environment, *pid)
+            }
+            _ => false,
+        })
+    });
+    environment.state.pipelines = pipelines;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::reader::read;
+    use crate::shell::eval;
+    use crate::spawn::{is_spawned, spawned_exit};
+
+    fn eval_str(environment: &mut Environment, text: &str) -> String {
+        let exp = read(text).unwrap();
+        let value = eval(environment, &exp).unwrap();
+        value.make_string(environment).unwrap()
+    }
+
+    #[test]
+    fn test_qualified_symbols() {
+        let mut environment = build_default_environment();
+        let env = &mut environment;
+        // Like loading a file with a ns in it.
+        let scope_depth = env.current_scope.len();
+        eval_str(env, "(ns util :export (greet))");
+        eval_str(env, "(def 'greet (fn (x) x))");
+        eval_str(env, "(def 'helper (fn (x) x))");
+        env.current_scope.truncate(scope_depth);
+        env.current_namespace = None;
+
+        assert!(get_form(env, "util/greet").is_some());
+        assert!(get_form(env, "util/helper").is_none());
+        assert!(get_expression(env, "util/greet").is_none());
+        assert_eq!(eval_str(env, "(util/greet \"hi\")"), "hi");
+        // Not in call position it is just a symbol (a path for a command).
+        let symbol = Expression::Atom(Atom::Symbol("util/greet".to_string()));
+        env.loose_symbols = true;
+        let value = eval(env, &symbol).unwrap();
+        assert_eq!(value.make_string(env).unwrap(), "util/greet");
+        env.loose_symbols = false;
+        assert!(eval(env, &symbol).is_err());
+    }
+
+    #[test]
+    fn test_reap_spawned() {
+        let mut environment = build_default_environment();
+        let env = &mut environment;
+        let task = eval(env, &read("(spawn (+ 1 2))").unwrap()).unwrap();
+        let pid = match task {
+            Expression::Process(ProcessState::Running(pid)) => pid,
+            _ => panic!("spawn did not return a process"),
+        };
+        for _ in 0..50 {
+            reap_procs(env).unwrap();
+            if !is_spawned_running(env, pid) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert_eq!(spawned_exit(env, pid), Some(Some(0)));
+        // Reaped, wait still has its value and then forgets it.
+        let wait = Expression::List(vec![
+            Expression::Atom(Atom::Symbol("wait".to_string())),
+            task,
+        ]);
+        assert_eq!(eval(env, &wait).unwrap().make_string(env).unwrap(), "3");
+        assert!(!is_spawned(env, pid));
+    }
+}
@@ -0,0 +1,688 @@
+use std::fs;
+use std::io;
+
+use crate::environment::*;
+use crate::types::*;
+
+// Globbing done by the shell for command arguments and the glob builtin.
+// Supports *, ?, [a-z] and [!a-z], ** for any number of directories and
+// (a|b) alternatives, with *glob-extended* set also ^pat (anything but pat)
+// and pat~exclude (pat without the paths matching exclude) like zsh.
+
+// Brace ranges bigger than this are left alone.
+const MAX_BRACE_RANGE: i64 = 100_000;
+
+#[derive(Clone, Debug)]
+enum Item {
+    Char(char),
+    // ?
+    Any,
+    // *
+    Star,
+    // [a-z] or [!a-z] (negated)
+    Class(Vec<(char, char)>, bool),
+    // (a|b)
+    Alt(Vec<Vec<Item>>),
+}
+
+// One component (between /'s) of a pattern.
+#[derive(Debug)]
+enum Component {
+    Literal(String),
+    // **
+    AnyDirs,
+    Pattern(Vec<Item>, bool),
+}
+
+fn find_close(chars: &[char], open: usize, open_ch: char, close_ch: char) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = open;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            ch if ch == open_ch => depth += 1,
+            ch if ch == close_ch => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Split chars on sep outside of brackets and parens (escapes are kept).
+fn split_top(chars: &[char], sep: char) -> Vec<Vec<char>> {
+    let mut parts = vec![Vec::new()];
+    let mut depth = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        match ch {
+            '\\' if i + 1 < chars.len() => {
+                parts.last_mut().unwrap().push(ch);
+                i += 1;
+                parts.last_mut().unwrap().push(chars[i]);
+                i += 1;
+                continue;
+            }
+            '(' | '{' | '[' => depth += 1,
+            ')' | '}' | ']' if depth > 0 => depth -= 1,
+            _ if ch == sep && depth == 0 => {
+                parts.push(Vec::new());
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        parts.last_mut().unwrap().push(ch);
+        i += 1;
+    }
+    parts
+}
+
+fn parse_class(chars: &[char], open: usize) -> Option<(Item, usize)> {
+    let mut i = open + 1;
+    let negated = matches!(chars.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut ranges = Vec::new();
+    let first = i;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch == ']' && i > first {
+            return Some((Item::Class(ranges, negated), i));
+        }
+        if chars.get(i + 1) == Some(&'-') && i + 2 < chars.len() && chars[i + 2] != ']' {
+            ranges.push((ch, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((ch, ch));
+            i += 1;
+        }
+    }
+    None
+}
+
+fn parse_items(chars: &[char]) -> Vec<Item> {
+    let mut items = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                items.push(Item::Char(chars[i]));
+            }
+            '?' => items.push(Item::Any),
+            '*' => {
+                if !matches!(items.last(), Some(Item::Star)) {
+                    items.push(Item::Star);
+                }
+            }
+            '[' => match parse_class(chars, i) {
+                Some((class, close)) => {
+                    items.push(class);
+                    i = close;
+                }
+                None => items.push(Item::Char('[')),
+            },
+            '(' => {
+                let close = find_close(chars, i, '(', ')');
+                let alts = close.map(|close| split_top(&chars[i + 1..close], '|'));
+                match (close, alts) {
+                    (Some(close), Some(alts)) if alts.len() > 1 => {
+                        items.push(Item::Alt(alts.iter().map(|a| parse_items(a)).collect()));
+                        i = close;
+                    }
+                    _ => items.push(Item::Char('(')),
+                }
+            }
+            ch => items.push(Item::Char(ch)),
+        }
+        i += 1;
+    }
+    items
+}
+
+fn matches_items(items: &[Item], text: &[char]) -> bool {
+    match items.split_first() {
+        None => text.is_empty(),
+        Some((Item::Star, rest)) => (0..=text.len()).any(|i| matches_items(rest, &text[i..])),
+        Some((item, rest)) => match item {
+            Item::Alt(alts) => alts.iter().any(|alt| {
+                let mut seq = alt.clone();
+                seq.extend_from_slice(rest);
+                matches_items(&seq, text)
+            }),
+            _ => match text.split_first() {
+                Some((ch, text)) => {
+                    let ok = match item {
+                        Item::Char(c) => c == ch,
+                        Item::Class(ranges, negated) => {
+                            ranges.iter().any(|(a, b)| a <= ch && ch <= b) != *negated
+                        }
+                        _ => true,
+                    };
+                    ok && matches_items(rest, text)
+                }
+                None => false,
+            },
+        },
+    }
+}
+
+fn is_literal(items: &[Item]) -> bool {
+    items.iter().all(|item| matches!(item, Item::Char(_)))
+}
+
+fn parse_component(text: &str, extended: bool) -> Component {
+    if text == "**" {
+        return Component::AnyDirs;
+    }
+    let (text, negated) = if extended && text.len() > 1 && text.starts_with('^') {
+        (&text[1..], true)
+    } else {
+        (text, false)
+    };
+    let chars: Vec<char> = text.chars().collect();
+    let items = parse_items(&chars);
+    if is_literal(&items) && !negated {
+        let literal = items
+            .iter()
+            .map(|item| match item {
+                Item::Char(ch) => *ch,
+                _ => unreachable!(),
+            })
+            .collect();
+        Component::Literal(literal)
+    } else {
+        Component::Pattern(items, negated)
+    }
+}
+
+struct Glob {
+    components: Vec<Component>,
+    absolute: bool,
+    excludes: Vec<Vec<Item>>,
+}
+
+fn parse_glob(pattern: &str, extended: bool) -> Glob {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut parts = if extended {
+        split_top(&chars, '~')
+    } else {
+        vec![chars]
+    };
+    // A ~ at the start was not expanded (no HOME) so is not an exclusion.
+    if parts.len() > 1 && parts[0].is_empty() {
+        parts = vec![pattern.chars().collect()];
+    }
+    let main: String = parts[0].iter().collect();
+    let excludes = parts[1..].iter().map(|p| parse_items(p)).collect();
+    Glob {
+        absolute: main.starts_with('/'),
+        components: main
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .map(|c| parse_component(c, extended))
+            .collect(),
+        excludes,
+    }
+}
+
+// Does arg need globbing (has a pattern in it)?
+pub fn is_glob(arg: &str, extended: bool) -> bool {
+    let glob = parse_glob(arg, extended);
+    !glob.excludes.is_empty()
+        || glob
+            .components
+            .iter()
+            .any(|c| !matches!(c, Component::Literal(_)))
+}
+
+fn read_names(dir: &str) -> Vec<(String, bool)> {
+    let dir = if dir.is_empty() { "." } else { dir };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_err) => return Vec::new(),
+    };
+    let mut names: Vec<(String, bool)> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let is_dir = entry.path().is_dir();
+            (entry.file_name().to_string_lossy().to_string(), is_dir)
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+fn walk(prefix: &str, components: &[Component], trailing_slash: bool, out: &mut Vec<String>) {
+    let (component, rest) = match components.split_first() {
+        Some(split) => split,
+        None => {
+            let path = if prefix.is_empty() { "." } else { prefix };
+            if fs::symlink_metadata(path).is_ok() {
+                out.push(prefix.to_string());
+            }
+            return;
+        }
+    };
+    let join = |name: &str| {
+        if rest.is_empty() && !trailing_slash {
+            format!("{}{}", prefix, name)
+        } else {
+            format!("{}{}/", prefix, name)
+        }
+    };
+    match component {
+        Component::Literal(name) => walk(&join(name), rest, trailing_slash, out),
+        Component::AnyDirs => {
+            walk(prefix, rest, trailing_slash, out);
+            for (name, is_dir) in read_names(prefix) {
+                // Like zsh ** does not follow symlinks to directories.
+                let path = format!("{}{}", prefix, name);
+                let is_link = fs::symlink_metadata(&path)
+                    .map(|md| md.file_type().is_symlink())
+                    .unwrap_or(true);
+                if is_dir && !is_link && !name.starts_with('.') {
+                    walk(&format!("{}/", path), components, trailing_slash, out);
+                }
+            }
+        }
+        Component::Pattern(items, negated) => {
+            let dot_ok = matches!(items.first(), Some(Item::Char('.')));
+            for (name, is_dir) in read_names(prefix) {
+                if name.starts_with('.') && !dot_ok {
+                    continue;
+                }
+                let chars: Vec<char> = name.chars().collect();
+                if matches_items(items, &chars) == *negated {
+                    continue;
+                }
+                if rest.is_empty() && !trailing_slash {
+                    out.push(join(&name));
+                } else if is_dir {
+                    walk(&join(&name), rest, trailing_slash, out);
+                }
+            }
+        }
+    }
+}
+
+// The paths matching pattern, sorted.
+pub fn expand_glob(pattern: &str, extended: bool) -> Vec<String> {
+    let glob = parse_glob(pattern, extended);
+    let prefix = if glob.absolute { "/" } else { "" };
+    let trailing_slash = pattern.ends_with('/') && !glob.components.is_empty();
+    let mut out = Vec::new();
+    walk(prefix, &glob.components, trailing_slash, &mut out);
+    if !glob.excludes.is_empty() {
+        out.retain(|path| {
+            let chars: Vec<char> = path.trim_end_matches('/').chars().collect();
+            !glob.excludes.iter().any(|ex| matches_items(ex, &chars))
+        });
+    }
+    out.sort();
+    out.dedup();
+    out
+}
+
+// The values from from to to (either way) by step, stops before overflowing.
+fn range_steps(from: i64, to: i64, step: i64) -> Vec<i64> {
+    let mut values = Vec::new();
+    let mut i = from;
+    loop {
+        values.push(i);
+        let next = if from <= to {
+            i.checked_add(step)
+        } else {
+            i.checked_sub(step)
+        };
+        i = match next {
+            Some(next) if (from <= to && next <= to) || (from > to && next >= to) => next,
+            _ => return values,
+        };
+    }
+}
+
+fn range_values(range: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = range.split("..").collect();
+    let (start, end, step) = match parts[..] {
+        [start, end] => (start, end, None),
+        [start, end, step] => (start, end, Some(step.parse::<i64>().ok()?)),
+        _ => return None,
+    };
+    let step = match step {
+        Some(step) => step.checked_abs()?.max(1),
+        None => 1,
+    };
+    if let (Ok(from), Ok(to)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        if to.checked_sub(from)?.checked_abs()? / step > MAX_BRACE_RANGE {
+            return None;
+        }
+        let padded = |s: &str| {
+            s.trim_start_matches('-').len() > 1 && s.trim_start_matches('-').starts_with('0')
+        };
+        let width = if padded(start) || padded(end) {
+            start.len().max(end.len())
+        } else {
+            0
+        };
+        return Some(
+            range_steps(from, to, step)
+                .iter()
+                .map(|i| format!("{:0width$}", i, width = width))
+                .collect(),
+        );
+    }
+    let mut start_chars = start.chars();
+    let mut end_chars = end.chars();
+    match (
+        start_chars.next(),
+        start_chars.next(),
+        end_chars.next(),
+        end_chars.next(),
+    ) {
+        (Some(from), None, Some(to), None) => range_steps(from as i64, to as i64, step)
+            .iter()
+            .map(|i| std::char::from_u32(*i as u32).map(|ch| ch.to_string()))
+            .collect(),
+        _ => None,
+    }
+}
+
+// The words in a brace expression starting at open ({a,b} or {1..3}) and
+// where it closes, None if it is just a brace.
+fn brace_words(chars: &[char], open: usize) -> Option<(Vec<String>, usize)> {
+    let close = find_close(chars, open, '{', '}')?;
+    let inner = &chars[open + 1..close];
+    let parts = split_top(inner, ',');
+    if parts.len() > 1 {
+        return Some((parts.iter().map(|p| p.iter().collect()).collect(), close));
+    }
+    let inner: String = inner.iter().collect();
+    Some((range_values(&inner)?, close))
+}
+
+// Expand {a,b} alternatives and {1..10} (or {a..e}, {01..10..2}) ranges,
+// braces without either are left alone.
+pub fn expand_braces(arg: &str) -> Vec<String> {
+    let chars: Vec<char> = arg.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '{' => {
+                if let Some((words, close)) = brace_words(&chars, i) {
+                    let prefix: String = chars[..i].iter().collect();
+                    let suffix: String = chars[close + 1..].iter().collect();
+                    return words
+                        .iter()
+                        .flat_map(|word| expand_braces(&format!("{}{}{}", prefix, word, suffix)))
+                        .collect();
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    vec![arg.to_string()]
+}
+
+// How an argument that matches nothing is handled (*glob-no-match*).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoMatch {
+    // Pass the pattern on as is (the default).
+    Pass,
+    // Drop it.
+    Empty,
+    Error,
+}
+
+// *glob-extended* (t or nil) and *glob-no-match* ('pass, 'empty or 'error).
+pub fn glob_settings(environment: &Environment) -> io::Result<(bool, NoMatch)> {
+    let extended = match get_expression(environment, "*glob-extended*") {
+        Some(exp) => !matches!(*exp, Expression::Atom(Atom::Nil)),
+        None => false,
+    };
+    let no_match = match get_expression(environment, "*glob-no-match*") {
+        Some(exp) => match &*exp {
+            Expression::Atom(Atom::Symbol(s)) | Expression::Atom(Atom::String(s)) => {
+                match s.as_str() {
+                    "pass" => NoMatch::Pass,
+                    "empty" => NoMatch::Empty,
+                    "error" => NoMatch::Error,
+                    _ => {
+                        let msg =
+                            format!("*glob-no-match* must be 'pass, 'empty or 'error, not {}", s);
+                        return Err(io::Error::new(io::ErrorKind::Other, msg));
+                    }
+                }
+            }
+            Expression::Atom(Atom::Nil) => NoMatch::Pass,
+            exp => {
+                let msg = format!(
+                    "*glob-no-match* must be 'pass, 'empty or 'error, not {}",
+                    exp.to_string()
+                );
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        },
+        None => NoMatch::Pass,
+    };
+    Ok((extended, no_match))
+}
+
+// Drop the \ from escaped chars (\* is a literal *).
+fn unescape(arg: &str) -> String {
+    let mut out = String::with_capacity(arg.len());
+    let mut chars = arg.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => out.push(chars.next().unwrap_or(ch)),
+            ch => out.push(ch),
+        }
+    }
+    out
+}
+
+// Glob a command argument, paths are added to args.
+pub fn glob_arg(
+    arg: &str,
+    extended: bool,
+    no_match: NoMatch,
+    args: &mut Vec<String>,
+) -> io::Result<()> {
+    if !is_glob(arg, extended) {
+        args.push(unescape(arg));
+        return Ok(());
+    }
+    let paths = expand_glob(arg, extended);
+    if paths.is_empty() {
+        match no_match {
+            NoMatch::Pass => args.push(unescape(arg)),
+            NoMatch::Empty => {}
+            NoMatch::Error => {
+                let msg = format!("no matches found: {}", arg);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+    } else {
+        args.extend(paths);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    // A directory with files (a trailing / makes a directory) for a test.
+    fn make_tree(name: &str, files: &[&str]) -> String {
+        let dir = env::temp_dir().join(format!("slsh-glob-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            if file.ends_with('/') {
+                fs::create_dir_all(&path).unwrap();
+            } else {
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, "").unwrap();
+            }
+        }
+        dir.to_string_lossy().to_string()
+    }
+
+    // The paths matching pattern in dir, relative to dir.
+    fn glob_in(dir: &str, pattern: &str, extended: bool) -> Vec<String> {
+        expand_glob(&format!("{}/{}", dir, pattern), extended)
+            .iter()
+            .map(|path| path[dir.len() + 1..].to_string())
+            .collect()
+    }
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        let items = parse_items(&pattern.chars().collect::<Vec<char>>());
+        matches_items(&items, &text.chars().collect::<Vec<char>>())
+    }
+
+    #[test]
+    fn test_braces() {
+        assert_eq!(expand_braces("a{b,c}d"), vec!["abd", "acd"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), vec!["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("x{a,{b,c}}"), vec!["xa", "xb", "xc"]);
+        assert_eq!(expand_braces("{a,}b"), vec!["ab", "b"]);
+        assert_eq!(expand_braces("{a}"), vec!["{a}"]);
+        assert_eq!(expand_braces("{a,b"), vec!["{a,b"]);
+        assert_eq!(expand_braces("\\{a,b}"), vec!["\\{a,b}"]);
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(expand_braces("{1..3}"), vec!["1", "2", "3"]);
+        assert_eq!(expand_braces("{3..1}"), vec!["3", "2", "1"]);
+        assert_eq!(expand_braces("{-1..1}"), vec!["-1", "0", "1"]);
+        assert_eq!(expand_braces("{01..10..3}"), vec!["01", "04", "07", "10"]);
+        assert_eq!(expand_braces("{a..e..2}"), vec!["a", "c", "e"]);
+        assert_eq!(expand_braces("f{c..a}"), vec!["fc", "fb", "fa"]);
+        assert_eq!(expand_braces("{1..ab}"), vec!["{1..ab}"]);
+        assert_eq!(expand_braces("{0..100001}"), vec!["{0..100001}"]);
+        assert_eq!(
+            expand_braces("{9223372036854775806..9223372036854775807}"),
+            vec!["9223372036854775806", "9223372036854775807"]
+        );
+        assert_eq!(
+            expand_braces("{9223372036854775800..9223372036854775807..5}"),
+            vec!["9223372036854775800", "9223372036854775805"]
+        );
+        assert_eq!(
+            expand_braces("{-9223372036854775807..-9223372036854775808}"),
+            vec!["-9223372036854775807", "-9223372036854775808"]
+        );
+        let overflow = "{-9223372036854775808..9223372036854775807}";
+        assert_eq!(expand_braces(overflow), vec![overflow]);
+        let overflow = "{1..2..-9223372036854775808}";
+        assert_eq!(expand_braces(overflow), vec![overflow]);
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[a-c]x", "dx"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(!matches("[^a-c]x", "ax"));
+        assert!(matches("[]a]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("f[xyz0-9]", "f5"));
+        assert!(matches("[a", "[a"));
+        assert!(matches("*.(rs|toml)", "Cargo.toml"));
+        assert!(!matches("*.(rs|toml)", "Cargo.lock"));
+        assert!(matches("a\\*", "a*"));
+        assert!(!matches("a\\*", "ab"));
+    }
+
+    #[test]
+    fn test_any_dirs() {
+        let dir = make_tree(
+            "any-dirs",
+            &[
+                "a.rs",
+                "b.txt",
+                "src/c.rs",
+                "src/d/e.rs",
+                "src/.hidden/f.rs",
+                "g/",
+            ],
+        );
+        assert_eq!(
+            glob_in(&dir, "**/*.rs", false),
+            vec!["a.rs", "src/c.rs", "src/d/e.rs"]
+        );
+        assert_eq!(
+            glob_in(&dir, "src/**/*.rs", false),
+            vec!["src/c.rs", "src/d/e.rs"]
+        );
+        assert_eq!(
+            glob_in(&dir, "**/", false),
+            vec!["", "g/", "src/", "src/d/"]
+        );
+        assert_eq!(glob_in(&dir, "*/", false), vec!["g/", "src/"]);
+        assert_eq!(
+            glob_in(&dir, "src/.*/*.rs", false),
+            vec!["src/.hidden/f.rs"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exclusion() {
+        let dir = make_tree("exclusion", &["a.rs", "b.rs", "c.txt", "d/e.rs"]);
+        assert_eq!(glob_in(&dir, "^*.rs", true), vec!["c.txt", "d"]);
+        assert_eq!(glob_in(&dir, "*~*b.rs", true), vec!["a.rs", "c.txt", "d"]);
+        assert_eq!(glob_in(&dir, "**/*.rs~*/d/*", true), vec!["a.rs", "b.rs"]);
+        // Not extended these are just chars.
+        assert!(glob_in(&dir, "^*.rs", false).is_empty());
+        assert!(glob_in(&dir, "*~*b.rs", false).is_empty());
+        assert!(!is_glob("^a", false));
+        assert!(is_glob("^a", true));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_match() {
+        let dir = make_tree("no-match", &["a.rs"]);
+        let pattern = format!("{}/*.txt", dir);
+        let mut args = Vec::new();
+        glob_arg(&pattern, false, NoMatch::Pass, &mut args).unwrap();
+        assert_eq!(args, vec![pattern.clone()]);
+        let mut args = Vec::new();
+        glob_arg(&pattern, false, NoMatch::Empty, &mut args).unwrap();
+        assert!(args.is_empty());
+        assert!(glob_arg(&pattern, false, NoMatch::Error, &mut args).is_err());
+        glob_arg(&format!("{}/*.rs", dir), false, NoMatch::Error, &mut args).unwrap();
+        assert_eq!(args, vec![format!("{}/a.rs", dir)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_literal_args() {
+        let mut args = Vec::new();
+        glob_arg("plain", false, NoMatch::Error, &mut args).unwrap();
+        glob_arg("\\*", false, NoMatch::Error, &mut args).unwrap();
+        glob_arg("a\\{b\\}", false, NoMatch::Error, &mut args).unwrap();
+        glob_arg("\\\\", false, NoMatch::Error, &mut args).unwrap();
+        glob_arg("end\\", false, NoMatch::Error, &mut args).unwrap();
+        assert_eq!(args, vec!["plain", "*", "a{b}", "\\", "end\\"]);
+        let mut args = Vec::new();
+        glob_arg("/no-such-dir-slsh/\\[*", false, NoMatch::Pass, &mut args).unwrap();
+        assert_eq!(args, vec!["/no-such-dir-slsh/[*"]);
+    }
+}
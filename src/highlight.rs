@@ -0,0 +1,233 @@
+use crate::builtins_util::*;
+use crate::environment::*;
+use crate::reader::*;
+use crate::types::*;
+
+// Colors for the line being edited, set *syntax-highlight* to nil in slshrc to
+// turn it off.
+const RESET: &str = "\x1b[0m";
+const STRING: &str = "\x1b[33m";
+const NUMBER: &str = "\x1b[36m";
+const CONSTANT: &str = "\x1b[35m";
+const ENV_VAR: &str = "\x1b[1;36m";
+const QUOTE: &str = "\x1b[35m";
+const COMMENT: &str = "\x1b[90m";
+const BUILTIN: &str = "\x1b[1;34m";
+const LAMBDA: &str = "\x1b[34m";
+const MACRO: &str = "\x1b[1;35m";
+const EXECUTABLE: &str = "\x1b[1;32m";
+const UNKNOWN: &str = "\x1b[31m";
+const ERROR: &str = "\x1b[1;37;41m";
+// Inline suggestion after the line.
+const SUGGESTION: &str = "\x1b[90m";
+// Matching parens get the same color, cycling by depth.
+const PARENS: &[&str] = &["\x1b[1;34m", "\x1b[1;33m", "\x1b[1;35m", "\x1b[1;36m"];
+
+fn is_number(text: &str) -> bool {
+    text.parse::<i64>().is_ok() || text.parse::<f64>().is_ok()
+}
+
+fn command_color(environment: &Environment, command: &str) -> &'static str {
+    if let Some(exp) = get_form(environment, command) {
+        match &*exp {
+            Expression::Func(_) => return BUILTIN,
+            Expression::Atom(Atom::Lambda(_)) => return LAMBDA,
+            Expression::Atom(Atom::Macro(_)) => return MACRO,
+            _ => {}
+        }
+    }
+    let command = match expand_tilde(command) {
+        Some(command) => command,
+        None => command.to_string(),
+    };
+    if environment
+        .path_hash
+        .borrow_mut()
+        .lookup(&command)
+        .is_some()
+    {
+        EXECUTABLE
+    } else {
+        UNKNOWN
+    }
+}
+
+fn symbol_color(text: &str) -> Option<&'static str> {
+    if text.starts_with('"') {
+        Some(STRING)
+    } else if is_number(text) {
+        Some(NUMBER)
+    } else if text == "t" || text == "nil" || text.starts_with(':') {
+        Some(CONSTANT)
+    } else if text.starts_with('$') {
+        Some(ENV_VAR)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ListKind {
+    Call,
+    Quoted,
+    // Parameters of fn/defn/defmacro, the bindings of a let and one binding.
+    Params,
+    Bindings,
+    Binding,
+}
+
+struct OpenList {
+    token: usize,
+    kind: ListKind,
+    head: String,
+    len: usize,
+}
+
+fn child_kind(parent: Option<&OpenList>, prev: &str) -> ListKind {
+    match prev {
+        "'" | "`" => return ListKind::Quoted,
+        "," | ",@" => return ListKind::Call,
+        _ => {}
+    }
+    let parent = match parent {
+        Some(parent) => parent,
+        None => return ListKind::Call,
+    };
+    match (parent.kind, parent.head.as_str(), parent.len) {
+        (ListKind::Quoted, _, _) | (ListKind::Params, _, _) => ListKind::Quoted,
+        (ListKind::Bindings, _, _) => ListKind::Binding,
+        (ListKind::Call, "fn", 1) | (ListKind::Call, "defn", 2) => ListKind::Params,
+        (ListKind::Call, "defmacro", 2) => ListKind::Params,
+        (ListKind::Call, "let", 1) => ListKind::Bindings,
+        _ => ListKind::Call,
+    }
+}
+
+// Colors for the tokens of line, commands are the first symbol in an unquoted
+// list (or the line if it does not start with a paren).
+fn token_colors(environment: &Environment, tokens: &[Token]) -> Vec<Option<&'static str>> {
+    let mut colors = vec![None; tokens.len()];
+    let mut opens: Vec<OpenList> = Vec::new();
+    let starts_list = match tokens.first() {
+        Some(token) => token.text == "(" || token.text == "'" || token.text == "`",
+        None => return colors,
+    };
+    let mut top_len = if starts_list { 1 } else { 0 };
+    for (i, token) in tokens.iter().enumerate() {
+        let prev = if i > 0 {
+            tokens[i - 1].text.as_str()
+        } else {
+            ""
+        };
+        match token.text.as_str() {
+            "(" => {
+                let kind = child_kind(opens.last(), prev);
+                match opens.last_mut() {
+                    Some(parent) => parent.len += 1,
+                    None => top_len += 1,
+                }
+                opens.push(OpenList {
+                    token: i,
+                    kind,
+                    head: String::new(),
+                    len: 0,
+                });
+            }
+            ")" => match opens.pop() {
+                Some(open) => {
+                    let color = PARENS[opens.len() % PARENS.len()];
+                    colors[open.token] = Some(color);
+                    colors[i] = Some(color);
+                }
+                None => colors[i] = Some(ERROR),
+            },
+            "'" | "`" | "," | ",@" => colors[i] = Some(QUOTE),
+            text => {
+                let is_command = match opens.last_mut() {
+                    Some(list) => {
+                        if list.len == 0 {
+                            list.head = text.to_string();
+                        }
+                        list.len += 1;
+                        list.len == 1 && list.kind == ListKind::Call && prev == "("
+                    }
+                    None => {
+                        top_len += 1;
+                        top_len == 1
+                    }
+                };
+                colors[i] = match symbol_color(text) {
+                    Some(color) => Some(color),
+                    None if is_command => Some(command_color(environment, text)),
+                    None => None,
+                };
+            }
+        }
+    }
+    for open in opens {
+        colors[open.token] = Some(ERROR);
+    }
+    colors
+}
+
+fn highlight_syntax(environment: &Environment, line: &str) -> String {
+    if let Some(exp) = get_expression(environment, "*syntax-highlight*") {
+        if let Expression::Atom(Atom::Nil) = *exp {
+            return line.to_string();
+        }
+    }
+    let (tokens, open_string) = tokenize_spans(line);
+    let mut colors = token_colors(environment, &tokens);
+    if open_string {
+        if let Some(last) = colors.last_mut() {
+            *last = Some(ERROR);
+        }
+    }
+    let mut starts = vec![None; line.chars().count() + 1];
+    let mut ends = vec![false; line.chars().count() + 1];
+    for (token, color) in tokens.iter().zip(colors.iter()) {
+        if let Some(color) = color {
+            starts[token.start] = Some(*color);
+            ends[token.end] = true;
+        }
+    }
+    let mut in_token = vec![false; line.chars().count()];
+    for token in &tokens {
+        for covered in &mut in_token[token.start..token.end] {
+            *covered = true;
+        }
+    }
+    let mut res = String::with_capacity(line.len() * 2);
+    let mut in_comment = false;
+    for (i, ch) in line.chars().enumerate() {
+        if ends[i] {
+            res.push_str(RESET);
+        }
+        if let Some(color) = starts[i] {
+            res.push_str(color);
+        }
+        if ch == ';' && !in_token[i] && !in_comment {
+            in_comment = true;
+            res.push_str(COMMENT);
+        } else if ch == '\n' && in_comment {
+            in_comment = false;
+            res.push_str(RESET);
+        }
+        res.push(ch);
+    }
+    if in_comment || ends[ends.len() - 1] {
+        res.push_str(RESET);
+    }
+    res
+}
+
+// The line with syntax colors followed by the dimmed suggestion (if any).
+pub fn highlight(environment: &Environment, line: &str, suggestion: Option<&str>) -> String {
+    let mut res = highlight_syntax(environment, line);
+    if let Some(suggestion) = suggestion {
+        res.push_str(SUGGESTION);
+        res.push_str(suggestion);
+        res.push_str(RESET);
+    }
+    res
+}
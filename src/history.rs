@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use termion::event::Key;
+
+// History with when, where and how each command ran, kept next to the line
+// editor's history in ~/.local/share/slsh/history_meta.  One entry per line:
+// time, duration (ms), status, cwd and the command separated by tabs.
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub command: String,
+    // Seconds since the epoch when the command was started.
+    pub time: u64,
+    pub cwd: String,
+    pub status: i64,
+    pub duration_ms: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RichHistory {
+    file: Option<PathBuf>,
+    pub entries: Vec<HistoryEntry>,
+    // The line editor's history when the shell started (oldest first), used
+    // for suggestions along with entries.
+    pub editor_history: Vec<String>,
+}
+
+const MAX_HISTORY_ENTRIES: usize = 10_000;
+
+fn clean_field(field: &str) -> String {
+    field.replace(&['\t', '\n', '\r'][..], " ")
+}
+
+impl HistoryEntry {
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            self.time,
+            self.duration_ms,
+            self.status,
+            clean_field(&self.cwd),
+            clean_field(&self.command)
+        )
+    }
+
+    fn from_line(line: &str) -> Option<HistoryEntry> {
+        let mut parts = line.splitn(5, '\t');
+        Some(HistoryEntry {
+            time: parts.next()?.parse().ok()?,
+            duration_ms: parts.next()?.parse().ok()?,
+            status: parts.next()?.parse().ok()?,
+            cwd: parts.next()?.to_string(),
+            command: parts.next()?.to_string(),
+        })
+    }
+}
+
+impl RichHistory {
+    pub fn load(&mut self, file: PathBuf) {
+        if let Ok(contents) = fs::read_to_string(&file) {
+            self.entries = contents
+                .lines()
+                .filter_map(HistoryEntry::from_line)
+                .collect();
+        }
+        self.file = Some(file);
+        if self.entries.len() > MAX_HISTORY_ENTRIES {
+            let extra = self.entries.len() - MAX_HISTORY_ENTRIES;
+            self.entries.drain(..extra);
+            if let Err(err) = self.save() {
+                eprintln!("WARNING: Unable to save history metadata: {}", err);
+            }
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            let contents: String = self.entries.iter().map(HistoryEntry::to_line).collect();
+            fs::write(file, contents)?;
+        }
+        Ok(())
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        if let Some(file) = &self.file {
+            let res = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .and_then(|mut f| f.write_all(entry.to_line().as_bytes()));
+            if let Err(err) = res {
+                eprintln!("WARNING: Unable to save history metadata: {}", err);
+            }
+        }
+        self.entries.push(entry);
+    }
+
+    // Newest first entries containing text (each command once), optionally
+    // only the ones run in cwd or that succeeded.
+    pub fn search(&self, text: &str, cwd: Option<&str>, success: bool) -> Vec<&HistoryEntry> {
+        let mut seen = HashSet::new();
+        self.entries
+            .iter()
+            .rev()
+            .filter(|e| e.command.contains(text))
+            .filter(|e| cwd.is_none() || cwd == Some(e.cwd.as_str()))
+            .filter(|e| !success || e.status == 0)
+            .filter(|e| seen.insert(e.command.as_str()))
+            .collect()
+    }
+}
+
+// The rest of the newest command starting with line for an inline suggestion,
+// commands run in cwd are preferred.
+pub fn suggestion(history: &RichHistory, line: &str, cwd: &str) -> Option<String> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    let is_match = |command: &str| command.len() > line.len() && command.starts_with(line);
+    let command = history
+        .entries
+        .iter()
+        .rev()
+        .find(|e| e.cwd == cwd && is_match(&e.command))
+        .or_else(|| history.entries.iter().rev().find(|e| is_match(&e.command)))
+        .map(|e| e.command.as_str())
+        .or_else(|| {
+            history
+                .editor_history
+                .iter()
+                .rev()
+                .find(|c| is_match(c))
+                .map(|c| c.as_str())
+        })?;
+    Some(command[line.len()..].to_string())
+}
+
+// Incremental reverse search (ctrl-r) run from the line editor, returns the
+// chosen command or None if cancelled.  While searching ctrl-r moves to the
+// next older match, ctrl-d toggles only commands run in the current directory
+// and ctrl-s only successful ones.  keys are the keys typed (from the line
+// editor's key reader).
+pub fn reverse_search(
+    history: &RichHistory,
+    cwd: &str,
+    keys: &mut dyn Iterator<Item = Key>,
+) -> Option<String> {
+    let mut query = String::new();
+    let mut index = 0;
+    let mut here = false;
+    let mut success = false;
+    loop {
+        let matches = history.search(&query, if here { Some(cwd) } else { None }, success);
+        if index >= matches.len() && index > 0 {
+            index = matches.len().saturating_sub(1);
+        }
+        let found = matches.get(index).map(|e| e.command.clone());
+        let mut filters = String::new();
+        if here {
+            filters.push_str(" here");
+        }
+        if success {
+            filters.push_str(" ok");
+        }
+        let mut out = io::stdout();
+        let _ = write!(
+            out,
+            "\r\x1b[K(reverse-i-search{})`{}': {}",
+            filters,
+            query,
+            found.as_ref().map_or("", |f| f.as_str())
+        );
+        let _ = out.flush();
+        let key = keys.next()?;
+        match key {
+            Key::Char('\n') | Key::Char('\r') => {
+                let _ = write!(out, "\r\x1b[K");
+                return found;
+            }
+            Key::Esc | Key::Ctrl('g') | Key::Ctrl('c') => {
+                let _ = write!(out, "\r\x1b[K");
+                return None;
+            }
+            Key::Ctrl('r') => index += 1,
+            Key::Ctrl('d') => {
+                here = !here;
+                index = 0;
+            }
+            Key::Ctrl('s') => {
+                success = !success;
+                index = 0;
+            }
+            Key::Backspace => {
+                query.pop();
+                index = 0;
+            }
+            Key::Char(ch) => {
+                query.push(ch);
+                index = 0;
+            }
+            _ => {}
+        }
+    }
+}
@@ -2,6 +2,8 @@ extern crate glob;
 extern crate libc;
 extern crate liner;
 extern crate nix;
+extern crate regex;
+extern crate termion;
 
 pub mod types;
 pub use crate::types::*;
@@ -39,5 +41,44 @@ pub use crate::builtins_util::*;
 pub mod builtins_file;
 pub use crate::builtins_file::*;
 
+pub mod builtins_match;
+pub use crate::builtins_match::*;
+
+pub mod builtins_complete;
+pub use crate::builtins_complete::*;
+
+pub mod builtins_doc;
+pub use crate::builtins_doc::*;
+
+pub mod builtins_path;
+pub use crate::builtins_path::*;
+
+pub mod path_hash;
+pub use crate::path_hash::*;
+
+pub mod builtins_editor;
+pub use crate::builtins_editor::*;
+
+pub mod history;
+pub use crate::history::*;
+
+pub mod line_editor;
+pub use crate::line_editor::*;
+
+pub mod highlight;
+pub use crate::highlight::*;
+
+pub mod async_segments;
+pub use crate::async_segments::*;
+
+pub mod globbing;
+pub use crate::globbing::*;
+
+pub mod snapshot;
+pub use crate::snapshot::*;
+
+pub mod spawn;
+pub use crate::spawn::*;
+
 pub mod process;
 pub use crate::process::*;
@@ -0,0 +1,469 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::io::{self, Stdout, Write};
+use std::iter;
+use std::rc::Rc;
+
+use liner::{ColorClosure, Completer, Context, Editor, Emacs, KeyBindings, KeyMap, Vi};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd;
+use termion::event::{parse_event, Event, Key};
+use termion::raw::{IntoRawMode, RawTerminal};
+
+use crate::environment::*;
+use crate::history::*;
+use crate::shell::*;
+use crate::types::*;
+
+// Line editor settings, changed from lisp with set-keymap, bind-key and
+// set-history and applied before each line is read.
+#[derive(Clone, Debug)]
+pub struct EditorConfig {
+    pub emacs: bool,
+    // Max history entries kept in memory and in the history file.
+    pub history_size: Option<usize>,
+    pub history_file_size: Option<usize>,
+    // Do not save or load duplicate history entries.
+    pub history_dedupe: bool,
+    // Share history with other running shells.
+    pub history_share: bool,
+    // Lisp functions called with the buffer and cursor by key name.
+    pub bindings: HashMap<String, Expression>,
+}
+
+impl Default for EditorConfig {
+    fn default() -> Self {
+        EditorConfig {
+            emacs: false,
+            history_size: None,
+            history_file_size: None,
+            history_dedupe: true,
+            history_share: true,
+            bindings: HashMap::new(),
+        }
+    }
+}
+
+pub fn apply_editor_config(con: &mut Context, config: &EditorConfig) {
+    con.key_bindings = if config.emacs {
+        KeyBindings::Emacs
+    } else {
+        KeyBindings::Vi
+    };
+    con.history.append_duplicate_entries = !config.history_dedupe;
+    con.history.load_duplicates = !config.history_dedupe;
+    con.history.share = config.history_share;
+    if let Some(size) = config.history_size {
+        con.history.set_max_buffers_size(size);
+    }
+    if let Some(size) = config.history_file_size {
+        con.history.set_max_file_size(size);
+    }
+}
+
+// Check and normalize a key name: a character, C-x (control), M-x (alt) or F1-F12.
+pub fn parse_key_name(name: &str) -> Option<String> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next(), chars.next()) {
+        (Some(ch), None, _, _) => Some(ch.to_string()),
+        (Some('C'), Some('-'), Some(ch), None) => Some(format!("C-{}", ch.to_ascii_lowercase())),
+        (Some('M'), Some('-'), Some(ch), None) => Some(format!("M-{}", ch)),
+        (Some('F'), _, _, _) => match name[1..].parse::<u8>() {
+            Ok(n) if (1..=12).contains(&n) => Some(format!("F{}", n)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn key_name(key: Key) -> Option<String> {
+    match key {
+        Key::Char(ch) => Some(ch.to_string()),
+        Key::Ctrl(ch) => Some(format!("C-{}", ch.to_ascii_lowercase())),
+        Key::Alt(ch) => Some(format!("M-{}", ch)),
+        Key::F(n) => Some(format!("F{}", n)),
+        _ => None,
+    }
+}
+
+// Keys read straight from the terminal (not through stdin's buffer) so the
+// line editor can poll for them.
+pub struct KeyReader {
+    bytes: VecDeque<u8>,
+}
+
+impl KeyReader {
+    pub fn new() -> KeyReader {
+        KeyReader {
+            bytes: VecDeque::new(),
+        }
+    }
+
+    // Read what is waiting on stdin, false at end of file.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 1024];
+        loop {
+            match unistd::read(0, &mut buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.bytes.extend(&buf[..n]);
+                    return Ok(true);
+                }
+                Err(nix::Error::Sys(Errno::EINTR)) => {}
+                Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+            }
+        }
+    }
+}
+
+impl Default for KeyReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iterator for KeyReader {
+    type Item = Key;
+
+    // The next key typed, None once stdin is closed.
+    fn next(&mut self) -> Option<Key> {
+        loop {
+            while let Some(byte) = self.bytes.pop_front() {
+                let bytes = &mut self.bytes;
+                let mut rest = iter::from_fn(|| bytes.pop_front().map(Ok));
+                if let Ok(Event::Key(key)) = parse_event(byte, &mut rest) {
+                    return Some(key);
+                }
+            }
+            match self.fill() {
+                Ok(true) => {}
+                _ => return None,
+            }
+        }
+    }
+}
+
+// How often to check on running async segments while waiting for a key (a
+// segment that timed out is killed when checked).
+const SEGMENT_POLL_MS: i32 = 1000;
+
+// A prompt and the highlighter for the line after it.
+pub type LinePrompt = (String, ColorClosure);
+
+enum Edit {
+    Line(String),
+    // Draw the prompt again, with the buffer and cursor to start from.
+    Redraw(String, usize),
+}
+
+// Read a line with liner's editor but our own key loop, keys the shell handles
+// (see run_key_binding) never reach liner so its binding for the same key does
+// not run as well.  make_prompt gives the prompt and highlighter, it is called
+// again to draw the line under listed completions and (with redraw set) when
+// an async segment finishes.  prompt is left as the one last drawn.
+pub fn read_line<C: Completer>(
+    con: &mut Context,
+    environment: &Rc<RefCell<Environment>>,
+    prompt: &mut String,
+    make_prompt: &dyn Fn() -> LinePrompt,
+    redraw: bool,
+    handler: &mut C,
+) -> io::Result<String> {
+    let (new_prompt, mut color) = make_prompt();
+    *prompt = new_prompt;
+    let mut out = io::stdout().into_raw_mode()?;
+    let mut keys = KeyReader::new();
+    let mut line = (String::new(), 0);
+    loop {
+        let emacs = matches!(con.key_bindings, KeyBindings::Emacs);
+        let mut editor = Editor::new(&mut out, prompt.clone(), Some(color), con)?;
+        if !line.0.is_empty() {
+            set_buffer(&mut editor, &line.0, Some(line.1 as i64));
+        }
+        let edit = if emacs {
+            edit_line(
+                Emacs::new(),
+                editor,
+                &mut keys,
+                environment,
+                prompt,
+                redraw,
+                handler,
+            )
+        } else {
+            edit_line(
+                Vi::new(),
+                editor,
+                &mut keys,
+                environment,
+                prompt,
+                redraw,
+                handler,
+            )
+        };
+        match edit? {
+            Edit::Redraw(buffer, cursor) => {
+                let (new_prompt, new_color) = make_prompt();
+                *prompt = new_prompt;
+                color = new_color;
+                line = (buffer, cursor);
+            }
+            Edit::Line(buffer) => return Ok(buffer),
+        }
+    }
+}
+
+fn edit_line<M: KeyMap, C: Completer>(
+    mut keymap: M,
+    mut editor: Editor<'_, &mut RawTerminal<Stdout>>,
+    keys: &mut KeyReader,
+    environment: &Rc<RefCell<Environment>>,
+    prompt: &str,
+    redraw: bool,
+    handler: &mut C,
+) -> io::Result<Edit> {
+    keymap.init(&mut editor);
+    loop {
+        if redraw && !wait_key(keys, environment) {
+            let segments = environment.borrow().async_segments.clone();
+            let changed = segments.borrow_mut().update();
+            if changed {
+                let cursor = editor.cursor();
+                let buffer = erase_line(&mut editor, prompt)?;
+                return Ok(Edit::Redraw(buffer, cursor));
+            }
+            continue;
+        }
+        let key = match keys.next() {
+            Some(key) => key,
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stdin closed")),
+        };
+        if run_key_binding(environment, &mut editor, key, keys) {
+            continue;
+        }
+        if keymap.handle_key(key, &mut editor, handler)? {
+            return Ok(Edit::Line(editor.into()));
+        }
+        let list = std::mem::take(&mut environment.borrow_mut().completion_list);
+        if !list.is_empty() {
+            let cursor = editor.cursor();
+            let buffer = list_under_line(&mut editor, &list)?;
+            return Ok(Edit::Redraw(buffer, cursor));
+        }
+    }
+}
+
+// Wait for a key, false if a running async segment has output first (or
+// SEGMENT_POLL_MS passed).
+fn wait_key(keys: &KeyReader, environment: &Rc<RefCell<Environment>>) -> bool {
+    if !keys.bytes.is_empty() {
+        return true;
+    }
+    let job_fds = environment.borrow().async_segments.borrow().job_fds();
+    if job_fds.is_empty() {
+        return true;
+    }
+    let mut fds = vec![PollFd::new(0, PollFlags::POLLIN)];
+    fds.extend(job_fds.iter().map(|fd| PollFd::new(*fd, PollFlags::POLLIN)));
+    match poll(&mut fds, SEGMENT_POLL_MS) {
+        Ok(_) => fds[0].revents().map_or(false, |events| !events.is_empty()),
+        Err(_err) => false,
+    }
+}
+
+// Clear the prompt and line from the screen and return the line, the editor
+// is done after this.
+fn erase_line<W: Write>(editor: &mut Editor<'_, W>, prompt: &str) -> io::Result<String> {
+    let len = editor.current_buffer().num_chars();
+    let buffer = editor.current_buffer().range(0, len);
+    editor.move_cursor_to(len)?;
+    let mut out = io::stdout();
+    let rows = prompt_rows(prompt, &buffer);
+    if rows > 1 {
+        write!(out, "\x1b[{}A", rows - 1)?;
+    }
+    write!(out, "\r\x1b[J")?;
+    out.flush()?;
+    Ok(buffer)
+}
+
+// Write lines under the line being edited and return the line, the editor is
+// done after this (the line is drawn again under the list).
+fn list_under_line<W: Write>(editor: &mut Editor<'_, W>, lines: &[String]) -> io::Result<String> {
+    let len = editor.current_buffer().num_chars();
+    let buffer = editor.current_buffer().range(0, len);
+    editor.move_cursor_to(len)?;
+    let mut out = io::stdout();
+    write!(out, "\r\n")?;
+    for line in lines {
+        write!(out, "{}\r\n", line)?;
+    }
+    out.flush()?;
+    Ok(buffer)
+}
+
+// Call the lisp function bound to key (if any) with the buffer and cursor.
+// It returns nil to leave the buffer alone, a new buffer (cursor at the end)
+// or a list of the new buffer and cursor position.  Keys that are not bound
+// get the shell's defaults (history search and accepting suggestions).
+// Returns true if the key was handled here and should not go to liner.
+pub fn run_key_binding<W: Write>(
+    environment: &Rc<RefCell<Environment>>,
+    editor: &mut Editor<'_, W>,
+    key: Key,
+    keys: &mut KeyReader,
+) -> bool {
+    let binding = key_name(key).and_then(|name| {
+        let binding = environment
+            .borrow()
+            .editor_config
+            .bindings
+            .get(&name)?
+            .clone();
+        Some((name, binding))
+    });
+    let (name, binding) = match binding {
+        Some(binding) => binding,
+        None => {
+            match key {
+                Key::Ctrl('r') => {
+                    search_history(environment, editor, keys);
+                    return true;
+                }
+                // Otherwise they move the cursor as usual.
+                Key::Right | Key::Ctrl('f') => {
+                    return accept_suggestion(environment, editor, false)
+                }
+                Key::Alt('f') => return accept_suggestion(environment, editor, true),
+                _ => {}
+            }
+            return false;
+        }
+    };
+    let buffer = editor
+        .current_buffer()
+        .range(0, editor.current_buffer().num_chars());
+    let call = Expression::List(vec![
+        Expression::List(vec![
+            Expression::Atom(Atom::Symbol("quote".to_string())),
+            binding,
+        ]),
+        Expression::Atom(Atom::String(buffer)),
+        Expression::Atom(Atom::Int(editor.cursor() as i64)),
+    ]);
+    let res = eval(&mut environment.borrow_mut(), &call);
+    let (buffer, cursor) = match res {
+        Ok(Expression::Atom(Atom::Nil)) => return true,
+        Ok(Expression::Atom(Atom::String(buffer))) => (buffer, None),
+        Ok(Expression::List(list)) => match &list[..] {
+            [Expression::Atom(Atom::String(buffer)), Expression::Atom(Atom::Int(cursor))] => {
+                (buffer.clone(), Some(*cursor))
+            }
+            _ => {
+                eprintln!(
+                    "WARNING: key binding {} should return (buffer cursor)",
+                    name
+                );
+                return true;
+            }
+        },
+        Ok(exp) => {
+            eprintln!(
+                "WARNING: unexpected result from key binding {}, {}",
+                name,
+                exp.to_string()
+            );
+            return true;
+        }
+        Err(err) => {
+            eprintln!("ERROR calling key binding {}: {}", name, err);
+            return true;
+        }
+    };
+    set_buffer(editor, &buffer, cursor);
+    true
+}
+
+// Replace the editor's buffer, cursor None (or out of range) is the end.
+fn set_buffer<W: Write>(editor: &mut Editor<'_, W>, buffer: &str, cursor: Option<i64>) {
+    let chars: Vec<char> = buffer.chars().collect();
+    let cursor = match cursor {
+        Some(cursor) if cursor >= 0 => (cursor as usize).min(chars.len()),
+        _ => chars.len(),
+    };
+    let buf = editor.current_buffer_mut();
+    let len = buf.num_chars();
+    buf.remove(0, len);
+    buf.insert(0, &chars);
+    if let Err(err) = editor.move_cursor_to(cursor) {
+        eprintln!("ERROR moving cursor: {}", err);
+    }
+}
+
+fn current_dir_string() -> String {
+    match env::current_dir() {
+        Ok(cwd) => cwd.to_string_lossy().to_string(),
+        Err(_err) => String::new(),
+    }
+}
+
+// The suggestion shown after line, None if they are off (*autosuggest* nil).
+pub fn line_suggestion(environment: &Environment, line: &str) -> Option<String> {
+    if let Some(exp) = get_expression(environment, "*autosuggest*") {
+        if let Expression::Atom(Atom::Nil) = *exp {
+            return None;
+        }
+    }
+    suggestion(&environment.history.borrow(), line, &current_dir_string())
+}
+
+// With the cursor at the end of the line add the suggestion (or its next word)
+// to the buffer, false if there was nothing to accept.
+fn accept_suggestion<W: Write>(
+    environment: &Rc<RefCell<Environment>>,
+    editor: &mut Editor<'_, W>,
+    one_word: bool,
+) -> bool {
+    let len = editor.current_buffer().num_chars();
+    if editor.cursor() != len {
+        return false;
+    }
+    let line = editor.current_buffer().range(0, len);
+    let rest = match line_suggestion(&environment.borrow(), &line) {
+        Some(rest) if !rest.is_empty() => rest,
+        _ => return false,
+    };
+    let rest = if one_word {
+        let word_start = rest.len() - rest.trim_start().len();
+        match rest[word_start..].find(char::is_whitespace) {
+            Some(end) => &rest[..word_start + end],
+            None => &rest[..],
+        }
+    } else {
+        &rest[..]
+    };
+    set_buffer(editor, &format!("{}{}", line, rest), None);
+    true
+}
+
+// Default ctrl-r, search the history and put the chosen command in the buffer.
+fn search_history<W: Write>(
+    environment: &Rc<RefCell<Environment>>,
+    editor: &mut Editor<'_, W>,
+    keys: &mut KeyReader,
+) {
+    let history = environment.borrow().history.clone();
+    let found = reverse_search(&history.borrow(), &current_dir_string(), keys);
+    match found {
+        Some(command) => set_buffer(editor, &command, None),
+        None => {
+            // The search used the line, draw it again.
+            let cursor = editor.cursor();
+            if let Err(err) = editor.move_cursor_to(cursor) {
+                eprintln!("ERROR moving cursor: {}", err);
+            }
+        }
+    }
+}
@@ -51,10 +51,10 @@ fn main() -> io::Result<()> {
                     eprintln!("{}", msg);
                     return Err(io::Error::new(io::ErrorKind::Other, msg));
                 }
-                start_interactive();
+                start_interactive(&config);
             } else {
                 // No tty, just read stdin and do something with it..
-                read_stdin();
+                read_stdin(&config);
             }
         } else if config.command.is_some() {
             let command = config.command.unwrap();
@@ -62,9 +62,8 @@ fn main() -> io::Result<()> {
                 eprintln!("Error running {}: {}", command, err);
                 return Err(err);
             }
-        } else if config.script.is_some() {
-            let script = config.script.unwrap();
-            if let Err(err) = run_one_script(&script, &config.args) {
+        } else if let Some(script) = &config.script {
+            if let Err(err) = run_one_script(script, &config.args, &config) {
                 eprintln!("Error running {}: {}", script, err);
                 return Err(err);
             }
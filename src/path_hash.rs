@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// Executables found in $PATH by name (the first directory in PATH wins).
+// Rebuilt when PATH changes or one of its directories is modified so
+// completion and command lookup do not have to rescan PATH every time.
+#[derive(Clone, Debug, Default)]
+pub struct PathHash {
+    path: Option<OsString>,
+    dirs: Vec<(PathBuf, Option<SystemTime>)>,
+    exes: HashMap<String, PathBuf>,
+}
+
+fn dir_mtime(dir: &Path) -> Option<SystemTime> {
+    fs::metadata(dir).ok()?.modified().ok()
+}
+
+pub fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(md) => md.is_file() && md.permissions().mode() & 0o111 != 0,
+        Err(_err) => false,
+    }
+}
+
+impl PathHash {
+    fn is_stale(&self) -> bool {
+        if env::var_os("PATH") != self.path {
+            return true;
+        }
+        self.dirs
+            .iter()
+            .any(|(dir, mtime)| dir_mtime(dir) != *mtime)
+    }
+
+    fn rebuild(&mut self) {
+        self.path = env::var_os("PATH");
+        self.dirs.clear();
+        self.exes.clear();
+        let dirs: Vec<PathBuf> = match &self.path {
+            Some(paths) => env::split_paths(paths).collect(),
+            None => Vec::new(),
+        };
+        for dir in dirs {
+            self.dirs.push((dir.clone(), dir_mtime(&dir)));
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_err) => continue,
+            };
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if self.exes.contains_key(&name) {
+                    continue;
+                }
+                let path = entry.path();
+                if is_executable(&path) {
+                    self.exes.insert(name, path);
+                }
+            }
+        }
+    }
+
+    fn refresh(&mut self) {
+        if self.is_stale() {
+            self.rebuild();
+        }
+    }
+
+    // Drop the table, it is rebuilt on the next lookup.
+    pub fn clear(&mut self) {
+        self.path = None;
+        self.dirs.clear();
+        self.exes.clear();
+    }
+
+    // The path for command, names with a / are checked as is.
+    pub fn lookup(&mut self, command: &str) -> Option<PathBuf> {
+        if command.contains('/') {
+            let path = PathBuf::from(command);
+            return if is_executable(&path) {
+                Some(path)
+            } else {
+                None
+            };
+        }
+        self.refresh();
+        self.exes.get(command).cloned()
+    }
+
+    pub fn names(&mut self) -> Vec<String> {
+        self.refresh();
+        self.exes.keys().cloned().collect()
+    }
+}
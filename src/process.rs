@@ -1,13 +1,16 @@
 use std::env;
 use std::fs::File;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::process::CommandExt;
 use std::process::{ChildStdin, ChildStdout, Command, Stdio};
 use std::rc::Rc;
+use std::time::Instant;
 
-use glob::glob;
 //use nix::sys::signal::{self, SigHandler, Signal};
 use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    poll::{poll, PollFd, PollFlags},
     sys::{
         signal::{self, SigHandler, Signal},
         termios,
@@ -18,6 +21,9 @@ use nix::{
 
 use crate::builtins_util::*;
 use crate::environment::*;
+use crate::globbing::*;
+use crate::shell::*;
+use crate::spawn::*;
 use crate::types::*;
 
 pub fn try_wait_pid(environment: &Environment, pid: u32) -> (bool, Option<i32>) {
@@ -40,6 +46,10 @@ pub fn try_wait_pid(environment: &Environment, pid: u32) -> (bool, Option<i32>)
             environment.procs.borrow_mut().remove(&pid);
             (true, Some(status))
         }
+        Ok(WaitStatus::Signaled(_, signal, _)) => {
+            environment.procs.borrow_mut().remove(&pid);
+            (true, Some(128 + signal as i32))
+        }
         Ok(WaitStatus::Stopped(..)) => {
             environment.stopped_procs.borrow_mut().push(pid);
             (true, None)
@@ -54,21 +64,13 @@ pub fn wait_pid(
     pid: u32,
     term_settings: Option<&termios::Termios>,
 ) -> Option<i32> {
-    let result: Option<i32>;
-    loop {
-        let (stop, status) = try_wait_pid(environment, pid);
-        if stop {
-            result = status;
-            if status.is_some() && environment.save_exit_status {
-                env::set_var("LAST_STATUS".to_string(), format!("{}", status.unwrap()));
-                environment.root_scope.borrow_mut().data.insert(
-                    "*last-status*".to_string(),
-                    Rc::new(Expression::Atom(Atom::Int(i64::from(status.unwrap())))),
-                );
-            }
-            break;
-        }
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    let result = wait_status(environment, pid);
+    if let Some(status) = result {
+        set_last_status(
+            environment,
+            status,
+            environment.state.started.get(&pid).copied(),
+        );
     }
     // If we were given terminal settings restore them.
     if let Some(settings) = term_settings {
@@ -88,6 +90,167 @@ pub fn wait_pid(
     result
 }
 
+fn wait_status(environment: &Environment, pid: u32) -> Option<i32> {
+    loop {
+        let (stop, status) = try_wait_pid(environment, pid);
+        if stop {
+            return status;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+// Remember the stages of a pipeline (results of its forms) for wait, pipelines
+// that are done are dropped once there is a new one.
+pub fn add_pipeline(environment: &mut Environment, pid: u32, stages: Vec<Expression>) {
+    environment.state.pipelines.retain(|_, stages| {
+        stages
+            .iter()
+            .any(|stage| matches!(stage, Expression::Process(ProcessState::Running(_))))
+    });
+    environment.state.pipelines.insert(pid, stages);
+}
+
+// Wait for every process of a pipeline, set *pipe-status* to the stage statuses
+// (0 for lisp forms, nil if unknown) and return the pipeline's status (the last
+// stage's or with *pipefail* set the last one that failed) and that list.
+pub fn wait_pipeline(
+    environment: &mut Environment,
+    stages: &mut [Expression],
+) -> (Option<i32>, Expression) {
+    let mut statuses = Vec::with_capacity(stages.len());
+    for stage in stages.iter_mut() {
+        let status = match stage {
+            Expression::Process(ProcessState::Running(pid)) => {
+                let pid = *pid;
+                // A spawned task that was already reaped is not waited on again.
+                let status = match spawned_exit(environment, pid) {
+                    Some(status) => status,
+                    None => wait_status(environment, pid),
+                };
+                environment.spawned.borrow_mut().remove(&pid);
+                if let Some(status) = status {
+                    *stage = Expression::Process(ProcessState::Over(pid, status));
+                }
+                status
+            }
+            Expression::Process(ProcessState::Over(_pid, status)) => Some(*status),
+            _ => Some(0),
+        };
+        statuses.push(status);
+    }
+    let pipefail = match get_expression(environment, "*pipefail*") {
+        Some(exp) => !matches!(*exp, Expression::Atom(Atom::Nil)),
+        None => false,
+    };
+    let last = statuses.last().copied().flatten();
+    let status = if pipefail {
+        statuses
+            .iter()
+            .rev()
+            .find(|status| matches!(status, Some(status) if *status != 0))
+            .copied()
+            .flatten()
+            .or(last)
+    } else {
+        last
+    };
+    let list = Expression::List(
+        statuses
+            .iter()
+            .map(|status| match status {
+                Some(status) => Expression::Atom(Atom::Int(i64::from(*status))),
+                None => Expression::Atom(Atom::Nil),
+            })
+            .collect(),
+    );
+    if environment.save_exit_status {
+        environment
+            .root_scope
+            .borrow_mut()
+            .data
+            .insert("*pipe-status*".to_string(), Rc::new(list.clone()));
+    }
+    if let Some(status) = status {
+        // From the first stage that started.
+        let started = stages
+            .iter()
+            .filter_map(|stage| match stage {
+                Expression::Process(ProcessState::Over(pid, _)) => {
+                    environment.state.started.get(pid)
+                }
+                _ => None,
+            })
+            .min()
+            .copied();
+        set_last_status(environment, status, started);
+    }
+    (status, list)
+}
+
+// Set *last-status* and *last-duration* (ms since started) for a command.
+pub fn set_last_status(environment: &Environment, status: i32, started: Option<Instant>) {
+    if environment.save_exit_status {
+        env::set_var("LAST_STATUS", format!("{}", status));
+        let duration = started.map_or(0, |started| started.elapsed().as_millis() as i64);
+        let mut root_scope = environment.root_scope.borrow_mut();
+        root_scope.data.insert(
+            "*last-status*".to_string(),
+            Rc::new(Expression::Atom(Atom::Int(i64::from(status)))),
+        );
+        root_scope.data.insert(
+            "*last-duration*".to_string(),
+            Rc::new(Expression::Atom(Atom::Int(duration))),
+        );
+    }
+}
+
+// Run the command-not-found hooks with the command and its args, then call
+// __command_not_found with them if it is defined, otherwise report it (unless
+// there were hooks) and set the status to 127.  In a pipe this is an error so
+// the next stage does not end up reading the terminal.
+fn command_not_found(
+    environment: &mut Environment,
+    command: &str,
+    args: &[String],
+) -> io::Result<Expression> {
+    let has_hooks = matches!(
+        environment.hooks.get("command-not-found"),
+        Some(hooks) if !hooks.is_empty()
+    );
+    if has_hooks {
+        let mut hook_args = Vec::with_capacity(1 + args.len());
+        hook_args.push(Expression::Atom(Atom::String(command.to_string())));
+        for a in args {
+            hook_args.push(Expression::Atom(Atom::String(a.clone())));
+        }
+        run_hooks(environment, "command-not-found", &hook_args);
+    }
+    let hook = get_expression(environment, "__command_not_found");
+    if let Some(hook) = hook {
+        if let Expression::Atom(Atom::Lambda(_)) = *hook {
+            let mut v = Vec::with_capacity(2 + args.len());
+            v.push(Expression::Atom(Atom::Symbol(
+                "__command_not_found".to_string(),
+            )));
+            v.push(Expression::Atom(Atom::String(command.to_string())));
+            for a in args {
+                v.push(Expression::Atom(Atom::String(a.clone())));
+            }
+            return eval(environment, &Expression::List(v));
+        }
+    }
+    set_last_status(environment, 127, None);
+    let msg = format!("{}: command not found", command);
+    if environment.data_in.is_some() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+    }
+    if !has_hooks {
+        eprintln!("{}", msg);
+    }
+    Ok(Expression::Atom(Atom::Nil))
+}
+
 fn run_command(
     environment: &mut Environment,
     command: &str,
@@ -101,7 +264,13 @@ fn run_command(
     for a in args {
         new_args.push(a.make_string(environment)?);
     }
-    let mut com_obj = Command::new(command);
+    let exe = environment.path_hash.borrow_mut().lookup(command);
+    let exe = match exe {
+        Some(exe) => exe,
+        None => return command_not_found(environment, command, &new_args),
+    };
+    let mut com_obj = Command::new(exe);
+    com_obj.arg0(command);
     let foreground =
         !environment.in_pipe && !environment.run_background && !environment.state.is_spawn;
     let shell_terminal = nix::libc::STDIN_FILENO;
@@ -111,6 +280,8 @@ fn run_command(
         .stdout(stdout)
         .stderr(stderr);
     let pgid = environment.state.pipe_pgid;
+    let subst_fds = environment.state.subst_fds.clone();
+    let (redirects, _redirect_files) = fd_redirects(environment)?;
 
     unsafe {
         com_obj.pre_exec(move || -> io::Result<()> {
@@ -141,6 +312,27 @@ fn run_command(
             signal::signal(Signal::SIGTTOU, SigHandler::SigDfl).unwrap();
             signal::signal(Signal::SIGCHLD, SigHandler::SigDfl).unwrap();
 
+            // Let the command open the process substitutions in its arguments.
+            for fd in &subst_fds {
+                let _ = fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()));
+            }
+            for (fd, source) in &redirects {
+                match source {
+                    // dup2 does nothing if they are the same, still clear close on exec.
+                    Some(source) if source == fd => {
+                        fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    }
+                    Some(source) => {
+                        unistd::dup2(*source, *fd)
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    }
+                    None => {
+                        let _ = unistd::close(*fd);
+                    }
+                }
+            }
+
             Ok(())
         });
     }
@@ -150,6 +342,7 @@ fn run_command(
     } else {
         None
     };
+    let started = Instant::now();
     let proc = com_obj.spawn();
 
     let mut result = Expression::Atom(Atom::Nil);
@@ -172,6 +365,12 @@ fn run_command(
                 }
             }
             let pid = proc.id();
+            let procs = environment.procs.clone();
+            let state = &mut environment.state;
+            state
+                .started
+                .retain(|pid, _| procs.borrow().contains_key(pid));
+            state.started.insert(pid, started);
             result = if foreground && !environment.in_pipe {
                 if let Err(_err) = unistd::tcsetpgrp(shell_terminal, pgid) {
                     // Ignore, do in parent and child.
@@ -201,6 +400,48 @@ fn run_command(
     Ok(result)
 }
 
+// Files for fd redirections are opened at or above this fd so they are not
+// clobbered by the redirections applied before them.
+const REDIRECT_FD_MIN: i32 = 100;
+
+// (fd, fd to copy to it or None to close it) in the order to apply them.
+type Redirects = Vec<(i32, Option<i32>)>;
+
+// The fd redirections for a command, files are opened here and kept open
+// until the command has started.
+fn fd_redirects(environment: &Environment) -> io::Result<(Redirects, Vec<File>)> {
+    let mut redirects = Vec::with_capacity(environment.state.fd_redirects.len());
+    let mut files = Vec::new();
+    for (fd, redirect) in &environment.state.fd_redirects {
+        let file = match redirect {
+            FdRedirect::FileAppend(f) => std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(f)
+                .map_err(|e| redirect_error(f, e))?,
+            FdRedirect::FileRead(f) => File::open(f).map_err(|e| redirect_error(f, e))?,
+            FdRedirect::Dup(source) => {
+                redirects.push((*fd, Some(*source)));
+                continue;
+            }
+            FdRedirect::Close => {
+                redirects.push((*fd, None));
+                continue;
+            }
+        };
+        let source = fcntl(file.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(REDIRECT_FD_MIN))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        files.push(unsafe { File::from_raw_fd(source) });
+        redirects.push((*fd, Some(source)));
+    }
+    Ok((redirects, files))
+}
+
+// Name the file a redirection could not open, the io error alone doesn't.
+fn redirect_error(file: &str, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", file, err))
+}
+
 fn get_output(
     environment: &Environment,
     out_status: &Option<IOState>,
@@ -229,6 +470,7 @@ fn get_output(
         Some(IOState::Null) => Stdio::null(),
         Some(IOState::Inherit) => Stdio::inherit(),
         Some(IOState::Pipe) => Stdio::piped(),
+        Some(IOState::Fd(fd)) => fd_stdio(*fd)?,
         None => {
             let use_stdout = environment.state.eval_level < 3 && !environment.in_pipe;
             if use_stdout {
@@ -263,6 +505,7 @@ fn get_output(
         Some(IOState::Null) => Stdio::null(),
         Some(IOState::Inherit) => Stdio::inherit(),
         Some(IOState::Pipe) => Stdio::piped(),
+        Some(IOState::Fd(fd)) => fd_stdio(*fd)?,
         None => {
             let use_stdout = environment.state.eval_level < 3 && !environment.in_pipe;
             if use_stdout {
@@ -275,39 +518,30 @@ fn get_output(
     Ok((out_res, err_res))
 }
 
-pub fn prep_string_arg(s: &str, nargs: &mut Vec<Expression>) -> io::Result<()> {
-    let s = match expand_tilde(&s) {
-        Some(p) => p,
-        None => s.to_string(), // XXX not great.
-    };
-    if s.contains('*') || s.contains('?') || s.contains('[') || s.contains('{') {
-        match glob(&s) {
-            Ok(paths) => {
-                let mut i = 0;
-                for p in paths {
-                    match p {
-                        Ok(p) => {
-                            i += 1;
-                            if let Some(p) = p.to_str() {
-                                nargs.push(Expression::Atom(Atom::String(p.to_string())));
-                            }
-                        }
-                        Err(err) => {
-                            let msg = format!("glob error on while iterating {}, {}", s, err);
-                            return Err(io::Error::new(io::ErrorKind::Other, msg));
-                        }
-                    }
-                }
-                if i == 0 {
-                    nargs.push(Expression::Atom(Atom::String(s)));
-                }
-            }
-            Err(_err) => {
-                nargs.push(Expression::Atom(Atom::String(s)));
-            }
-        }
+// Brace expand, tilde expand and glob a string argument for a command, only
+// loose symbols (not quoted strings) are brace expanded.
+pub fn prep_string_arg(
+    environment: &Environment,
+    s: &str,
+    loose: bool,
+    nargs: &mut Vec<Expression>,
+) -> io::Result<()> {
+    let (extended, no_match) = glob_settings(environment)?;
+    let words = if loose {
+        expand_braces(s)
     } else {
-        nargs.push(Expression::Atom(Atom::String(s)));
+        vec![s.to_string()]
+    };
+    let mut args = Vec::new();
+    for word in words {
+        let word = match expand_tilde(&word) {
+            Some(p) => p,
+            None => word,
+        };
+        glob_arg(&word, extended, no_match, &mut args)?;
+    }
+    for arg in args {
+        nargs.push(Expression::Atom(Atom::String(arg)));
     }
     Ok(())
 }
@@ -339,6 +573,8 @@ pub fn do_command(
                 } else {
                     Stdio::null()
                 }
+            } else if let Some(out) = take_spawned_stdout(environment, *pid) {
+                Stdio::from(out)
             } else if foreground {
                 Stdio::inherit()
             } else {
@@ -363,38 +599,286 @@ pub fn do_command(
                 "Invalid expression state before command (form).",
             ))
         }
-        None => {
-            if foreground {
-                Stdio::inherit()
-            } else {
-                Stdio::null()
-            }
-        }
+        None => match environment.state.stdin_fd {
+            Some(fd) => fd_stdio(fd)?,
+            None if foreground => Stdio::inherit(),
+            None => Stdio::null(),
+        },
     };
     let (stdout, stderr) = get_output(
         environment,
         &environment.state.stdout_status,
         &environment.state.stderr_status,
     )?;
+    // Process substitutions made for this command's arguments go away once
+    // it has started, it has its own copies.
+    let subst_start = environment.state.subst_fds.len();
+    let res = command_args(environment, parts).and_then(|mut nargs| {
+        run_command(
+            environment,
+            command,
+            &mut nargs,
+            stdin,
+            stdout,
+            stderr,
+            data,
+        )
+    });
+    close_subst_fds(environment, subst_start);
+    res
+}
+
+fn command_args(
+    environment: &mut Environment,
+    parts: &[Expression],
+) -> io::Result<Vec<Expression>> {
     let old_loose_syms = environment.loose_symbols;
     environment.loose_symbols = true;
     let mut args = to_args(environment, parts)?;
     environment.loose_symbols = old_loose_syms;
     let mut nargs: Vec<Expression> = Vec::with_capacity(args.len());
-    for arg in args.drain(..) {
+    for (part, arg) in parts.iter().zip(args.drain(..)) {
         if let Expression::Atom(Atom::String(s)) = arg {
-            prep_string_arg(&s, &mut nargs)?;
+            let loose = matches!(part, Expression::Atom(Atom::Symbol(sym)) if *sym == s);
+            prep_string_arg(environment, &s, loose, &mut nargs)?;
         } else {
             nargs.push(arg.clone());
         }
     }
-    run_command(
-        environment,
-        command,
-        &mut nargs,
-        stdin,
-        stdout,
-        stderr,
-        data,
-    )
+    Ok(nargs)
+}
+
+// A close on exec copy of fd for a command to use as stdin or stdout.
+fn fd_stdio(fd: i32) -> io::Result<Stdio> {
+    let fd = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    Ok(unsafe { Stdio::from_raw_fd(fd) })
+}
+
+// Close the shell's ends of the process substitutions made since start.
+pub fn close_subst_fds(environment: &mut Environment, start: usize) {
+    if start < environment.state.subst_fds.len() {
+        for fd in environment.state.subst_fds.drain(start..) {
+            let _ = unistd::close(fd);
+        }
+    }
+}
+
+// Start form (a command) in the background with its stdout (or stdin when
+// to_command is true) attached to a pipe and return /dev/fd/N for the other
+// end, for psub< and psub>.  The shell keeps that end open until the
+// command it is an argument for has started.
+pub fn process_substitution(
+    environment: &mut Environment,
+    form: &Expression,
+    to_command: bool,
+) -> io::Result<Expression> {
+    let (read_fd, write_fd) =
+        unistd::pipe2(OFlag::O_CLOEXEC).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let (command_fd, shell_fd) = if to_command {
+        (read_fd, write_fd)
+    } else {
+        (write_fd, read_fd)
+    };
+    let old_out = environment.state.stdout_status.clone();
+    let old_in = environment.state.stdin_fd;
+    let old_background = environment.run_background;
+    let old_in_pipe = environment.in_pipe;
+    let old_data_in = environment.data_in.take();
+    // Don't let form's commands hold on to the enclosing command's pipes.
+    let subst_fds = std::mem::take(&mut environment.state.subst_fds);
+    if to_command {
+        environment.state.stdin_fd = Some(command_fd);
+        environment.state.stdout_status = Some(IOState::Inherit);
+    } else {
+        environment.state.stdout_status = Some(IOState::Fd(command_fd));
+    }
+    environment.run_background = true;
+    environment.in_pipe = false;
+    let res = eval(environment, form);
+    environment.run_background = old_background;
+    environment.in_pipe = old_in_pipe;
+    environment.data_in = old_data_in;
+    environment.state.stdout_status = old_out;
+    environment.state.stdin_fd = old_in;
+    close_subst_fds(environment, 0);
+    environment.state.subst_fds = subst_fds;
+    let _ = unistd::close(command_fd);
+    match res {
+        Ok(_) => {
+            environment.state.subst_fds.push(shell_fd);
+            Ok(Expression::Atom(Atom::String(format!(
+                "/dev/fd/{}",
+                shell_fd
+            ))))
+        }
+        Err(err) => {
+            let _ = unistd::close(shell_fd);
+            Err(err)
+        }
+    }
+}
+
+// How long pmap waits for output before checking its jobs again.
+const PARALLEL_POLL_MS: i32 = 100;
+
+// A call of pmap's lambda, its output is read as it comes so a command that
+// writes a lot does not block.
+struct ParallelJob {
+    index: usize,
+    pid: Option<u32>,
+    stdout: Option<ChildStdout>,
+    output: Vec<u8>,
+    // *last-status* after the lambda when it did not return a command.
+    status: Option<i32>,
+}
+
+impl ParallelJob {
+    // Read what is available, true once its stdout is closed.
+    fn read(&mut self) -> bool {
+        let stdout = match &mut self.stdout {
+            Some(stdout) => stdout,
+            None => return true,
+        };
+        let mut chunk = [0; 4096];
+        loop {
+            match stdout.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => self.output.extend_from_slice(&chunk[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // Nothing more for now.
+                Err(_err) => return false,
+            }
+        }
+        self.stdout = None;
+        true
+    }
+}
+
+// Call lambda with item like run-bg, with the command's stdout on a pipe.
+fn start_parallel_job(
+    environment: &mut Environment,
+    lambda: &Expression,
+    item: Expression,
+    index: usize,
+) -> io::Result<ParallelJob> {
+    let quote = |exp| {
+        Expression::List(vec![
+            Expression::Atom(Atom::Symbol("quote".to_string())),
+            exp,
+        ])
+    };
+    let call = Expression::List(vec![quote(lambda.clone()), quote(item)]);
+    let old_background = environment.run_background;
+    let old_out = environment.state.stdout_status.clone();
+    environment.run_background = true;
+    environment.state.stdout_status = Some(IOState::Pipe);
+    let res = eval(environment, &call);
+    environment.run_background = old_background;
+    environment.state.stdout_status = old_out;
+    let mut job = ParallelJob {
+        index,
+        pid: None,
+        stdout: None,
+        output: Vec::new(),
+        status: None,
+    };
+    match res? {
+        Expression::Process(ProcessState::Running(pid)) => {
+            job.pid = Some(pid);
+            job.stdout = environment
+                .procs
+                .borrow_mut()
+                .get_mut(&pid)
+                .and_then(|child| child.stdout.take());
+            if let Some(stdout) = &job.stdout {
+                fcntl(stdout.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            }
+        }
+        // Not a command, its value is the output.
+        value => {
+            job.output = value.make_string(environment)?.into_bytes();
+            job.status = match get_expression(environment, "*last-status*") {
+                Some(exp) => match *exp {
+                    Expression::Atom(Atom::Int(status)) => Some(status as i32),
+                    _ => None,
+                },
+                None => None,
+            };
+        }
+    }
+    Ok(job)
+}
+
+// Kill and wait for the running jobs when pmap fails part way.
+fn stop_parallel_jobs(environment: &Environment, running: Vec<ParallelJob>) {
+    for job in running {
+        if let Some(pid) = job.pid {
+            let _ = signal::kill(Pid::from_raw(pid as i32), Signal::SIGTERM);
+            wait_status(environment, pid);
+        }
+    }
+}
+
+// Call lambda (which runs an external command) on each item with at most jobs
+// commands running at once, returns (output status) for each item in order.
+pub fn parallel_map(
+    environment: &mut Environment,
+    lambda: &Expression,
+    items: Vec<Expression>,
+    jobs: usize,
+) -> io::Result<Vec<Expression>> {
+    let mut results = vec![Expression::Atom(Atom::Nil); items.len()];
+    let mut items = items.into_iter().enumerate();
+    let mut running: Vec<ParallelJob> = Vec::with_capacity(jobs);
+    loop {
+        while running.len() < jobs {
+            match items.next() {
+                Some((index, item)) => match start_parallel_job(environment, lambda, item, index) {
+                    Ok(job) => running.push(job),
+                    Err(err) => {
+                        stop_parallel_jobs(environment, running);
+                        return Err(err);
+                    }
+                },
+                None => break,
+            }
+        }
+        if running.is_empty() {
+            return Ok(results);
+        }
+        let mut fds: Vec<PollFd> = running
+            .iter()
+            .filter_map(|job| job.stdout.as_ref())
+            .map(|stdout| PollFd::new(stdout.as_raw_fd(), PollFlags::POLLIN))
+            .collect();
+        if !fds.is_empty() {
+            if let Err(err) = poll(&mut fds, PARALLEL_POLL_MS) {
+                if err.as_errno() != Some(nix::errno::Errno::EINTR) {
+                    stop_parallel_jobs(environment, running);
+                    return Err(io::Error::new(io::ErrorKind::Other, err));
+                }
+            }
+        }
+        let mut i = 0;
+        while i < running.len() {
+            if !running[i].read() {
+                i += 1;
+                continue;
+            }
+            let job = running.remove(i);
+            let status = match job.pid {
+                Some(pid) => wait_pid(environment, pid, None),
+                None => job.status,
+            };
+            let status = match status {
+                Some(status) => Expression::Atom(Atom::Int(i64::from(status))),
+                None => Expression::Atom(Atom::Nil),
+            };
+            let output = String::from_utf8_lossy(&job.output).to_string();
+            results[job.index] =
+                Expression::List(vec![Expression::Atom(Atom::String(output)), status]);
+        }
+    }
 }
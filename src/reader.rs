@@ -6,15 +6,35 @@ fn is_whitespace(ch: char) -> bool {
     match ch {
         ' ' => true,
         '\t' => true,
+        '\n' => true,
+        '\r' => true,
         _ => false,
     }
 }
 
+// A token and the range of chars in the source text it was read from.
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Token {
+    fn new(text: &str, start: usize, end: usize) -> Token {
+        Token {
+            text: text.to_string(),
+            start,
+            end,
+        }
+    }
+}
+
 macro_rules! save_token {
-    ($tokens:expr, $token:expr) => {{
+    ($tokens:expr, $token:expr, $start:expr, $end:expr) => {{
         let t_token = $token.trim();
         if !t_token.is_empty() {
-            $tokens.push(t_token.to_string());
+            $tokens.push(Token::new(t_token, $start, $end));
             $token = String::new();
         }
     }};
@@ -94,29 +114,30 @@ fn do_in_string(
 }
 
 fn handle_char(
-    tokens: &mut Vec<String>,
+    tokens: &mut Vec<Token>,
     mut token: String,
     ch: char,
     last_ch: char,
     last_comma: &mut bool,
+    (token_start, idx): (usize, usize),
 ) -> String {
     if ch == '(' {
-        save_token!(tokens, token);
-        tokens.push("(".to_string());
+        save_token!(tokens, token, token_start, idx);
+        tokens.push(Token::new("(", idx, idx + 1));
     } else if ch == ')' {
-        save_token!(tokens, token);
-        tokens.push(")".to_string());
+        save_token!(tokens, token, token_start, idx);
+        tokens.push(Token::new(")", idx, idx + 1));
     } else if ch == '\'' && (last_ch == ' ' || last_ch == '(' || last_ch == '\'' || last_ch == '`')
     {
-        save_token!(tokens, token);
-        tokens.push("'".to_string());
+        save_token!(tokens, token, token_start, idx);
+        tokens.push(Token::new("'", idx, idx + 1));
     } else if ch == '`' && (last_ch == ' ' || last_ch == '(' || last_ch == '\'' || last_ch == '`') {
-        save_token!(tokens, token);
-        tokens.push("`".to_string());
+        save_token!(tokens, token, token_start, idx);
+        tokens.push(Token::new("`", idx, idx + 1));
     } else if ch == ',' && (last_ch == ' ' || last_ch == '(') {
         *last_comma = true;
     } else if is_whitespace(ch) {
-        save_token!(tokens, token);
+        save_token!(tokens, token, token_start, idx);
     } else if ch == '\\' && last_ch != '\\' {
         // Do nothing...
     } else {
@@ -125,8 +146,11 @@ fn handle_char(
     token
 }
 
-fn tokenize(text: &str) -> Vec<String> {
-    let mut tokens: Vec<String> = Vec::new();
+// Split text into tokens, each with the chars of text it covers (used by the
+// reader and to highlight the line being edited), also returns true if text
+// ended inside a string.
+pub fn tokenize_spans(text: &str) -> (Vec<Token>, bool) {
+    let mut tokens: Vec<Token> = Vec::new();
     let mut in_string = false;
     let mut token = String::new();
     let mut last_ch = ' ';
@@ -138,18 +162,22 @@ fn tokenize(text: &str) -> Vec<String> {
         // Work with shebanged scripts.
         in_comment = true;
     }
-    for ch in text.chars() {
+    let mut token_start = 0;
+    for (idx, ch) in text.chars().enumerate() {
         if last_comma {
             last_comma = false;
-            save_token!(tokens, token);
+            save_token!(tokens, token, token_start, idx - 1);
             if ch == '@' {
-                tokens.push(",@".to_string());
+                tokens.push(Token::new(",@", idx - 1, idx + 1));
                 last_ch = ch;
                 continue;
             } else {
-                tokens.push(",".to_string());
+                tokens.push(Token::new(",", idx - 1, idx));
             }
         }
+        if token.is_empty() {
+            token_start = idx;
+        }
         if in_comment {
             if ch == '\n' {
                 in_comment = false;
@@ -167,7 +195,7 @@ fn tokenize(text: &str) -> Vec<String> {
             in_string = !in_string;
             token.push(ch);
             if !in_string {
-                tokens.push(token);
+                tokens.push(Token::new(&token, token_start, idx + 1));
                 token = String::new();
             } else {
                 in_escape_code = false;
@@ -190,15 +218,22 @@ fn tokenize(text: &str) -> Vec<String> {
                 in_comment = true;
                 continue;
             }
-            token = handle_char(&mut tokens, token, ch, last_ch, &mut last_comma);
+            token = handle_char(
+                &mut tokens,
+                token,
+                ch,
+                last_ch,
+                &mut last_comma,
+                (token_start, idx),
+            );
             last_ch = ch;
         }
     }
     let token = token.trim();
     if !token.is_empty() {
-        tokens.push(token.to_string());
+        tokens.push(Token::new(token, token_start, text.chars().count()));
     }
-    tokens
+    (tokens, in_string)
 }
 
 fn parse_atom(token: &str) -> Expression {
@@ -234,6 +269,7 @@ fn close_list(level: i32, stack: &mut Vec<Vec<Expression>>) -> Result<(), ParseE
     if level < 0 {
         return Err(ParseError {
             reason: "Unexpected `)`".to_string(),
+            incomplete: false,
         });
     }
     if level > 0 {
@@ -250,6 +286,7 @@ fn close_list(level: i32, stack: &mut Vec<Vec<Expression>>) -> Result<(), ParseE
             None => {
                 return Err(ParseError {
                     reason: "Unexpected `)`".to_string(),
+                    incomplete: false,
                 });
             }
         }
@@ -257,15 +294,17 @@ fn close_list(level: i32, stack: &mut Vec<Vec<Expression>>) -> Result<(), ParseE
     Ok(())
 }
 
-fn parse(tokens: &[String]) -> Result<Expression, ParseError> {
+fn parse(tokens: &[Token]) -> Result<Expression, ParseError> {
     if tokens.is_empty() {
         return Err(ParseError {
             reason: "No tokens".to_string(),
+            incomplete: false,
         });
     }
-    if tokens[0] != "(" && tokens[0] != "'" && tokens[0] != "`" {
+    if tokens[0].text != "(" && tokens[0].text != "'" && tokens[0].text != "`" {
         return Err(ParseError {
             reason: "Not a list".to_string(),
+            incomplete: false,
         });
     }
     let mut stack: Vec<Vec<Expression>> = Vec::new();
@@ -273,7 +312,7 @@ fn parse(tokens: &[String]) -> Result<Expression, ParseError> {
     let mut qexits: Vec<i32> = Vec::new();
     let mut backtick_level = 0;
     for token in tokens {
-        match &token[..] {
+        match &token.text[..] {
             "'" => {
                 level += 1;
                 qexits.push(level);
@@ -315,7 +354,7 @@ fn parse(tokens: &[String]) -> Result<Expression, ParseError> {
             }
             _ => match stack.pop() {
                 Some(mut v) => {
-                    v.push(parse_atom(token));
+                    v.push(parse_atom(&token.text));
                     stack.push(v);
                     if let Some(quote_exit_level) = qexits.pop() {
                         if level == quote_exit_level {
@@ -332,6 +371,7 @@ fn parse(tokens: &[String]) -> Result<Expression, ParseError> {
                 None => {
                     return Err(ParseError {
                         reason: "Found symbol without containing list".to_string(),
+                        incomplete: false,
                     });
                 }
             },
@@ -349,6 +389,7 @@ fn parse(tokens: &[String]) -> Result<Expression, ParseError> {
     if level != 0 {
         return Err(ParseError {
             reason: "Unclosed list(s)".to_string(),
+            incomplete: true,
         });
     }
     if stack.len() > 1 {
@@ -362,12 +403,19 @@ fn parse(tokens: &[String]) -> Result<Expression, ParseError> {
             Some(v) => Ok(Expression::List(v)),
             None => Err(ParseError {
                 reason: "Empty results".to_string(),
+                incomplete: false,
             }),
         }
     }
 }
 
 pub fn read(text: &str) -> Result<Expression, ParseError> {
-    let tokens = tokenize(text);
+    let (tokens, in_string) = tokenize_spans(text);
+    if in_string {
+        return Err(ParseError {
+            reason: "Unclosed string".to_string(),
+            incomplete: true,
+        });
+    }
     parse(&tokens)
 }
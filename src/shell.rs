@@ -1,23 +1,31 @@
-use liner::Context;
+use liner::{ColorClosure, Context};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
-use std::ffi::CStr;
+use std::ffi::{CStr, OsString};
 use std::fs;
 use std::fs::create_dir_all;
-use std::io::{self, ErrorKind};
+use std::io::{self, ErrorKind, Write};
 use std::os::unix::process::CommandExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use nix::sys::signal::{self, SigHandler, Signal};
 use nix::unistd::gethostname;
 
+use crate::async_segments::*;
 use crate::builtins_util::*;
 use crate::completions::*;
+use crate::config::Config;
 use crate::environment::*;
+use crate::highlight::*;
+use crate::history::*;
+use crate::line_editor::*;
 use crate::process::*;
 use crate::reader::*;
+use crate::snapshot::*;
 use crate::types::*;
 
 fn call_lambda(
@@ -147,7 +155,7 @@ fn internal_eval(environment: &mut Environment, expression: &Expression) -> io::
                     let form = if environment.form_type == FormType::Any
                         || environment.form_type == FormType::FormOnly
                     {
-                        get_expression(environment, &command)
+                        get_form(environment, command)
                     } else {
                         None
                     };
@@ -226,55 +234,292 @@ pub fn eval(environment: &mut Environment, expression: &Expression) -> io::Resul
     result
 }
 
-fn load_scripts(environment: &mut Environment, home: &str) {
-    let mut script = format!("{}/.config/slsh/slsh_std.lisp", home);
-    if let Err(err) = run_script(&script, environment) {
-        eprintln!(
-            "WARNING: Failed to load standard macros script {}: {}",
-            script, err
-        );
+// The standard lisp libraries are compiled in, a file with the same name in
+// ~/.config/slsh will be used instead if it exists.
+const STD_SCRIPTS: [(&str, &str, &str); 2] = [
+    (
+        "slsh_std.lisp",
+        "standard macros",
+        include_str!("../lisp/slsh_std.lisp"),
+    ),
+    (
+        "slsh_shell.lisp",
+        "shell macros",
+        include_str!("../lisp/slsh_shell.lisp"),
+    ),
+];
+
+fn load_std_scripts(environment: &mut Environment, home: &str) {
+    for (name, desc, contents) in STD_SCRIPTS.iter() {
+        let script = format!("{}/.config/slsh/{}", home, name);
+        let res = if Path::new(&script).exists() {
+            run_script(&script, environment)
+        } else {
+            run_script_contents(contents, environment)
+        };
+        if let Err(err) = res {
+            eprintln!(
+                "WARNING: Failed to load {} script {}: {}",
+                desc, script, err
+            );
+        }
     }
-    script = format!("{}/.config/slsh/slsh_shell.lisp", home);
-    if let Err(err) = run_script(&script, environment) {
-        eprintln!(
-            "WARNING: Failed to load shell macros script {}: {}",
-            script, err
-        );
+}
+
+fn load_scripts_full(environment: &mut Environment, home: &str, no_std: bool) {
+    if !no_std {
+        load_std_scripts(environment, home);
     }
-    script = format!("{}/.config/slsh/slshrc", home);
+    let script = format!("{}/.config/slsh/slshrc", home);
     if let Err(err) = run_script(&script, environment) {
         eprintln!("WARNING: Failed to load init script {}: {}", script, err);
     }
 }
 
-fn get_prompt(environment: &mut Environment) -> String {
-    if environment
-        .root_scope
-        .borrow()
-        .data
-        .contains_key("__prompt")
-    {
-        let mut exp = environment
-            .root_scope
-            .borrow()
-            .data
-            .get("__prompt")
-            .unwrap()
-            .clone();
-        exp = match *exp {
-            Expression::Atom(Atom::Lambda(_)) => {
-                let mut v = Vec::with_capacity(1);
-                v.push(Expression::Atom(Atom::Symbol("__prompt".to_string())));
-                Rc::new(Expression::List(v))
-            }
-            _ => exp,
-        };
-        environment.save_exit_status = false; // Do not overwrite last exit status with prompt commands.
-        let res = eval(environment, &exp);
-        environment.save_exit_status = true;
+// Use the snapshot image if it is current, otherwise do a full load and save
+// a new image.  Scripts (rc false) do not run slshrc so get their own image.
+fn load_scripts_snapshot(environment: &mut Environment, home: &str, no_std: bool, rc: bool) {
+    let image = if rc {
+        format!("{}/.local/share/slsh/image", home)
+    } else {
+        format!("{}/.local/share/slsh/script-image", home)
+    };
+    let mut files: Vec<PathBuf> = Vec::new();
+    if !no_std {
+        for (name, _, _) in STD_SCRIPTS.iter() {
+            files.push(PathBuf::from(format!("{}/.config/slsh/{}", home, name)));
+        }
+    }
+    if rc {
+        files.push(PathBuf::from(format!("{}/.config/slsh/slshrc", home)));
+    }
+    match load_snapshot(environment, &image, no_std, &files) {
+        Ok(true) => return,
+        Ok(false) => {}
+        Err(err) => eprintln!("WARNING: Failed to load snapshot {}: {}", image, err),
+    }
+    let env_before: HashMap<OsString, OsString> = env::vars_os().collect();
+    environment.loaded_files.clear();
+    if rc {
+        load_scripts_full(environment, home, no_std);
+    } else {
+        load_std_scripts(environment, home);
+    }
+    files.append(&mut environment.loaded_files.clone());
+    if let Err(err) = save_snapshot(environment, &image, no_std, &files, &env_before) {
+        eprintln!("WARNING: Failed to save snapshot {}: {}", image, err);
+    }
+}
+
+fn load_scripts(environment: &mut Environment, home: &str, config: &Config) {
+    if config.snapshot {
+        load_scripts_snapshot(environment, home, config.no_std, true);
+    } else {
+        load_scripts_full(environment, home, config.no_std);
+    }
+}
+
+// Evaluate a prompt hook (call it if it is a lambda), None if it is not defined.
+fn prompt_hook(environment: &mut Environment, hook: &str) -> Option<String> {
+    let mut exp = environment.root_scope.borrow().data.get(hook)?.clone();
+    exp = match *exp {
+        Expression::Atom(Atom::Lambda(_)) => {
+            let v = vec![Expression::Atom(Atom::Symbol(hook.to_string()))];
+            Rc::new(Expression::List(v))
+        }
+        _ => exp,
+    };
+    environment.save_exit_status = false; // Do not overwrite last exit status with prompt commands.
+    let res = eval(environment, &exp);
+    environment.save_exit_status = true;
+    Some(
         res.unwrap_or_else(|e| Expression::Atom(Atom::String(format!("ERROR: {}", e).to_string())))
             .make_string(environment)
-            .unwrap_or_else(|_| "ERROR".to_string())
+            .unwrap_or_else(|_| "ERROR".to_string()),
+    )
+}
+
+// Hooks that can be added with add-hook: preexec gets the command before it
+// runs, precmd its status and duration after, chpwd the old and new directory
+// after cd, exit nothing and command-not-found the command and its args.
+pub const HOOK_NAMES: &[&str] = &["preexec", "precmd", "chpwd", "exit", "command-not-found"];
+
+// Call each function added to hook with args, like the prompt they do not
+// change the last exit status.
+pub fn run_hooks(environment: &mut Environment, hook: &str, args: &[Expression]) {
+    let hooks = match environment.hooks.get(hook) {
+        Some(hooks) if !hooks.is_empty() => hooks.clone(),
+        _ => return,
+    };
+    let save_exit_status = environment.save_exit_status;
+    environment.save_exit_status = false;
+    for lambda in hooks {
+        let mut call = Vec::with_capacity(args.len() + 1);
+        call.push(Expression::List(vec![
+            Expression::Atom(Atom::Symbol("quote".to_string())),
+            lambda,
+        ]));
+        call.extend_from_slice(args);
+        if let Err(err) = eval(environment, &Expression::List(call)) {
+            eprintln!("ERROR in {} hook: {}", hook, err);
+        }
+    }
+    environment.save_exit_status = save_exit_status;
+}
+
+// Prompt for the following lines of a form that is not complete yet.
+fn get_continuation_prompt(environment: &mut Environment) -> String {
+    match prompt_hook(environment, "__prompt_continuation") {
+        Some(prompt) => prompt,
+        None => "\x1b[32m...>\x1b[39m ".to_string(),
+    }
+}
+
+// Number of columns text takes on the terminal, escape sequences take none.
+fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\x1b' {
+            if chars.next() == Some('[') {
+                for ch in chars.by_ref() {
+                    if ch.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+        } else if !ch.is_control() {
+            width += 1;
+        }
+    }
+    width
+}
+
+fn terminal_width() -> usize {
+    match termion::terminal_size() {
+        Ok((cols, _rows)) if cols > 0 => cols as usize,
+        _ => 80,
+    }
+}
+
+// The right prompt (__rprompt) padded out to the right edge after a line of
+// used columns, None if it would not fit on the line.
+fn right_prompt(rprompt: &str, used: usize) -> Option<String> {
+    let width = display_width(rprompt);
+    let cols = terminal_width();
+    // Leave the last column free so the terminal does not wrap.
+    if width == 0 || used + width + 2 > cols {
+        return None;
+    }
+    Some(format!(
+        "{}{}\x1b[0m",
+        " ".repeat(cols - used - width - 1),
+        rprompt
+    ))
+}
+
+// Terminal rows taken by prompt followed by the line input.
+pub fn prompt_rows(prompt: &str, input: &str) -> usize {
+    let cols = terminal_width();
+    let mut lines: Vec<String> = prompt.split('\n').map(|l| l.to_string()).collect();
+    if let Some(last) = lines.last_mut() {
+        last.push_str(input);
+    }
+    lines
+        .iter()
+        .map(|l| (display_width(l).max(1) + cols - 1) / cols)
+        .sum()
+}
+
+// Replace the prompt and line just accepted with the __transient_prompt so
+// scrollback only keeps the short form.
+fn show_transient_prompt(environment: &mut Environment, prompt: &str, input: &str) {
+    let transient = match prompt_hook(environment, "__transient_prompt") {
+        Some(transient) => transient,
+        None => return,
+    };
+    print!(
+        "\x1b[{}A\r\x1b[J{}{}\n",
+        prompt_rows(prompt, input),
+        transient,
+        highlight(environment, input, None)
+    );
+    let _ = io::stdout().flush();
+}
+
+// Fold the lines of a multiline form into one line for history, newlines in
+// strings become \n escapes and comments are dropped.
+fn history_line(input: &str) -> String {
+    let mut res = String::with_capacity(input.len());
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut last_ch = ' ';
+    for ch in input.chars() {
+        if in_comment {
+            if ch == '\n' {
+                in_comment = false;
+                res.push(' ');
+            }
+            continue;
+        }
+        match ch {
+            '"' if last_ch != '\\' => in_string = !in_string,
+            ';' if !in_string => {
+                in_comment = true;
+                continue;
+            }
+            '\n' if in_string => {
+                res.push_str("\\n");
+                last_ch = ch;
+                continue;
+            }
+            '\n' => {
+                res.push(' ');
+                last_ch = ch;
+                continue;
+            }
+            _ => {}
+        }
+        res.push(ch);
+        last_ch = ch;
+    }
+    res.trim_end().to_string()
+}
+
+// Add a command that ran to the history with its cwd, status and duration,
+// returns the status and duration (ms).
+fn record_history(
+    environment: &Environment,
+    command: String,
+    cwd: &Path,
+    started: SystemTime,
+    failed: bool,
+) -> (i64, u64) {
+    let status = match get_expression(environment, "*last-status*") {
+        Some(exp) => match *exp {
+            Expression::Atom(Atom::Int(status)) => status,
+            _ => 0,
+        },
+        None => 0,
+    };
+    let status = if status == 0 && failed { 1 } else { status };
+    let entry = HistoryEntry {
+        command,
+        time: started
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        cwd: cwd.to_string_lossy().to_string(),
+        status,
+        duration_ms: started.elapsed().map_or(0, |d| d.as_millis() as u64),
+    };
+    let res = (entry.status, entry.duration_ms);
+    environment.history.borrow_mut().push(entry);
+    res
+}
+
+fn get_prompt(environment: &mut Environment) -> String {
+    if let Some(prompt) = prompt_hook(environment, "__prompt") {
+        prompt
     } else {
         // Nothing set, use a default.
         let hostname = match env::var("HOST") {
@@ -297,13 +542,44 @@ fn get_prompt(environment: &mut Environment) -> String {
     }
 }
 
-pub fn start_interactive() {
+// The prompt (or continuation prompt for the next line of a form) and the
+// highlighter for the line being edited after it.
+fn line_prompt(environment: &Rc<RefCell<Environment>>, first_line: bool) -> LinePrompt {
+    let prompt = if first_line {
+        get_prompt(&mut environment.borrow_mut())
+    } else {
+        get_continuation_prompt(&mut environment.borrow_mut())
+    };
+    let rprompt = if first_line {
+        prompt_hook(&mut environment.borrow_mut(), "__rprompt")
+    } else {
+        None
+    };
+    let prompt_width = display_width(prompt.rsplit('\n').next().unwrap_or(""));
+    let highlight_env = environment.clone();
+    let color: ColorClosure = Box::new(move |line: &str| match highlight_env.try_borrow() {
+        Ok(environment) => {
+            let suggestion = line_suggestion(&environment, line);
+            let mut res = highlight(&environment, line, suggestion.as_deref());
+            if let Some(rprompt) = &rprompt {
+                let used = prompt_width
+                    + display_width(line)
+                    + suggestion.as_deref().map_or(0, display_width);
+                if let Some(rprompt) = right_prompt(rprompt, used) {
+                    res.push_str(&rprompt);
+                }
+            }
+            res
+        }
+        Err(_err) => line.to_string(),
+    });
+    (prompt, color)
+}
+
+pub fn start_interactive(config: &Config) {
     let mut con = Context::new();
-    con.history.append_duplicate_entries = false;
     con.history.inc_append = true;
-    con.history.load_duplicates = false;
-    con.history.share = true;
-    con.key_bindings = liner::KeyBindings::Vi;
+    apply_editor_config(&mut con, &EditorConfig::default());
     // Initialize the HOST variable
     let mut hostname = [0_u8; 512];
     env::set_var(
@@ -334,7 +610,19 @@ pub fn start_interactive() {
         eprintln!("WARNING: Unable to load history: {}", err);
     }
     let environment = Rc::new(RefCell::new(build_default_environment()));
-    load_scripts(&mut environment.borrow_mut(), &home);
+    environment
+        .borrow()
+        .frecency
+        .borrow_mut()
+        .load(PathBuf::from(format!("{}/frecency", share_dir)));
+    environment
+        .borrow()
+        .history
+        .borrow_mut()
+        .load(PathBuf::from(format!("{}/history_meta", share_dir)));
+    environment.borrow().history.borrow_mut().editor_history =
+        con.history.buffers.iter().map(|b| b.to_string()).collect();
+    load_scripts(&mut environment.borrow_mut(), &home, config);
     environment
         .borrow_mut()
         .root_scope
@@ -344,16 +632,52 @@ pub fn start_interactive() {
             "*last-status*".to_string(),
             Rc::new(Expression::Atom(Atom::Int(0))),
         );
+    environment
+        .borrow_mut()
+        .root_scope
+        .borrow_mut()
+        .data
+        .insert(
+            "*last-duration*".to_string(),
+            Rc::new(Expression::Atom(Atom::Int(0))),
+        );
+    // Lines of a form that is not complete yet.
+    let mut pending = String::new();
     loop {
         environment.borrow_mut().state.stdout_status = None;
         environment.borrow_mut().state.stderr_status = None;
-        let prompt = get_prompt(&mut environment.borrow_mut());
-        if let Err(err) = reap_procs(&environment.borrow()) {
+        // Process substitutions not used by a command.
+        close_subst_fds(&mut environment.borrow_mut(), 0);
+        apply_editor_config(&mut con, &environment.borrow().editor_config);
+        let first_line = pending.is_empty();
+        if first_line {
+            refresh_segments(&mut environment.borrow_mut());
+        }
+        if let Err(err) = reap_procs(&mut environment.borrow_mut()) {
             eprintln!("Error reaping processes: {}", err);
         }
         let mut shell_completer = ShellCompleter::new(environment.clone());
-        match con.read_line(prompt, None, &mut shell_completer) {
+        let mut prompt = String::new();
+        let make_prompt = || line_prompt(&environment, first_line);
+        // The first line's prompt is drawn again when async segments finish.
+        match read_line(
+            &mut con,
+            &environment,
+            &mut prompt,
+            &make_prompt,
+            first_line,
+            &mut shell_completer,
+        ) {
             Ok(input) => {
+                if first_line {
+                    show_transient_prompt(&mut environment.borrow_mut(), &prompt, &input);
+                }
+                let input = if pending.is_empty() {
+                    input
+                } else {
+                    format!("{}\n{}", pending, input)
+                };
+                pending.clear();
                 if input.is_empty() {
                     continue;
                 }
@@ -365,6 +689,14 @@ pub fn start_interactive() {
                 } else {
                     format!("({})", input)
                 };
+                let ast = read(&mod_input);
+                if let Err(ParseError {
+                    incomplete: true, ..
+                }) = ast
+                {
+                    pending = input;
+                    continue;
+                }
                 // Clear the last status once something new is entered.
                 env::set_var("LAST_STATUS".to_string(), format!("{}", 0));
                 environment
@@ -376,14 +708,45 @@ pub fn start_interactive() {
                         "*last-status*".to_string(),
                         Rc::new(Expression::Atom(Atom::Int(i64::from(0)))),
                     );
-                let ast = read(&mod_input);
                 match ast {
                     Ok(ast) => {
                         environment.borrow_mut().loose_symbols = true;
+                        let cwd = env::current_dir().unwrap_or_default();
+                        run_hooks(
+                            &mut environment.borrow_mut(),
+                            "preexec",
+                            &[Expression::Atom(Atom::String(history_line(&input)))],
+                        );
+                        let started = SystemTime::now();
                         let res = eval(&mut environment.borrow_mut(), &ast);
+                        let (status, duration) = record_history(
+                            &environment.borrow(),
+                            history_line(&input),
+                            &cwd,
+                            started,
+                            res.is_err(),
+                        );
+                        environment.borrow().root_scope.borrow_mut().data.insert(
+                            "*last-duration*".to_string(),
+                            Rc::new(Expression::Atom(Atom::Int(duration as i64))),
+                        );
+                        run_hooks(
+                            &mut environment.borrow_mut(),
+                            "precmd",
+                            &[
+                                Expression::Atom(Atom::Int(status)),
+                                Expression::Atom(Atom::Int(duration as i64)),
+                            ],
+                        );
                         match res {
                             Ok(exp) => {
                                 if !input.is_empty() {
+                                    let input = history_line(&input);
+                                    environment
+                                        .borrow()
+                                        .frecency
+                                        .borrow_mut()
+                                        .record_line(&input);
                                     if let Err(err) = con.history.push(input.into()) {
                                         eprintln!("Error saving history: {}", err);
                                     }
@@ -406,15 +769,19 @@ pub fn start_interactive() {
                 }
             }
             Err(err) => match err.kind() {
-                ErrorKind::UnexpectedEof => return,
-                ErrorKind::Interrupted => {}
+                ErrorKind::UnexpectedEof if !pending.is_empty() => pending.clear(),
+                ErrorKind::UnexpectedEof => {
+                    run_hooks(&mut environment.borrow_mut(), "exit", &[]);
+                    return;
+                }
+                ErrorKind::Interrupted => pending.clear(),
                 _ => println!("Error on input: {}", err),
             },
         }
     }
 }
 
-pub fn read_stdin() {
+pub fn read_stdin(config: &Config) {
     let mut home = match env::var("HOME") {
         Ok(val) => val,
         Err(_) => ".".to_string(),
@@ -431,14 +798,20 @@ pub fn read_stdin() {
     }
     let mut environment = build_default_environment();
     environment.is_tty = false;
-    load_scripts(&mut environment, &home);
+    load_scripts(&mut environment, &home, config);
 
     let mut input = String::new();
     loop {
         match io::stdin().read_line(&mut input) {
-            Ok(0) => return,
+            Ok(0) => {
+                if !input.trim().is_empty() {
+                    eprintln!("ERROR: incomplete form at end of input: {}", input.trim());
+                }
+                return;
+            }
             Ok(_n) => {
                 environment.state.stdout_status = None;
+                close_subst_fds(&mut environment, 0);
                 let mod_input = if input.starts_with('(')
                     || input.starts_with('\'')
                     || input.starts_with('`')
@@ -448,6 +821,14 @@ pub fn read_stdin() {
                     format!("({})", input)
                 };
                 let ast = read(&mod_input);
+                if let Err(ParseError {
+                    incomplete: true, ..
+                }) = ast
+                {
+                    // Keep reading lines until the form is complete.
+                    continue;
+                }
+                input.clear();
                 match ast {
                     Ok(ast) => {
                         environment.loose_symbols = true;
@@ -558,7 +939,21 @@ pub fn run_one_command(command: &str, args: &[String]) -> io::Result<()> {
 
 fn run_script(file_name: &str, environment: &mut Environment) -> io::Result<()> {
     let contents = fs::read_to_string(file_name)?;
-    let ast = read(&contents);
+    run_script_contents(&contents, environment)
+}
+
+fn run_script_contents(contents: &str, environment: &mut Environment) -> io::Result<()> {
+    // A ns in the script only lasts until the end of the script.
+    let old_namespace = environment.current_namespace.clone();
+    let scope_depth = environment.current_scope.len();
+    let res = run_script_forms(contents, environment);
+    environment.current_scope.truncate(scope_depth);
+    environment.current_namespace = old_namespace;
+    res
+}
+
+fn run_script_forms(contents: &str, environment: &mut Environment) -> io::Result<()> {
+    let ast = read(contents);
     match ast {
         Ok(Expression::List(list)) => {
             for exp in list {
@@ -586,8 +981,22 @@ fn run_script(file_name: &str, environment: &mut Environment) -> io::Result<()>
     }
 }
 
-pub fn run_one_script(command: &str, args: &[String]) -> io::Result<()> {
+pub fn run_one_script(command: &str, args: &[String], config: &Config) -> io::Result<()> {
     let mut environment = build_default_environment();
+    if !config.no_std {
+        let mut home = match env::var("HOME") {
+            Ok(val) => val,
+            Err(_) => ".".to_string(),
+        };
+        if home.ends_with('/') {
+            home = home[..home.len() - 1].to_string();
+        }
+        if config.snapshot {
+            load_scripts_snapshot(&mut environment, &home, false, false);
+        } else {
+            load_std_scripts(&mut environment, &home);
+        }
+    }
     let mut exp_args: Vec<Expression> = Vec::with_capacity(args.len());
     for a in args {
         exp_args.push(Expression::Atom(Atom::String(a.clone())));
@@ -0,0 +1,854 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::async_segments::AsyncSegments;
+use crate::completions::{Candidate, CompletionSpec};
+use crate::config::VERSION_STRING;
+use crate::environment::*;
+use crate::line_editor::EditorConfig;
+use crate::types::*;
+
+// A snapshot image is the root scope (and everything reachable from it) after
+// the init scripts ran.  It is only used if the version, the std mode and the
+// mtimes of all the files that went into it match.  Environment variables the
+// scripts set are replayed only if they had the same value going in (so an
+// exported PATH built from $PATH is not restored from another PATH).
+
+const MAGIC: &[u8; 8] = b"SLSHIMG6";
+const NO_SCOPE: u32 = u32::MAX;
+
+const TAG_NIL: u8 = 0;
+const TAG_TRUE: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_SYMBOL: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_LAMBDA: u8 = 6;
+const TAG_MACRO: u8 = 7;
+const TAG_LIST: u8 = 8;
+const TAG_FUNC: u8 = 9;
+
+type FileKey = Vec<(PathBuf, Option<(u64, u32)>)>;
+
+fn file_mtime(path: &Path) -> Option<(u64, u32)> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    let since = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some((since.as_secs(), since.subsec_nanos()))
+}
+
+// The mtimes of files (None if missing) plus the slsh executable (it has the
+// standard libraries compiled in).
+fn make_key(files: &[PathBuf]) -> FileKey {
+    let mut key: FileKey = Vec::with_capacity(files.len() + 1);
+    if let Ok(exe) = env::current_exe() {
+        let mtime = file_mtime(&exe);
+        key.push((exe, mtime));
+    }
+    for file in files {
+        key.push((file.clone(), file_mtime(file)));
+    }
+    key
+}
+
+fn bad_image(msg: &str) -> io::Error {
+    let msg = format!("invalid snapshot image: {}", msg);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct ImageWriter<W: Write> {
+    out: W,
+    scope_ids: HashMap<usize, u32>,
+    scopes: Vec<Rc<RefCell<Scope>>>,
+}
+
+impl<W: Write> ImageWriter<W> {
+    fn add_scope(&mut self, scope: &Rc<RefCell<Scope>>) {
+        let ptr = scope.as_ptr() as usize;
+        if self.scope_ids.contains_key(&ptr) {
+            return;
+        }
+        self.scope_ids.insert(ptr, self.scopes.len() as u32);
+        self.scopes.push(scope.clone());
+        let outer = scope.borrow().outer.clone();
+        if let Some(outer) = outer {
+            self.add_scope(&outer);
+        }
+        let values: Vec<Rc<Expression>> = scope.borrow().data.values().cloned().collect();
+        for value in values {
+            self.add_expression_scopes(&value);
+        }
+    }
+
+    fn add_expression_scopes(&mut self, exp: &Expression) {
+        match exp {
+            Expression::Atom(Atom::Lambda(l)) => {
+                self.add_expression_scopes(&l.params);
+                self.add_expression_scopes(&l.body);
+                self.add_scope(&l.capture);
+            }
+            Expression::Atom(Atom::Macro(m)) => {
+                self.add_expression_scopes(&m.params);
+                self.add_expression_scopes(&m.body);
+            }
+            Expression::List(list) => {
+                for exp in list {
+                    self.add_expression_scopes(exp);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn scope_id(&self, scope: &Rc<RefCell<Scope>>) -> u32 {
+        match self.scope_ids.get(&(scope.as_ptr() as usize)) {
+            Some(id) => *id,
+            None => NO_SCOPE,
+        }
+    }
+
+    fn write_u8(&mut self, val: u8) -> io::Result<()> {
+        self.out.write_all(&[val])
+    }
+
+    fn write_u32(&mut self, val: u32) -> io::Result<()> {
+        self.out.write_all(&val.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, val: u64) -> io::Result<()> {
+        self.out.write_all(&val.to_le_bytes())
+    }
+
+    fn write_bytes(&mut self, val: &[u8]) -> io::Result<()> {
+        self.write_u32(val.len() as u32)?;
+        self.out.write_all(val)
+    }
+
+    fn write_str(&mut self, val: &str) -> io::Result<()> {
+        self.write_bytes(val.as_bytes())
+    }
+
+    fn write_size(&mut self, size: Option<usize>) -> io::Result<()> {
+        match size {
+            Some(size) => {
+                self.write_u8(1)?;
+                self.write_u64(size as u64)
+            }
+            None => self.write_u8(0),
+        }
+    }
+
+    fn write_os_str(&mut self, val: Option<&OsString>) -> io::Result<()> {
+        match val {
+            Some(val) => {
+                self.write_u8(1)?;
+                self.write_bytes(val.as_bytes())
+            }
+            None => self.write_u8(0),
+        }
+    }
+
+    fn write_doc(&mut self, doc: &Option<String>) -> io::Result<()> {
+        match doc {
+            Some(doc) => {
+                self.write_u8(1)?;
+                self.write_str(doc)
+            }
+            None => self.write_u8(0),
+        }
+    }
+
+    fn write_candidates(&mut self, candidates: &[Candidate]) -> io::Result<()> {
+        self.write_u32(candidates.len() as u32)?;
+        for candidate in candidates {
+            self.write_str(&candidate.name)?;
+            match &candidate.description {
+                Some(description) => {
+                    self.write_u8(1)?;
+                    self.write_str(description)?;
+                }
+                None => self.write_u8(0)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_expression(&mut self, exp: &Expression) -> io::Result<()> {
+        match exp {
+            Expression::Atom(Atom::Nil) => self.write_u8(TAG_NIL),
+            Expression::Atom(Atom::True) => self.write_u8(TAG_TRUE),
+            Expression::Atom(Atom::Float(f)) => {
+                self.write_u8(TAG_FLOAT)?;
+                self.write_u64(f.to_bits())
+            }
+            Expression::Atom(Atom::Int(i)) => {
+                self.write_u8(TAG_INT)?;
+                self.write_u64(*i as u64)
+            }
+            Expression::Atom(Atom::Symbol(s)) => {
+                self.write_u8(TAG_SYMBOL)?;
+                self.write_str(s)
+            }
+            Expression::Atom(Atom::String(s)) => {
+                self.write_u8(TAG_STRING)?;
+                self.write_str(s)
+            }
+            Expression::Atom(Atom::Lambda(l)) => {
+                self.write_u8(TAG_LAMBDA)?;
+                self.write_expression(&l.params)?;
+                self.write_expression(&l.body)?;
+                self.write_doc(&l.doc)?;
+                let id = self.scope_id(&l.capture);
+                self.write_u32(id)
+            }
+            Expression::Atom(Atom::Macro(m)) => {
+                self.write_u8(TAG_MACRO)?;
+                self.write_expression(&m.params)?;
+                self.write_expression(&m.body)?;
+                self.write_doc(&m.doc)
+            }
+            Expression::List(list) => {
+                self.write_u8(TAG_LIST)?;
+                self.write_u32(list.len() as u32)?;
+                for exp in list {
+                    self.write_expression(exp)?;
+                }
+                Ok(())
+            }
+            // Builtins are restored by name from a fresh scope.
+            Expression::Func(_) => self.write_u8(TAG_FUNC),
+            // A pid will mean nothing to the next shell.
+            Expression::Process(_) => self.write_u8(TAG_NIL),
+        }
+    }
+}
+
+// Save environment to image, written to a temp file that is renamed over image
+// so a shell starting at the same time never sees a partial image.
+pub fn save_snapshot(
+    environment: &Environment,
+    image: &str,
+    no_std: bool,
+    files: &[PathBuf],
+    env_before: &HashMap<OsString, OsString>,
+) -> io::Result<()> {
+    if let Some(dir) = Path::new(image).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_image = format!("{}.{}.tmp", image, process::id());
+    let res = write_snapshot(environment, &tmp_image, no_std, files, env_before)
+        .and_then(|_| fs::rename(&tmp_image, image));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_image);
+    }
+    res
+}
+
+fn write_snapshot(
+    environment: &Environment,
+    image: &str,
+    no_std: bool,
+    files: &[PathBuf],
+    env_before: &HashMap<OsString, OsString>,
+) -> io::Result<()> {
+    let mut writer = ImageWriter {
+        out: BufWriter::new(File::create(image)?),
+        scope_ids: HashMap::new(),
+        scopes: Vec::new(),
+    };
+    writer.add_scope(&environment.root_scope);
+    let mut ns_names: Vec<&String> = environment.namespaces.keys().collect();
+    ns_names.sort();
+    for name in &ns_names {
+        writer.add_scope(&environment.namespaces[*name].scope);
+    }
+    for spec in environment.completion_specs.values() {
+        if let Some(args) = &spec.args {
+            writer.add_expression_scopes(args);
+        }
+    }
+    for binding in environment.editor_config.bindings.values() {
+        writer.add_expression_scopes(binding);
+    }
+    for segment in environment.async_segments.borrow().segments.values() {
+        writer.add_expression_scopes(&segment.lambda);
+    }
+    for hook in environment.hooks.values().flatten() {
+        writer.add_expression_scopes(hook);
+    }
+
+    writer.out.write_all(MAGIC)?;
+    writer.write_str(VERSION_STRING)?;
+    writer.write_u8(no_std as u8)?;
+    let key = make_key(files);
+    writer.write_u32(key.len() as u32)?;
+    for (path, mtime) in &key {
+        writer.write_bytes(path.as_os_str().as_bytes())?;
+        match mtime {
+            Some((secs, nanos)) => {
+                writer.write_u8(1)?;
+                writer.write_u64(*secs)?;
+                writer.write_u32(*nanos)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+    }
+
+    writer.write_u32(environment.state.gensym_count)?;
+    // The init scripts environment variable changes (export etc).
+    let env_after: HashMap<OsString, OsString> = env::vars_os().collect();
+    let mut env_changes: Vec<(&OsString, Option<&OsString>, Option<&OsString>)> = Vec::new();
+    for (name, val) in &env_after {
+        let before = env_before.get(name);
+        if before != Some(val) {
+            env_changes.push((name, before, Some(val)));
+        }
+    }
+    for (name, before) in env_before {
+        if !env_after.contains_key(name) {
+            env_changes.push((name, Some(before), None));
+        }
+    }
+    writer.write_u32(env_changes.len() as u32)?;
+    for (name, before, val) in env_changes {
+        writer.write_bytes(name.as_bytes())?;
+        writer.write_os_str(before)?;
+        writer.write_os_str(val)?;
+    }
+
+    let scopes = writer.scopes.clone();
+    writer.write_u32(scopes.len() as u32)?;
+    for scope in &scopes {
+        let scope = scope.borrow();
+        let outer = match &scope.outer {
+            Some(outer) => writer.scope_id(outer),
+            None => NO_SCOPE,
+        };
+        writer.write_u32(outer)?;
+        writer.write_u32(scope.data.len() as u32)?;
+        for (key, val) in &scope.data {
+            writer.write_str(key)?;
+            writer.write_expression(val)?;
+        }
+    }
+
+    writer.write_u32(ns_names.len() as u32)?;
+    for name in ns_names {
+        let namespace = &environment.namespaces[name];
+        writer.write_str(name)?;
+        let id = writer.scope_id(&namespace.scope);
+        writer.write_u32(id)?;
+        writer.write_u32(namespace.exports.len() as u32)?;
+        for export in &namespace.exports {
+            writer.write_str(export)?;
+        }
+    }
+    writer.write_u32(environment.ns_imports.len() as u32)?;
+    for (importer, imports) in &environment.ns_imports {
+        writer.write_str(importer)?;
+        writer.write_u32(imports.len() as u32)?;
+        for (alias, name) in imports {
+            writer.write_str(alias)?;
+            writer.write_str(name)?;
+        }
+    }
+    writer.write_u32(environment.completion_specs.len() as u32)?;
+    for (command, spec) in &environment.completion_specs {
+        writer.write_str(command)?;
+        writer.write_candidates(&spec.subcommands)?;
+        writer.write_candidates(&spec.options)?;
+        writer.write_u8(spec.help_options as u8)?;
+        match &spec.args {
+            Some(args) => writer.write_expression(args)?,
+            None => writer.write_u8(TAG_NIL)?,
+        }
+    }
+    let config = &environment.editor_config;
+    writer.write_u8(config.emacs as u8)?;
+    writer.write_size(config.history_size)?;
+    writer.write_size(config.history_file_size)?;
+    writer.write_u8(config.history_dedupe as u8)?;
+    writer.write_u8(config.history_share as u8)?;
+    writer.write_u32(config.bindings.len() as u32)?;
+    for (key, binding) in &config.bindings {
+        writer.write_str(key)?;
+        writer.write_expression(binding)?;
+    }
+    let segments = environment.async_segments.borrow();
+    writer.write_u32(segments.segments.len() as u32)?;
+    for (name, segment) in &segments.segments {
+        writer.write_str(name)?;
+        writer.write_expression(&segment.lambda)?;
+        writer.write_size(segment.ttl.map(|ttl| ttl.as_secs() as usize))?;
+        writer.write_u64(segment.timeout.as_secs())?;
+    }
+    writer.write_u32(environment.hooks.len() as u32)?;
+    for (name, hooks) in &environment.hooks {
+        writer.write_str(name)?;
+        writer.write_u32(hooks.len() as u32)?;
+        for hook in hooks {
+            writer.write_expression(hook)?;
+        }
+    }
+    writer.out.flush()
+}
+
+struct ImageReader<R: Read> {
+    inp: R,
+    scopes: Vec<Rc<RefCell<Scope>>>,
+    // Capture for lambdas written without their scope (read_value).
+    global: Option<Rc<RefCell<Scope>>>,
+}
+
+impl<R: Read> ImageReader<R> {
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0; 1];
+        self.inp.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        self.inp.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.inp.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_u32()? as usize;
+        let mut buf = vec![0; len];
+        self.inp.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_str(&mut self) -> io::Result<String> {
+        String::from_utf8(self.read_bytes()?).map_err(|_| bad_image("string not utf8"))
+    }
+
+    fn read_scope(&mut self) -> io::Result<Option<Rc<RefCell<Scope>>>> {
+        match self.read_u32()? {
+            NO_SCOPE => Ok(None),
+            id => match self.scopes.get(id as usize) {
+                Some(scope) => Ok(Some(scope.clone())),
+                None => Err(bad_image("scope out of range")),
+            },
+        }
+    }
+
+    // None is a builtin, the caller has to look it up by name.
+    fn read_expression(&mut self) -> io::Result<Option<Expression>> {
+        let exp = match self.read_u8()? {
+            TAG_NIL => Expression::Atom(Atom::Nil),
+            TAG_TRUE => Expression::Atom(Atom::True),
+            TAG_FLOAT => Expression::Atom(Atom::Float(f64::from_bits(self.read_u64()?))),
+            TAG_INT => Expression::Atom(Atom::Int(self.read_u64()? as i64)),
+            TAG_SYMBOL => Expression::Atom(Atom::Symbol(self.read_str()?)),
+            TAG_STRING => Expression::Atom(Atom::String(self.read_str()?)),
+            TAG_LAMBDA => {
+                let params = self.read_list_item()?;
+                let body = self.read_list_item()?;
+                let doc = self.read_doc()?;
+                let capture = match self.read_scope()?.or_else(|| self.global.clone()) {
+                    Some(capture) => capture,
+                    None => return Err(bad_image("lambda without a scope")),
+                };
+                Expression::Atom(Atom::Lambda(Lambda {
+                    params: Box::new(params),
+                    body: Box::new(body),
+                    capture,
+                    doc,
+                }))
+            }
+            TAG_MACRO => {
+                let params = self.read_list_item()?;
+                let body = self.read_list_item()?;
+                let doc = self.read_doc()?;
+                Expression::Atom(Atom::Macro(Macro {
+                    params: Box::new(params),
+                    body: Box::new(body),
+                    doc,
+                }))
+            }
+            TAG_LIST => {
+                let len = self.read_u32()? as usize;
+                let mut list = Vec::with_capacity(len);
+                for _ in 0..len {
+                    list.push(self.read_list_item()?);
+                }
+                Expression::List(list)
+            }
+            TAG_FUNC => return Ok(None),
+            _ => return Err(bad_image("unknown expression tag")),
+        };
+        Ok(Some(exp))
+    }
+
+    fn read_list_item(&mut self) -> io::Result<Expression> {
+        match self.read_expression()? {
+            Some(exp) => Ok(exp),
+            None => Ok(Expression::Atom(Atom::Nil)),
+        }
+    }
+
+    fn read_size(&mut self) -> io::Result<Option<usize>> {
+        if self.read_u8()? == 1 {
+            Ok(Some(self.read_u64()? as usize))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn read_os_str(&mut self) -> io::Result<Option<OsString>> {
+        if self.read_u8()? == 1 {
+            Ok(Some(OsString::from_vec(self.read_bytes()?)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn read_doc(&mut self) -> io::Result<Option<String>> {
+        if self.read_u8()? == 1 {
+            Ok(Some(self.read_str()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn read_candidates(&mut self) -> io::Result<Vec<Candidate>> {
+        let len = self.read_u32()?;
+        let mut candidates = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let name = self.read_str()?;
+            let description = if self.read_u8()? == 1 {
+                Some(self.read_str()?)
+            } else {
+                None
+            };
+            candidates.push(Candidate { name, description });
+        }
+        Ok(candidates)
+    }
+
+    fn read_key(&mut self) -> io::Result<FileKey> {
+        let len = self.read_u32()?;
+        let mut key: FileKey = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let path = PathBuf::from(OsString::from_vec(self.read_bytes()?));
+            let mtime = if self.read_u8()? == 1 {
+                Some((self.read_u64()?, self.read_u32()?))
+            } else {
+                None
+            };
+            key.push((path, mtime));
+        }
+        Ok(key)
+    }
+}
+
+// Load the snapshot image into environment if it is current for no_std, files
+// and the environment.  Returns false (and leaves environment alone) if it is
+// missing or out of date.
+pub fn load_snapshot(
+    environment: &mut Environment,
+    image: &str,
+    no_std: bool,
+    files: &[PathBuf],
+) -> io::Result<bool> {
+    let file = match File::open(image) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let mut reader = ImageReader {
+        inp: BufReader::new(file),
+        scopes: Vec::new(),
+        global: None,
+    };
+    let mut magic = [0; 8];
+    reader.inp.read_exact(&mut magic)?;
+    if &magic != MAGIC || reader.read_str()? != VERSION_STRING {
+        return Ok(false);
+    }
+    if reader.read_u8()? != no_std as u8 {
+        return Ok(false);
+    }
+    if reader.read_key()? != make_key(files) {
+        return Ok(false);
+    }
+
+    let gensym_count = reader.read_u32()?;
+    let num_env = reader.read_u32()?;
+    let mut env_changes: Vec<(OsString, Option<OsString>)> = Vec::with_capacity(num_env as usize);
+    for _ in 0..num_env {
+        let name = OsString::from_vec(reader.read_bytes()?);
+        let before = reader.read_os_str()?;
+        let val = reader.read_os_str()?;
+        if env::var_os(&name) != before {
+            // The scripts may have used the old value (PATH=$PATH:...).
+            return Ok(false);
+        }
+        env_changes.push((name, val));
+    }
+
+    let builtins = Scope::default().data;
+    let num_scopes = reader.read_u32()?;
+    if num_scopes == 0 {
+        return Err(bad_image("no root scope"));
+    }
+    for _ in 0..num_scopes {
+        reader.scopes.push(build_new_scope(None));
+    }
+    for idx in 0..num_scopes as usize {
+        let outer = reader.read_scope()?;
+        let num_data = reader.read_u32()?;
+        let mut data: HashMap<String, Rc<Expression>> = HashMap::with_capacity(num_data as usize);
+        for _ in 0..num_data {
+            let key = reader.read_str()?;
+            match reader.read_expression()? {
+                Some(exp) => {
+                    data.insert(key, Rc::new(exp));
+                }
+                None => {
+                    if let Some(builtin) = builtins.get(&key) {
+                        data.insert(key, builtin.clone());
+                    }
+                }
+            }
+        }
+        let mut scope = reader.scopes[idx].borrow_mut();
+        scope.outer = outer;
+        scope.data = data;
+    }
+
+    let mut namespaces: HashMap<String, Namespace> = HashMap::new();
+    for _ in 0..reader.read_u32()? {
+        let name = reader.read_str()?;
+        let scope = match reader.read_scope()? {
+            Some(scope) => scope,
+            None => return Err(bad_image("namespace without a scope")),
+        };
+        let mut exports = HashSet::new();
+        for _ in 0..reader.read_u32()? {
+            exports.insert(reader.read_str()?);
+        }
+        namespaces.insert(name, Namespace { scope, exports });
+    }
+    let mut ns_imports: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for _ in 0..reader.read_u32()? {
+        let importer = reader.read_str()?;
+        let mut imports = HashMap::new();
+        for _ in 0..reader.read_u32()? {
+            let alias = reader.read_str()?;
+            imports.insert(alias, reader.read_str()?);
+        }
+        ns_imports.insert(importer, imports);
+    }
+    let mut completion_specs: HashMap<String, CompletionSpec> = HashMap::new();
+    for _ in 0..reader.read_u32()? {
+        let command = reader.read_str()?;
+        let subcommands = reader.read_candidates()?;
+        let options = reader.read_candidates()?;
+        let help_options = reader.read_u8()? == 1;
+        let args = match reader.read_expression()? {
+            Some(Expression::Atom(Atom::Nil)) | None => None,
+            args => args,
+        };
+        completion_specs.insert(
+            command,
+            CompletionSpec {
+                subcommands,
+                options,
+                help_options,
+                args,
+            },
+        );
+    }
+
+    let mut editor_config = EditorConfig {
+        emacs: reader.read_u8()? == 1,
+        history_size: reader.read_size()?,
+        history_file_size: reader.read_size()?,
+        history_dedupe: reader.read_u8()? == 1,
+        history_share: reader.read_u8()? == 1,
+        bindings: HashMap::new(),
+    };
+    for _ in 0..reader.read_u32()? {
+        let key = reader.read_str()?;
+        if let Some(binding) = reader.read_expression()? {
+            editor_config.bindings.insert(key, binding);
+        }
+    }
+    let mut async_segments = AsyncSegments::default();
+    for _ in 0..reader.read_u32()? {
+        let name = reader.read_str()?;
+        let lambda = reader.read_expression()?;
+        let ttl = reader.read_size()?;
+        let timeout = reader.read_u64()?;
+        if let Some(lambda) = lambda {
+            async_segments.add(
+                name,
+                lambda,
+                ttl.map(|ttl| Duration::from_secs(ttl as u64)),
+                Duration::from_secs(timeout),
+            );
+        }
+    }
+
+    let mut hooks = HashMap::new();
+    for _ in 0..reader.read_u32()? {
+        let name = reader.read_str()?;
+        let mut lambdas = Vec::new();
+        for _ in 0..reader.read_u32()? {
+            if let Some(hook) = reader.read_expression()? {
+                lambdas.push(hook);
+            }
+        }
+        hooks.insert(name, lambdas);
+    }
+
+    // Everything read, safe to change the environment now.
+    for (name, val) in env_changes {
+        match val {
+            Some(val) => env::set_var(name, val),
+            None => env::remove_var(name),
+        }
+    }
+    let root_scope = reader.scopes[0].clone();
+    environment.root_scope = root_scope.clone();
+    environment.current_scope = vec![root_scope];
+    environment.namespaces = namespaces;
+    environment.ns_imports = ns_imports;
+    environment.completion_specs = completion_specs;
+    environment.editor_config = editor_config;
+    environment.async_segments = Rc::new(RefCell::new(async_segments));
+    environment.hooks = hooks;
+    environment.state.gensym_count = gensym_count;
+    Ok(true)
+}
+
+// Write one value (for a spawned task's result) in the image format.  Scopes
+// are not written, read_value closes lambdas over the global scope.
+pub fn write_value(out: &mut dyn Write, exp: &Expression) -> io::Result<()> {
+    let mut writer = ImageWriter {
+        out,
+        scope_ids: HashMap::new(),
+        scopes: Vec::new(),
+    };
+    writer.write_expression(exp)?;
+    writer.out.flush()
+}
+
+pub fn read_value(inp: &mut dyn Read, environment: &Environment) -> io::Result<Expression> {
+    let mut reader = ImageReader {
+        inp,
+        scopes: Vec::new(),
+        global: Some(environment.root_scope.clone()),
+    };
+    reader.read_list_item()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::reader::read;
+    use crate::shell::eval;
+
+    fn eval_str(environment: &mut Environment, text: &str) -> String {
+        let exp = read(text).unwrap();
+        let value = eval(environment, &exp).unwrap();
+        value.make_string(environment).unwrap()
+    }
+
+    #[test]
+    fn test_value_round_trip() {
+        let mut environment = build_default_environment();
+        let env = &mut environment;
+        eval_str(env, "(def 'add-one (fn (x) (+ x 1)))");
+        eval_str(env, "(def 'val (list 1 2.5 \"str\" 'sym nil t))");
+        for name in &["add-one", "val"] {
+            let exp = get_expression(env, name).unwrap();
+            let mut buf: Vec<u8> = Vec::new();
+            write_value(&mut buf, &exp).unwrap();
+            let read_back = read_value(&mut &buf[..], env).unwrap();
+            assert_eq!(
+                read_back.make_string(env).unwrap(),
+                exp.make_string(env).unwrap()
+            );
+            env.root_scope
+                .borrow_mut()
+                .data
+                .insert(format!("{}-copy", name), Rc::new(read_back));
+        }
+        assert_eq!(eval_str(env, "(add-one-copy 2)"), "3");
+        assert_eq!(eval_str(env, "(length val-copy)"), "6");
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut environment = build_default_environment();
+        let env = &mut environment;
+        eval_str(env, "(def 'counter 10)");
+        eval_str(env, "(def 'add-counter (fn (x) (+ x counter)))");
+        eval_str(env, "(defmacro twice (form) `(progn ,form ,form))");
+        let scope_depth = env.current_scope.len();
+        eval_str(env, "(ns util :export (greet))");
+        eval_str(
+            env,
+            "(def 'greet (fn (x) (str-cat-list \" \" (list \"hello\" x))))",
+        );
+        env.current_scope.truncate(scope_depth);
+        env.current_namespace = None;
+
+        let image = env::temp_dir().join(format!("slsh-test-image-{}", process::id()));
+        let image = image.to_str().unwrap();
+        let env_before: HashMap<OsString, OsString> = env::vars_os().collect();
+        save_snapshot(env, image, false, &[], &env_before).unwrap();
+
+        let mut loaded = build_default_environment();
+        // A --no-std image does not match.
+        assert!(!load_snapshot(&mut loaded, image, true, &[]).unwrap());
+        assert!(load_snapshot(&mut loaded, image, false, &[]).unwrap());
+        fs::remove_file(image).unwrap();
+        let env = &mut loaded;
+        assert_eq!(eval_str(env, "(add-counter 5)"), "15");
+        // The lambda still shares the global scope with counter.
+        eval_str(env, "(set 'counter 1)");
+        assert_eq!(eval_str(env, "(add-counter 5)"), "6");
+        assert_eq!(eval_str(env, "(twice (set 'counter (+ counter 1)))"), "3");
+        assert_eq!(eval_str(env, "(util/greet \"bob\")"), "hello bob");
+    }
+
+    #[test]
+    fn test_snapshot_env() {
+        let name = format!("SLSH_TEST_SNAPSHOT_{}", process::id());
+        let image = env::temp_dir().join(format!("slsh-test-env-image-{}", process::id()));
+        let image = image.to_str().unwrap();
+        let env_before: HashMap<OsString, OsString> = env::vars_os().collect();
+        env::set_var(&name, "/init/bin");
+        save_snapshot(&build_default_environment(), image, false, &[], &env_before).unwrap();
+        env::remove_var(&name);
+
+        assert!(load_snapshot(&mut build_default_environment(), image, false, &[]).unwrap());
+        assert_eq!(env::var(&name).unwrap(), "/init/bin");
+        // Set before the scripts ran, the image value may be stale.
+        env::set_var(&name, "/other/bin");
+        assert!(!load_snapshot(&mut build_default_environment(), image, false, &[]).unwrap());
+        assert_eq!(env::var(&name).unwrap(), "/other/bin");
+        env::remove_var(&name);
+        fs::remove_file(image).unwrap();
+    }
+}
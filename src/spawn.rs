@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use nix::fcntl::OFlag;
+use nix::unistd::{self, ForkResult, Pid};
+
+use crate::environment::*;
+use crate::process::*;
+use crate::shell::*;
+use crate::snapshot::*;
+use crate::types::*;
+
+// Written before the result so the parent knows if the form failed.
+const RESULT_OK: u8 = 0;
+const RESULT_ERR: u8 = 1;
+
+// A lisp form running in a forked child (spawn).  Its stdout is read like an
+// external command's output and wait returns the value of the form.
+#[derive(Debug)]
+pub struct SpawnedTask {
+    stdout: Option<File>,
+    output: Vec<u8>,
+    result_pipe: Option<File>,
+    result: Option<Result<Expression, String>>,
+    // Set once the task has been reaped, with its exit status.
+    exited: Option<Option<i32>>,
+}
+
+impl SpawnedTask {
+    // Read the rest of its output, this blocks until the task is done writing.
+    fn read_output(&mut self) -> io::Result<()> {
+        if let Some(mut stdout) = self.stdout.take() {
+            stdout.read_to_end(&mut self.output)?;
+        }
+        Ok(())
+    }
+
+    fn read_result(&mut self, environment: &Environment) -> io::Result<Expression> {
+        self.read_output()?;
+        if let Some(mut pipe) = self.result_pipe.take() {
+            let mut tag = [0; 1];
+            self.result = Some(match pipe.read_exact(&mut tag) {
+                Ok(()) => {
+                    let value = read_value(&mut pipe, environment)?;
+                    match (tag[0], value) {
+                        (RESULT_OK, value) => Ok(value),
+                        (_, Expression::Atom(Atom::String(msg))) => Err(msg),
+                        (_, _) => Err("spawned task failed".to_string()),
+                    }
+                }
+                Err(_) => Err("spawned task exited without a result".to_string()),
+            });
+        }
+        match &self.result {
+            Some(Ok(value)) => Ok(value.clone()),
+            Some(Err(msg)) => Err(io::Error::new(io::ErrorKind::Other, msg.clone())),
+            None => Ok(Expression::Atom(Atom::Nil)),
+        }
+    }
+}
+
+pub type SpawnedTasks = HashMap<u32, SpawnedTask>;
+
+// Spawned tasks still running (not reaped yet).
+pub fn is_spawned_running(environment: &Environment, pid: u32) -> bool {
+    environment
+        .spawned
+        .borrow()
+        .get(&pid)
+        .map_or(false, |task| task.exited.is_none())
+}
+
+// The exit status of the spawned task pid if it has been reaped.
+pub fn spawned_exit(environment: &Environment, pid: u32) -> Option<Option<i32>> {
+    environment.spawned.borrow().get(&pid)?.exited
+}
+
+// Reap the spawned tasks that are done.  Their output and value are read now
+// and kept for wait, their pids are not waited on again (the pid could belong
+// to a new child by then).
+pub fn reap_spawned(environment: &Environment) {
+    let pids: Vec<u32> = environment
+        .spawned
+        .borrow()
+        .iter()
+        .filter(|(_, task)| task.exited.is_none())
+        .map(|(pid, _)| *pid)
+        .collect();
+    for pid in pids {
+        let (done, status) = try_wait_pid(environment, pid);
+        if done && !environment.stopped_procs.borrow().contains(&pid) {
+            let mut tasks = environment.spawned.borrow_mut();
+            if let Some(task) = tasks.get_mut(&pid) {
+                // An error is kept in the task for wait.
+                let _ = task.read_result(environment);
+                task.exited = Some(status);
+            }
+        }
+    }
+}
+
+pub fn is_spawned(environment: &Environment, pid: u32) -> bool {
+    environment.spawned.borrow().contains_key(&pid)
+}
+
+// Everything the spawned task pid wrote to stdout, None if pid is not one.
+pub fn spawned_output(environment: &Environment, pid: u32) -> Option<io::Result<String>> {
+    let mut tasks = environment.spawned.borrow_mut();
+    let task = tasks.get_mut(&pid)?;
+    Some(
+        task.read_output()
+            .map(|_| String::from_utf8_lossy(&task.output).to_string()),
+    )
+}
+
+// The stdout of the spawned task pid for the next command in a pipe.
+pub fn take_spawned_stdout(environment: &Environment, pid: u32) -> Option<File> {
+    environment
+        .spawned
+        .borrow_mut()
+        .get_mut(&pid)?
+        .stdout
+        .take()
+}
+
+// Wait for the spawned task pid and return its value, None if pid is not one.
+// The task is forgotten after this.
+pub fn wait_spawned(environment: &Environment, pid: u32) -> Option<io::Result<Expression>> {
+    let mut task = environment.spawned.borrow_mut().remove(&pid)?;
+    let res = task.read_result(environment);
+    match task.exited {
+        Some(Some(status)) => set_last_status(environment, status, None),
+        Some(None) => {}
+        None => {
+            wait_pid(environment, pid, None);
+        }
+    }
+    Some(res)
+}
+
+fn redirect_stdio(out: i32) -> io::Result<()> {
+    let to_io = |e: nix::Error| io::Error::new(io::ErrorKind::Other, e);
+    let null = OpenOptions::new().read(true).open("/dev/null")?;
+    unistd::dup2(null.as_raw_fd(), 0).map_err(to_io)?;
+    unistd::dup2(out, 1).map_err(to_io)?;
+    unistd::close(out).map_err(to_io)?;
+    Ok(())
+}
+
+// In the forked child, evaluate form with stdout on the output pipe then send
+// its value back on the result pipe.
+fn run_spawned(mut environment: Environment, form: &Expression, out: i32, result: i32) -> ! {
+    let mut result = unsafe { File::from_raw_fd(result) };
+    // Its own process group, like run-bg, so ^C in the shell leaves it alone.
+    let res = unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .and_then(|_| redirect_stdio(out))
+        .and_then(|_| eval(&mut environment, form));
+    // Commands run in the background in a spawn, wait for them before
+    // reporting and close stdout so the parent sees the end of the output.
+    let pids: Vec<u32> = environment.procs.borrow().keys().copied().collect();
+    for pid in pids {
+        wait_pid(&environment, pid, None);
+    }
+    let _ = io::stdout().flush();
+    if let Ok(null) = OpenOptions::new().write(true).open("/dev/null") {
+        let _ = unistd::dup2(null.as_raw_fd(), 1);
+    }
+    let sent = match res {
+        Ok(value) => result
+            .write_all(&[RESULT_OK])
+            .and_then(|_| write_value(&mut result, &value)),
+        Err(err) => result.write_all(&[RESULT_ERR]).and_then(|_| {
+            write_value(
+                &mut result,
+                &Expression::Atom(Atom::String(err.to_string())),
+            )
+        }),
+    };
+    unsafe { nix::libc::_exit(if sent.is_ok() { 0 } else { 1 }) }
+}
+
+// Start form in a forked child with a copy of the current scope, returns the
+// child as a running process.
+pub fn spawn(environment: &mut Environment, form: &Expression) -> io::Result<Expression> {
+    let to_io = |e: nix::Error| io::Error::new(io::ErrorKind::Other, e);
+    let mut data: HashMap<String, Expression> = HashMap::new();
+    clone_symbols(
+        &environment.current_scope.last().unwrap().borrow(),
+        &mut data,
+    );
+    let (out_read, out_write) = unistd::pipe2(OFlag::O_CLOEXEC).map_err(to_io)?;
+    let (result_read, result_write) = match unistd::pipe2(OFlag::O_CLOEXEC) {
+        Ok(fds) => fds,
+        Err(err) => {
+            let _ = unistd::close(out_read);
+            let _ = unistd::close(out_write);
+            return Err(to_io(err));
+        }
+    };
+    match unistd::fork() {
+        Ok(ForkResult::Child) => {
+            let _ = unistd::close(out_read);
+            let _ = unistd::close(result_read);
+            run_spawned(build_new_spawn_scope(data), form, out_write, result_write)
+        }
+        Ok(ForkResult::Parent { child }) => {
+            let _ = unistd::close(out_write);
+            let _ = unistd::close(result_write);
+            let pid = child.as_raw() as u32;
+            environment.spawned.borrow_mut().insert(
+                pid,
+                SpawnedTask {
+                    stdout: Some(unsafe { File::from_raw_fd(out_read) }),
+                    output: Vec::new(),
+                    result_pipe: Some(unsafe { File::from_raw_fd(result_read) }),
+                    result: None,
+                    exited: None,
+                },
+            );
+            Ok(Expression::Process(ProcessState::Running(pid)))
+        }
+        Err(err) => {
+            for fd in &[out_read, out_write, result_read, result_write] {
+                let _ = unistd::close(*fd);
+            }
+            Err(to_io(err))
+        }
+    }
+}
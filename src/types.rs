@@ -8,10 +8,13 @@ use std::rc::Rc;
 
 use crate::environment::*;
 use crate::process::*;
+use crate::spawn::*;
 
 #[derive(Clone, Debug)]
 pub struct ParseError {
     pub reason: String,
+    // The text ended inside a list or string (more input could complete it).
+    pub incomplete: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -19,12 +22,14 @@ pub struct Lambda {
     pub params: Box<Expression>,
     pub body: Box<Expression>,
     pub capture: Rc<RefCell<Scope>>,
+    pub doc: Option<String>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Macro {
     pub params: Box<Expression>,
     pub body: Box<Expression>,
+    pub doc: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -154,7 +159,12 @@ impl Expression {
     pub fn make_string(&self, environment: &Environment) -> io::Result<String> {
         match self {
             Expression::Atom(a) => Ok(a.to_string()),
-            Expression::Process(ProcessState::Running(_pid)) => Ok("".to_string()),
+            Expression::Process(ProcessState::Running(pid)) => {
+                match spawned_output(environment, *pid) {
+                    Some(output) => output,
+                    None => Ok("".to_string()),
+                }
+            }
             Expression::Process(ProcessState::Over(pid, _exit_status)) => {
                 self.pid_to_string(environment.procs.clone(), *pid)
             }
@@ -218,7 +228,11 @@ impl Expression {
     pub fn writef(&self, environment: &Environment, writer: &mut dyn Write) -> io::Result<()> {
         match self {
             Expression::Atom(a) => write!(writer, "{}", a.to_string())?,
-            Expression::Process(ProcessState::Running(_pid)) => {
+            Expression::Process(ProcessState::Running(pid)) => {
+                if let Some(output) = spawned_output(environment, *pid) {
+                    write!(writer, "{}", output?)?;
+                    return Ok(());
+                }
                 // Maybe should write anything available?
                 return Err(io::Error::new(
                     io::ErrorKind::Other,